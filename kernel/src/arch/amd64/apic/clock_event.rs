@@ -0,0 +1,84 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+use x86_64::registers::model_specific::Msr;
+
+use crate::{
+    arch::amd64::{
+        apic::{PercpuLapic, TIMER_VECTOR, calibrate_lapic_timer, lapic::{Lapic, LapicTimerDivide}},
+        timer::{get_hpet, now_ns},
+    },
+};
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// CPUID.01H:ECX bit advertising the TSC-deadline timer mode.
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+
+/// TSC cycles per millisecond, measured against the HPET.
+fn calibrate_tsc() -> u64 {
+    const CALIBRATION_MS: u64 = 10;
+
+    let hpet_ticks_target =
+        (CALIBRATION_MS * 1_000_000_000_000u64) / get_hpet().read().period_fs();
+
+    let hpet_start = get_hpet().read().read_counter();
+    let tsc_start = unsafe { _rdtsc() };
+
+    while get_hpet().read().read_counter().wrapping_sub(hpet_start) < hpet_ticks_target {
+        core::hint::spin_loop();
+    }
+
+    (unsafe { _rdtsc() } - tsc_start) / CALIBRATION_MS
+}
+
+/// Sets up this CPU's timer for one-shot events: TSC-deadline mode where
+/// the CPU has it, the LAPIC timer's one-shot mode otherwise. Nothing is
+/// armed until `set_next_event`.
+pub fn init_clock_event(lapic: &Lapic) {
+    let tsc_deadline = __cpuid(1).ecx & CPUID_TSC_DEADLINE != 0;
+
+    let counts_per_ms = if tsc_deadline {
+        calibrate_tsc()
+    } else {
+        calibrate_lapic_timer(lapic) as u64 / 10
+    };
+
+    lapic.stop_timer();
+    if tsc_deadline {
+        lapic.set_lvt_timer_tsc_deadline(TIMER_VECTOR);
+    } else {
+        lapic.set_timer_divide(LapicTimerDivide::Div16);
+        lapic.set_lvt_timer(TIMER_VECTOR);
+    }
+
+    PercpuLapic::with_guard(|plapic| {
+        plapic.tsc_deadline = tsc_deadline;
+        plapic.counts_per_ms = counts_per_ms;
+    });
+}
+
+/// Arms this CPU's timer interrupt for `deadline`, in nanoseconds of
+/// `now_ns`, replacing whatever was armed before; `None` disarms it. A
+/// deadline already past fires at once. One beyond the LAPIC counter's
+/// range fires early, and the scheduler simply arms it again.
+pub fn set_next_event(lapic: &Lapic, deadline: Option<u64>) {
+    let plapic = PercpuLapic::get();
+
+    let Some(deadline) = deadline else {
+        if plapic.tsc_deadline {
+            unsafe { Msr::new(IA32_TSC_DEADLINE).write(0) };
+        } else {
+            lapic.stop_timer();
+        }
+        return;
+    };
+
+    let delta_ns = deadline.saturating_sub(now_ns());
+    let counts = (delta_ns as u128 * plapic.counts_per_ms as u128 / 1_000_000) as u64;
+
+    if plapic.tsc_deadline {
+        unsafe { Msr::new(IA32_TSC_DEADLINE).write(_rdtsc() + counts.max(1)) };
+    } else {
+        lapic.set_timer_initial(counts.clamp(1, u32::MAX as u64) as u32);
+    }
+}
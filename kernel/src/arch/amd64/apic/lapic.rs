@@ -103,17 +103,6 @@ impl Lapic {
         self.registers.lapic_tpr().write(priority);
     }
 
-    pub fn setup_timer_periodic(
-        &self,
-        vector: u8,
-        div: LapicTimerDivide,
-        initial_count: u32,
-    ) {
-        self.set_timer_divide(div);
-        self.set_lvt_timer(vector, true);
-        self.set_timer_initial(initial_count);
-    }
-
     pub fn stop_timer(&self) {
         self.registers.lapic_timer_init().write(0);
     }
@@ -122,14 +111,16 @@ impl Lapic {
         self.registers.lapic_timer_div().write(div as u32);
     }
 
-    pub fn set_lvt_timer(&self, vector: u8, periodic: bool) {
-        let mut value = vector as u32;
-
-        if periodic {
-            value |= 1 << 17; 
-        }
+    /// Puts the timer in one-shot mode: it fires once the count written to
+    /// the initial count register runs down.
+    pub fn set_lvt_timer(&self, vector: u8) {
+        self.registers.lapic_timer().write(vector as u32);
+    }
 
-        self.registers.lapic_timer().write(value);
+    /// Switches the timer to TSC-deadline mode: it fires once the TSC
+    /// reaches the value written to `IA32_TSC_DEADLINE`.
+    pub fn set_lvt_timer_tsc_deadline(&self, vector: u8) {
+        self.registers.lapic_timer().write(vector as u32 | (0b10 << 17));
     }
 
     pub fn set_timer_initial(&self, count: u32) {
@@ -140,7 +131,11 @@ impl Lapic {
         self.registers.lapic_timer_curr().read()
     }
 
-    pub fn is_initialized(&self) -> bool {
-        self.registers.address != 0
+    /// Sends a fixed interrupt `vector` to the CPU whose LAPIC ID is `apic_id`.
+    pub fn send_ipi(&self, apic_id: u32, vector: u8) {
+        self.registers.lapic_icr_high().write(apic_id << 24);
+        self.registers.lapic_icr_low().write(
+            vector as u32 | ICR_DELIVERY_FIXED | ICR_DEST_PHYSICAL | ICR_LEVEL_ASSERT | ICR_TRIGGER_EDGE,
+        );
     }
 }
\ No newline at end of file
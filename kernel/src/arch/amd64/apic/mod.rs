@@ -12,12 +12,18 @@ use crate::{
 
 pub mod lapic;
 pub mod ioapic;
+pub mod clock_event;
 
 static LAPIC: Once<Lapic> = Once::new();
 
 define_per_cpu_struct! {
+    /// This CPU's LAPIC and how `clock_event` programs its timer interrupt.
+    /// `counts_per_ms` is in TSC cycles in TSC-deadline mode, in LAPIC timer
+    /// counts at divide 16 otherwise.
     pub struct PercpuLapic {
-        pub lapic: Lapic
+        pub lapic: Lapic,
+        tsc_deadline: bool,
+        counts_per_ms: u64,
     }
 }
 
@@ -40,7 +46,7 @@ pub fn calibrate_lapic_timer(lapic: &Lapic) -> u32 {
         (CALIBRATION_MS * 1_000_000_000_000u64) / get_hpet().read().period_fs();
 
     lapic.set_timer_divide(LapicTimerDivide::Div16);
-    lapic.set_lvt_timer(TIMER_VECTOR, false);
+    lapic.set_lvt_timer(TIMER_VECTOR);
     lapic.set_timer_initial(u32::MAX);
 
     let hpet_start = get_hpet().read().read_counter();
@@ -81,17 +87,6 @@ pub fn init_bootstrap_lapic() {
     });
 }
 
-pub fn start_timer(lapic: &Lapic) {
-    let ticks_10ms = calibrate_lapic_timer(lapic);
-    let ticks_1ms = ticks_10ms / 10;
-
-    lapic.setup_timer_periodic(
-        TIMER_VECTOR,
-        LapicTimerDivide::Div16,          
-        ticks_1ms,  
-    );
-}
-
 static IOAPIC: Once<IOApic> = Once::new();
 
 pub fn init_ioapic() {
@@ -146,15 +141,13 @@ irq!(150, keyboard_irq, |stack| {
 
     let scancode: u8 = port.read();
 
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => {
-                    early_print!("{character}");
-                }
-                _ => () //temporally unhandled 
-            }
-        }
+    let key = match keyboard.add_byte(scancode) {
+        Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+        _ => None,
+    };
+    // only printable keys are handled for now
+    if let Some(DecodedKey::Unicode(character)) = key {
+        early_print!("{character}");
     }
 
     lapic_eoi();
@@ -32,6 +32,21 @@ pub struct InterruptFrame {
     pub ss: u64,
 }
 
+/// Number of 64-bit words in an `InterruptFrame`.
+pub const INTERRUPT_FRAME_WORDS: usize = core::mem::size_of::<InterruptFrame>() / 8;
+
+impl InterruptFrame {
+    /// Whether the interrupted code ran in ring 3.
+    pub fn is_from_user(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+
+    /// The frame as raw words, in field order.
+    pub fn as_words(&self) -> &[u64; INTERRUPT_FRAME_WORDS] {
+        unsafe { &*(self as *const Self as *const [u64; INTERRUPT_FRAME_WORDS]) }
+    }
+}
+
 impl fmt::Display for InterruptFrame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(
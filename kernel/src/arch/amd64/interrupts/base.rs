@@ -1,6 +1,6 @@
 use core::arch::asm;
 
-use crate::{arch::amd64::{apic::lapic_eoi, cpu::{frames::InterruptFrame, hlt_loop}, interrupts::{idt::{IDT_COUNT, ISR_COUNT}, tables::{__irq_table_end, __irq_table_start, __isr_table_end, __isr_table_start, Handler, InterruptDescriptor}}}, early_println};
+use crate::{arch::amd64::{apic::lapic_eoi, cpu::{frames::InterruptFrame, hlt_loop}, interrupts::{idt::{IDT_COUNT, ISR_COUNT}, tables::{__irq_table_end, __irq_table_start, __isr_table_end, __isr_table_start, Handler, InterruptDescriptor}}, scheduler::{fault::raise_user_exception, note_kernel_entry, task::KernelEntry}}, early_println};
 
 static mut HANDLERS: [Option<Handler>; IDT_COUNT] = [None; IDT_COUNT];
 
@@ -28,6 +28,10 @@ extern "C" fn base_trap(stack_frame: *const InterruptFrame) {
 
     let vec = frame.interrupt as usize;
 
+    if frame.is_from_user() {
+        note_kernel_entry(KernelEntry::Interrupt);
+    }
+
     let handler = unsafe { HANDLERS[vec] };
     if let Some(h) = handler {
         h(&frame);
@@ -36,6 +40,10 @@ extern "C" fn base_trap(stack_frame: *const InterruptFrame) {
     } 
 
     if (frame.interrupt as usize) < ISR_COUNT {
+        if raise_user_exception(frame) {
+            return;
+        }
+
         early_println!("Unhandled isr interrupt!\n {}", frame);
         hlt_loop();
     }
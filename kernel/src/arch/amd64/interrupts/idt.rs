@@ -1,7 +1,7 @@
 use x86_64::{VirtAddr, instructions::tables::lidt, registers::segmentation::{CS, Segment}, structures::{DescriptorTablePointer, gdt::SegmentSelector}};
 use lazy_static::lazy_static;
 
-use crate::arch::amd64::{gdt::{DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX}, interrupts::base::init_dispatch_from_sections};
+use crate::arch::amd64::{gdt::DOUBLE_FAULT_IST_INDEX, interrupts::base::init_dispatch_from_sections};
 
 pub const IDT_COUNT: usize = 256;
 pub const ISR_COUNT: usize = 32;
@@ -107,17 +107,9 @@ fn handle_specific_interrupts(idt_num: usize, vectors: &mut [IDTEntry], handler:
         return true;
     }
 
-    if idt_num == 14 as usize {
-        vectors[idt_num] = IDTEntry::new(
-            handler,
-            CS::get_reg(),
-            PAGE_FAULT_IST_INDEX as u8,
-            true,
-            0,
-        );
-        
-        return true;
-    }
+    // #PF takes the default path on purpose: a user fault may block the
+    // thread until its fault handler replies, which needs the thread's own
+    // kernel stack rather than a per-CPU IST stack.
 
     if idt_num == 8 as usize {
         vectors[idt_num] = IDTEntry::new(
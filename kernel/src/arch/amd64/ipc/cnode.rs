@@ -1,47 +1,212 @@
-use crate::arch::amd64::ipc::message::Capability;
+use alloc::{sync::Arc, vec::Vec};
+use core::ptr::NonNull;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::arch::amd64::{
+    ipc::{message::Capability, object_table::{ObjData, with_object}},
+    memory::{
+        misc::phys_to_virt,
+        pmm::pages_allocator::{PAllocFlags, alloc_pages_by_order, put_pages},
+        vmm::PAGE_SIZE,
+    },
+};
 
-//TODO, BUG: when set to 256 - kernel crash :(
-pub const CAPABILITY_MAX: usize = 50;
 pub type CapIdx = u32;
+pub type CNodeRef = Arc<Mutex<CNode>>;
+
+/// Width of a capability pointer as seen by user space.
+pub const CPTR_BITS: u32 = 64;
 
+/// Slot count of the CNode every task starts with is `1 << ROOT_CNODE_RADIX_BITS`.
+pub const ROOT_CNODE_RADIX_BITS: u8 = 8;
+pub const MAX_CNODE_RADIX_BITS:  u8 = 12;
+
+/// A table of capability slots.
+///
+/// CNodes are addressed with guards, as in seL4, but a capability pointer is
+/// consumed from the least significant bit up. Each level first has to match
+/// its low `guard_bits` bits against `guard`, then uses the next `radix_bits`
+/// bits as the slot index. The walk ends at that slot once no set bits
+/// remain; otherwise the slot has to hold another CNode and the walk goes on
+/// there. Root CNodes have no guard so small integers index them directly;
+/// slot 0 of a nested CNode is only reachable through a non-zero guard.
+///
+/// The slots live in a block of physical pages, either allocated for the
+/// CNode or carved out of an untyped, of which the CNode holds a reference.
 pub struct CNode {
-    slots: [Capability; CAPABILITY_MAX]
+    slots:      NonNull<Capability>,
+    phys:       PhysAddr,
+    radix_bits: u8,
+    guard_bits: u8,
+    guard:      u64,
 }
 
+unsafe impl Send for CNode {}
+
 impl CNode {
-    pub fn new() -> Self {
+    fn geometry_ok(radix_bits: u8, guard_bits: u8, guard: u64) -> bool {
+        radix_bits <= MAX_CNODE_RADIX_BITS
+            && radix_bits as u32 + guard_bits as u32 <= CPTR_BITS
+            && (guard_bits >= 64 || guard >> guard_bits == 0)
+    }
+
+    /// Bytes of slot storage a CNode with `radix_bits` needs.
+    pub const fn storage_bytes(radix_bits: u8) -> usize {
+        core::mem::size_of::<Capability>() << radix_bits
+    }
+
+    /// Builds a CNode over `phys`, which must be at least `storage_bytes`
+    /// long and carry a page reference the CNode takes over.
+    fn with_storage(phys: PhysAddr, radix_bits: u8, guard_bits: u8, guard: u64) -> Self {
+        let slots = phys_to_virt(phys.as_u64() as usize) as *mut Capability;
+        for i in 0..1usize << radix_bits {
+            unsafe { slots.add(i).write(Capability::NULL) };
+        }
+
         Self {
-            slots: [Capability::NULL; CAPABILITY_MAX]
+            slots: NonNull::new(slots).expect("CNode storage is mapped"),
+            phys,
+            radix_bits,
+            guard_bits,
+            guard,
         }
     }
 
-    pub fn insert_at(&mut self, idx: CapIdx, cap: Capability) {
-        self.slots[idx as usize] = cap;
+    /// Allocates a CNode with its own pages. `None` when the geometry is
+    /// invalid or memory runs out.
+    pub fn new(radix_bits: u8, guard_bits: u8, guard: u64) -> Option<Self> {
+        if !Self::geometry_ok(radix_bits, guard_bits, guard) {
+            return None;
+        }
+
+        let pages = Self::storage_bytes(radix_bits).div_ceil(PAGE_SIZE);
+        let order = pages.next_power_of_two().trailing_zeros() as usize;
+        let phys = alloc_pages_by_order(order, PAllocFlags::KERNEL)?;
+        Some(Self::with_storage(phys, radix_bits, guard_bits, guard))
     }
 
-    pub fn get(&self, idx: CapIdx) -> Option<&Capability> {
-        if idx >= CAPABILITY_MAX as u32 {
+    /// Builds a CNode over `storage_bytes(radix_bits)` bytes at `base`, carved
+    /// out of an untyped. It takes over the page reference on the untyped's
+    /// block that came with the carved memory.
+    pub fn from_untyped(base: PhysAddr, radix_bits: u8, guard_bits: u8, guard: u64) -> Option<Self> {
+        if !Self::geometry_ok(radix_bits, guard_bits, guard) {
             return None;
         }
 
-        let cap = &self.slots[idx as usize];
+        Some(Self::with_storage(base, radix_bits, guard_bits, guard))
+    }
+
+    /// Unguarded single level CNode, so small integers index it directly and
+    /// higher bits select slots of CNodes stored in it.
+    pub fn new_root() -> Self {
+        Self::new(ROOT_CNODE_RADIX_BITS, 0, 0).expect("out of memory for root CNode")
+    }
+
+    fn slots(&self) -> &[Capability] {
+        unsafe { core::slice::from_raw_parts(self.slots.as_ptr(), 1 << self.radix_bits) }
+    }
+
+    fn slots_mut(&mut self) -> &mut [Capability] {
+        unsafe { core::slice::from_raw_parts_mut(self.slots.as_ptr(), 1 << self.radix_bits) }
+    }
+
+    pub fn into_ref(self) -> CNodeRef {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn insert_at(&mut self, idx: CapIdx, cap: Capability) {
+        self.slots_mut()[idx as usize] = cap;
+    }
+
+    pub fn get(&self, idx: CapIdx) -> Option<&Capability> {
+        let cap = self.slots().get(idx as usize)?;
         if cap.is_null() { None } else { Some(cap) }
     }
-    
+
     pub fn find_free(&self) -> Option<CapIdx> {
-        self.slots.iter()
+        self.slots().iter()
             .enumerate()
             .find(|(_, c)| c.is_null())
             .map(|(i, _)| i as CapIdx)
     }
-    
-    pub fn alloc(&mut self, cap: Capability) -> Option<CapIdx> {
-        let idx = self.find_free()?;
-        self.slots[idx as usize] = cap;
-        Some(idx)
+
+    pub fn is_valid_idx(&self, idx: CapIdx) -> bool {
+        (idx as usize) < self.slots().len()
+    }
+
+    pub fn is_free(&self, idx: CapIdx) -> bool {
+        self.is_valid_idx(idx) && self.slots()[idx as usize].is_null()
+    }
+
+    pub fn take(&mut self, idx: CapIdx) -> Option<Capability> {
+        let cap = *self.get(idx)?;
+        self.slots_mut()[idx as usize] = Capability::NULL;
+        Some(cap)
+    }
+
+    /// Empties every slot and returns the caps that were in them.
+    pub fn take_all(&mut self) -> Vec<Capability> {
+        self.slots_mut().iter_mut()
+            .filter(|c| !c.is_null())
+            .map(|c| core::mem::replace(c, Capability::NULL))
+            .collect()
+    }
+}
+
+impl Drop for CNode {
+    fn drop(&mut self) {
+        put_pages(self.phys);
     }
-    
-    pub fn delete(&mut self, idx: CapIdx) {
-        self.slots[idx as usize] = Capability::NULL;
+}
+
+#[inline]
+fn low_mask(bits: u32) -> u64 {
+    if bits >= 64 { u64::MAX } else { (1u64 << bits) - 1 }
+}
+
+/// Walks `cptr` starting at `root` and returns the CNode and index of the
+/// slot it names, or `None` when a guard does not match or bits are left
+/// over at a slot that does not hold a CNode.
+pub fn lookup_slot(root: &CNodeRef, cptr: u64) -> Option<(CNodeRef, CapIdx)> {
+    let mut node = root.clone();
+    let mut rest = cptr;
+    let mut bits_left = CPTR_BITS;
+
+    loop {
+        let (radix, guard_bits, guard) = {
+            let cn = node.lock();
+            (cn.radix_bits as u32, cn.guard_bits as u32, cn.guard)
+        };
+
+        let level_bits = radix + guard_bits;
+        if level_bits == 0 || level_bits > bits_left {
+            return None;
+        }
+
+        if rest & low_mask(guard_bits) != guard {
+            return None;
+        }
+        rest = rest.checked_shr(guard_bits).unwrap_or(0);
+
+        let idx = (rest & low_mask(radix)) as CapIdx;
+        rest = rest.checked_shr(radix).unwrap_or(0);
+        bits_left -= level_bits;
+
+        if rest == 0 {
+            return Some((node, idx));
+        }
+
+        let next = {
+            let cn = node.lock();
+            cn.get(idx).and_then(|cap| with_object(cap.handle, |obj| {
+                match &obj.data {
+                    ObjData::CNode(child) => Some(child.clone()),
+                    _ => None,
+                }
+            }).flatten())
+        };
+
+        node = next?;
     }
-}
\ No newline at end of file
+}
@@ -1,8 +1,8 @@
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::cell::UnsafeCell;
 use crate::arch::amd64::{
     ipc::IpcError,
-    ipc::message::FastMessage,
     scheduler::task::TaskIdIndex,
 };
 
@@ -84,33 +84,35 @@ impl WaitQueue {
         self.acquire();
         let result = {
             let q = self.inner();
-            loop {
-                if q.count == 0 { break None; }
+            if q.count == 0 {
+                None
+            } else {
                 let id = q.buf[q.head].take();
                 q.head  = (q.head + 1) % WQ_CAP;
                 q.count -= 1;
-                if id.is_some() { break id; }
+                id
             }
         };
         self.release();
         result
     }
 
+    /// Removes `id` and moves the entries behind it up, so `count` only
+    /// ever covers tasks that are still waiting.
     fn cancel(&self, id: TaskIdIndex) -> bool {
         self.acquire();
         let found = {
             let q = self.inner();
-            let mut i = q.head;
-            let mut found = false;
-            for _ in 0..q.count {
-                if q.buf[i] == Some(id) {
-                    q.buf[i] = None;
-                    found = true;
-                    break;
+            let pos = (0..q.count).find(|n| q.buf[(q.head + n) % WQ_CAP] == Some(id));
+            if let Some(pos) = pos {
+                for n in pos..q.count - 1 {
+                    q.buf[(q.head + n) % WQ_CAP] = q.buf[(q.head + n + 1) % WQ_CAP];
                 }
-                i = (i + 1) % WQ_CAP;
+                q.tail = (q.tail + WQ_CAP - 1) % WQ_CAP;
+                q.buf[q.tail] = None;
+                q.count -= 1;
             }
-            found
+            pos.is_some()
         };
         self.release();
         found
@@ -124,22 +126,20 @@ impl WaitQueue {
 pub struct Endpoint {
     pub id:     EndpointId,
     recv_queue: WaitQueue,
+    send_queue: WaitQueue,
     closed:     bool,
 }
 
 impl Endpoint {
     pub fn new() -> Self {
-        Self {
-            id:         EndpointId::alloc(),
-            recv_queue: WaitQueue::new(),
-            closed:     false,
-        }
+        Self::new_with_id(EndpointId::alloc())
     }
 
     pub fn new_with_id(id: EndpointId) -> Self {
         Self {
             id:         id,
             recv_queue: WaitQueue::new(),
+            send_queue: WaitQueue::new(),
             closed:     false,
         }
     }
@@ -147,19 +147,33 @@ impl Endpoint {
     pub fn close(&mut self)         { self.closed = true; }
     pub fn is_closed(&self) -> bool { self.closed }
 
-    pub fn try_send(&mut self, _msg: FastMessage) -> Result<Option<TaskIdIndex>, IpcError> {
+    /// Hands the message to a waiting receiver if there is one, otherwise
+    /// queues `sender_id`, whose message must be parked in its TCB.
+    pub fn try_send(&mut self, sender_id: TaskIdIndex) -> Result<Option<TaskIdIndex>, IpcError> {
         if self.closed {
             return Err(IpcError::EndpointClosed);
         }
-        Ok(self.recv_queue.dequeue())
+        if let Some(receiver) = self.recv_queue.dequeue() {
+            return Ok(Some(receiver));
+        }
+        if self.send_queue.enqueue(sender_id) {
+            Ok(None)
+        } else {
+            Err(IpcError::NotReady)
+        }
     }
 
-    pub fn try_recv(&mut self, receiver_id: TaskIdIndex) -> Result<(), IpcError> {
+    /// Returns the first queued sender, or queues `receiver_id` when there is
+    /// none.
+    pub fn try_recv(&mut self, receiver_id: TaskIdIndex) -> Result<Option<TaskIdIndex>, IpcError> {
         if self.closed {
             return Err(IpcError::EndpointClosed);
         }
+        if let Some(sender) = self.send_queue.dequeue() {
+            return Ok(Some(sender));
+        }
         if self.recv_queue.enqueue(receiver_id) {
-            Ok(())
+            Ok(None)
         } else {
             Err(IpcError::NotReady) 
         }
@@ -169,7 +183,27 @@ impl Endpoint {
         self.recv_queue.cancel(id)
     }
 
+    pub fn cancel_send(&mut self, id: TaskIdIndex) -> bool {
+        self.send_queue.cancel(id)
+    }
+
+    /// Empties both wait queues and returns every task that was queued.
+    pub fn drain(&mut self) -> Vec<TaskIdIndex> {
+        let mut tasks = Vec::new();
+        while let Some(id) = self.send_queue.dequeue() {
+            tasks.push(id);
+        }
+        while let Some(id) = self.recv_queue.dequeue() {
+            tasks.push(id);
+        }
+        tasks
+    }
+
     pub fn has_waiting_receiver(&self) -> bool {
         !self.recv_queue.is_empty()
     }
+
+    pub fn has_waiting_sender(&self) -> bool {
+        !self.send_queue.is_empty()
+    }
 }
\ No newline at end of file
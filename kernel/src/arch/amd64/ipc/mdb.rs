@@ -0,0 +1,197 @@
+use alloc::{collections::btree_map::BTreeMap, sync::{Arc, Weak}, vec::Vec};
+use spin::Mutex;
+
+use crate::arch::amd64::ipc::{cnode::{CNode, CNodeRef, CapIdx}, message::Capability};
+
+pub type MdbId = u64;
+
+pub const MDB_NONE: MdbId = 0;
+
+/// Location of a capability: the CNode holding it and the slot inside it.
+#[derive(Clone)]
+pub struct SlotRef {
+    pub cnode: Weak<Mutex<CNode>>,
+    pub idx:   CapIdx,
+}
+
+impl SlotRef {
+    pub fn new(cnode: &CNodeRef, idx: CapIdx) -> Self {
+        Self { cnode: Arc::downgrade(cnode), idx }
+    }
+}
+
+struct MdbNode {
+    parent:   MdbId,
+    children: Vec<MdbId>,
+    slot:     SlotRef,
+}
+
+/// Mapping database: records which capability was derived from which across
+/// all CNodes, so that revoking a capability can find every copy made from it.
+pub struct MappingDb {
+    nodes:   BTreeMap<MdbId, MdbNode>,
+    next_id: MdbId,
+}
+
+pub static MDB: Mutex<MappingDb> = Mutex::new(MappingDb::new());
+
+impl MappingDb {
+    pub const fn new() -> Self {
+        Self {
+            nodes:   BTreeMap::new(),
+            next_id: MDB_NONE + 1,
+        }
+    }
+
+    fn alloc_id(&mut self) -> MdbId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Whether a cap derived from `parent` may still be installed: `parent`
+    /// is `MDB_NONE` or still in the tree. A cap in flight whose parent was
+    /// deleted or revoked meanwhile must not turn up as a new root.
+    pub fn can_derive_from(&self, parent: MdbId) -> bool {
+        parent == MDB_NONE || self.nodes.contains_key(&parent)
+    }
+
+    fn insert(&mut self, parent: MdbId, slot: SlotRef) -> Option<MdbId> {
+        if !self.can_derive_from(parent) {
+            return None;
+        }
+
+        let id = self.alloc_id();
+        if let Some(p) = self.nodes.get_mut(&parent) {
+            p.children.push(id);
+        }
+        self.nodes.insert(id, MdbNode { parent, children: Vec::new(), slot });
+        Some(id)
+    }
+
+    /// Places `cap` into `cnode[idx]` and records it as a child of `parent`
+    /// (or as a new root when `parent` is `MDB_NONE`). Fails, leaving the
+    /// slot alone, when `parent` is no longer in the tree.
+    pub fn install_at(
+        &mut self,
+        cnode:  &mut CNode,
+        owner:  &CNodeRef,
+        idx:    CapIdx,
+        parent: MdbId,
+        mut cap: Capability,
+    ) -> bool {
+        let Some(id) = self.insert(parent, SlotRef::new(owner, idx)) else {
+            return false;
+        };
+        cap.mdb = id;
+        cnode.insert_at(idx, cap);
+        true
+    }
+
+    /// Like `install_at` into the first free slot. `None` when there is no
+    /// free slot or `parent` is gone.
+    pub fn install(
+        &mut self,
+        cnode:  &mut CNode,
+        owner:  &CNodeRef,
+        parent: MdbId,
+        cap:    Capability,
+    ) -> Option<CapIdx> {
+        let idx = cnode.find_free()?;
+        self.install_at(cnode, owner, idx, parent, cap).then_some(idx)
+    }
+
+    pub fn moved(&mut self, id: MdbId, slot: SlotRef) {
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.slot = slot;
+        }
+    }
+
+    /// Drops a single node. Its children stay in the tree and are attached
+    /// to the removed node's parent.
+    pub fn remove(&mut self, id: MdbId) {
+        let node = match self.nodes.remove(&id) {
+            Some(n) => n,
+            None    => return,
+        };
+
+        if let Some(p) = self.nodes.get_mut(&node.parent) {
+            p.children.retain(|c| *c != id);
+            p.children.extend_from_slice(&node.children);
+        }
+
+        for child in node.children.iter() {
+            if let Some(c) = self.nodes.get_mut(child) {
+                c.parent = node.parent;
+            }
+        }
+    }
+
+    fn descendants(&self, id: MdbId) -> Vec<(MdbId, SlotRef)> {
+        let mut out = Vec::new();
+        let mut stack: Vec<MdbId> = match self.nodes.get(&id) {
+            Some(n) => n.children.clone(),
+            None    => return out,
+        };
+
+        while let Some(curr) = stack.pop() {
+            if let Some(node) = self.nodes.get(&curr) {
+                out.push((curr, node.slot.clone()));
+                stack.extend_from_slice(&node.children);
+            }
+        }
+        out
+    }
+
+    /// Removes every capability derived from `id` from whatever CNode holds
+    /// it and returns the removed capabilities so that the caller can drop
+    /// their object references. The cap at `id` itself stays, under a new
+    /// id: copies still in flight in IPC messages name the old one as their
+    /// parent and are dropped on delivery.
+    ///
+    /// The owners' CNodes are locked one at a time while the database lock
+    /// is held, so no other capability operation can observe a partially
+    /// revoked subtree.
+    pub fn revoke(&mut self, id: MdbId) -> Vec<Capability> {
+        let victims = self.descendants(id);
+        let mut revoked = Vec::with_capacity(victims.len());
+
+        for (victim, slot) in victims {
+            if let Some(owner) = slot.cnode.upgrade() {
+                let mut cnode = owner.lock();
+                let matches = cnode.get(slot.idx).map(|c| c.mdb) == Some(victim);
+                if matches {
+                    revoked.extend(cnode.take(slot.idx));
+                }
+            }
+            self.nodes.remove(&victim);
+        }
+
+        self.renumber(id);
+        revoked
+    }
+
+    /// Moves the node `id` to a fresh id without children, updating its
+    /// parent and the cap in its slot.
+    fn renumber(&mut self, id: MdbId) {
+        let Some(mut node) = self.nodes.remove(&id) else { return };
+        let new_id = self.alloc_id();
+
+        if let Some(p) = self.nodes.get_mut(&node.parent) {
+            for child in p.children.iter_mut().filter(|c| **c == id) {
+                *child = new_id;
+            }
+        }
+
+        if let Some(owner) = node.slot.cnode.upgrade() {
+            let mut cnode = owner.lock();
+            if let Some(mut cap) = cnode.get(node.slot.idx).copied().filter(|c| c.mdb == id) {
+                cap.mdb = new_id;
+                cnode.insert_at(node.slot.idx, cap);
+            }
+        }
+
+        node.children.clear();
+        self.nodes.insert(new_id, node);
+    }
+}
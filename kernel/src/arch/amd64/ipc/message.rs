@@ -1,9 +1,15 @@
-use crate::arch::amd64::ipc::object_table::{HandleRef, KernelObjType, with_object};
+use bitfield_struct::bitfield;
+
+use crate::arch::amd64::ipc::{mdb::{MDB_NONE, MdbId}, object_table::{HandleRef, KernelObjType, with_object}};
 
 pub const MAX_CAPS_PER_MSG: usize = 4;
 
 pub const MSG_DATA_WORDS: usize = 4;
 
+/// Message words that travel through the IPC buffer on top of the ones in
+/// registers.
+pub const MSG_EXTRA_WORDS: usize = 64;
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Rights(u64);
 
@@ -15,6 +21,10 @@ impl Rights {
     pub const GRANT: Rights = Rights(1 << 3); 
     pub const ALL:   Rights = Rights(0xF);
 
+    pub const fn from_bits(bits: u64) -> Rights {
+        Rights(bits & Self::ALL.0)
+    }
+
     pub fn contains(self, other: Rights) -> bool {
         (self.0 & other.0) == other.0
     }
@@ -22,6 +32,10 @@ impl Rights {
     pub fn intersect(self, other: Rights) -> Rights {
         Rights(self.0 & other.0)
     }
+
+    pub const fn union(self, other: Rights) -> Rights {
+        Rights(self.0 | other.0)
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -30,6 +44,10 @@ pub struct Capability {
     pub handle: HandleRef,   
     pub rights: Rights,
     pub depth:  u32,
+    pub mdb:    MdbId,
+    /// Word chosen when the cap was minted. Endpoint caps hand it to the
+    /// receiver so a server can tell its clients apart.
+    pub badge:  u64,
 }
 
 impl Capability {
@@ -37,10 +55,12 @@ impl Capability {
         handle: HandleRef { index: 0, generation: 0 },
         rights: Rights::NONE,
         depth: 0,
+        mdb: MDB_NONE,
+        badge: 0,
     };
 
     pub fn new(handle: HandleRef, rights: Rights) -> Self {
-        Capability { handle, rights, depth: 0 }
+        Capability { handle, rights, depth: 0, mdb: MDB_NONE, badge: 0 }
     }
 
     pub fn is_null(&self) -> bool {
@@ -60,8 +80,21 @@ impl Capability {
             handle: self.handle,
             rights: child_rights,
             depth: self.depth + 1,
+            mdb: MDB_NONE,
+            badge: self.badge,
         })
     }
+
+    /// Like `derive`, but stamps `badge` on the child. A badge, once set,
+    /// cannot be changed by minting again.
+    pub fn mint(&self, requested: Rights, badge: u64) -> Option<Capability> {
+        if self.badge != 0 {
+            return None;
+        }
+        let mut child = self.derive(requested)?;
+        child.badge = badge;
+        Some(child)
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -74,7 +107,49 @@ impl MsgLabel {
     pub const REPLY_ERR:  MsgLabel = MsgLabel(2);
     pub const NOTIFY:     MsgLabel = MsgLabel(3);
     pub const CALL:       MsgLabel = MsgLabel(4);
+    pub const SEND:       MsgLabel = MsgLabel(5);
+    pub const FAULT:      MsgLabel = MsgLabel(6);
+    /// Left for tasks blocked on an endpoint or notification that was
+    /// destroyed. Never delivered: the syscall fails instead.
+    pub const CLOSED:     MsgLabel = MsgLabel(7);
+    /// Left for a sender a suspend took off a send queue before its message
+    /// went out. Never delivered: the send or call returns as timed out.
+    pub const CANCELLED:  MsgLabel = MsgLabel(8);
     pub const USER_BASE:  MsgLabel = MsgLabel(0x1000);
+
+    /// Whether the sender waits for an answer through a reply object.
+    pub fn expects_reply(self) -> bool {
+        self == Self::CALL || self == Self::FAULT
+    }
+}
+
+/// Message descriptor passed in `r9`. On send `n_caps` says how many
+/// capability pointers follow in `r12`..`r15`, on receive how many of those
+/// registers hold freshly installed slots. `nonblock` turns send, recv and
+/// call into polls that fail instead of waiting for a partner.
+/// `extra_words` counts the words taken from or placed in the IPC buffer.
+/// On receive `dropped_caps` counts the caps of the message that could not
+/// be installed, because the receiver's CNode was full or the cap went stale
+/// in flight.
+#[bitfield(u64)]
+pub struct MsgInfo {
+    #[bits(3)]
+    pub n_caps: u8,
+    pub nonblock: bool,
+    #[bits(7)]
+    pub extra_words: u8,
+    #[bits(3)]
+    pub dropped_caps: u8,
+    #[bits(50)]
+    __reserved: u64,
+}
+
+/// Layout of the page a thread registers as its IPC buffer. On receive
+/// `caps` repeats the slots reported in `r12`..`r15`.
+#[repr(C)]
+pub struct IpcBuffer {
+    pub words: [u64; MSG_EXTRA_WORDS],
+    pub caps:  [u64; MAX_CAPS_PER_MSG],
 }
 
 #[derive(Clone, Copy)]
@@ -84,6 +159,10 @@ pub struct FastMessage {
     pub data:  [u64; MSG_DATA_WORDS],
     pub caps:  [Capability; MAX_CAPS_PER_MSG],
     pub n_caps: usize,
+    /// Badge of the endpoint cap the message was sent through.
+    pub badge:  u64,
+    pub extra:  [u64; MSG_EXTRA_WORDS],
+    pub n_extra: usize,
 }
 
 impl Default for FastMessage {
@@ -93,6 +172,9 @@ impl Default for FastMessage {
             data:  [0u64; MSG_DATA_WORDS],
             caps:  [Capability::NULL; MAX_CAPS_PER_MSG],
             n_caps: 0,
+            badge: 0,
+            extra: [0u64; MSG_EXTRA_WORDS],
+            n_extra: 0,
         }
     }
 }
@@ -104,6 +186,9 @@ impl FastMessage {
             data:   [0u64; MSG_DATA_WORDS],
             caps:   [Capability::NULL; MAX_CAPS_PER_MSG],
             n_caps: 0,
+            badge:  0,
+            extra:  [0u64; MSG_EXTRA_WORDS],
+            n_extra: 0,
         }
     }
 
@@ -113,6 +198,9 @@ impl FastMessage {
             data,
             caps:   [Capability::NULL; MAX_CAPS_PER_MSG],
             n_caps: 0,
+            badge:  0,
+            extra:  [0u64; MSG_EXTRA_WORDS],
+            n_extra: 0,
         }
     }
 
@@ -128,4 +216,8 @@ impl FastMessage {
     pub fn caps(&self) -> &[Capability] {
         &self.caps[..self.n_caps]
     }
+
+    pub fn extra(&self) -> &[u64] {
+        &self.extra[..self.n_extra]
+    }
 }
\ No newline at end of file
@@ -1,18 +1,19 @@
-use alloc::collections::btree_map::BTreeMap;
+use alloc::{collections::btree_map::BTreeMap, vec::Vec};
 use spin::Mutex;
 use crate::{arch::amd64::{
     ipc::{
         endpoint::{Endpoint, EndpointId},
-        message::{Capability, FastMessage, Rights},
-        notification::Notification,
+        message::{Capability, FastMessage, MsgLabel, Rights},
+        notification::{Notification, NotificationId},
     },
-    scheduler::task::TaskIdIndex,
+    scheduler::{mark_blocked_on_ipc, reschedule_task, task::TaskIdIndex, task_storage::get_task_by_index},
 }, early_println};
 
 pub mod endpoint;
 pub mod message;
 pub mod notification;
 pub mod cnode;
+pub mod mdb;
 pub mod object_table;
 
 pub static IPC_MANAGER: Mutex<IpcManager> = Mutex::new(IpcManager::new());
@@ -28,9 +29,10 @@ pub enum IpcError {
     NotReady        = 3,
     Blocked         = 4,
     NoGrant         = 5,
-    TooManyCaps     = 6,
     EndpointClosed  = 7,
     Timeout         = 8,
+    NoReplyObject   = 9,
+    ReplyPending    = 10,
 }
 
 pub struct EndpointTable {
@@ -46,10 +48,10 @@ impl EndpointTable {
         }
     }
 
-    pub fn create_endpoint(&mut self, task_id: TaskIdIndex) -> Option<EndpointId> {
+    pub fn create_endpoint(&mut self) -> Option<EndpointId> {
         for slot in self.endpoints.iter_mut() {
             if slot.is_none() {
-                let ep = Endpoint::new_with_id(EndpointId::new(task_id as u64));
+                let ep = Endpoint::new();
                 let id = ep.id;
                 *slot = Some(ep);
                 return Some(id);
@@ -58,81 +60,321 @@ impl EndpointTable {
         None
     }
 
+    pub fn endpoints_mut(&mut self) -> impl Iterator<Item = &mut Endpoint> {
+        self.endpoints.iter_mut().filter_map(|s| s.as_mut())
+    }
+
+    pub fn notifications_mut(&mut self) -> impl Iterator<Item = &mut Notification> {
+        self.notifications.iter_mut().filter_map(|s| s.as_mut())
+    }
+
     pub fn get_endpoint(&mut self, id: EndpointId) -> Option<&mut Endpoint> {
         self.endpoints.iter_mut()
             .filter_map(|s| s.as_mut())
             .find(|ep| ep.id == id)
     }
 
-    pub fn destroy_endpoint(&mut self, id: EndpointId) {
-        for slot in self.endpoints.iter_mut() {
-            if let Some(ep) = slot {
-                if ep.id == id {
-                    ep.close();
-                    *slot = None;
-                    return;
-                }
-            }
-        }
+    /// Closes and frees `id`, returning the tasks that were queued on it.
+    pub fn destroy_endpoint(&mut self, id: EndpointId) -> Vec<TaskIdIndex> {
+        let slot = self.endpoints.iter_mut().find(|s| matches!(s, Some(ep) if ep.id == id));
+        slot.and_then(Option::take).map_or_else(Vec::new, |mut ep| {
+            ep.close();
+            ep.drain()
+        })
+    }
+    pub fn create_notification(&mut self) -> Option<NotificationId> {
+        let slot = self.notifications.iter_mut().find(|s| s.is_none())?;
+        let ntfn = Notification::new();
+        let id = ntfn.id;
+        *slot = Some(ntfn);
+        Some(id)
+    }
+
+    pub fn get_notification(&mut self, id: NotificationId) -> Option<&mut Notification> {
+        self.notifications.iter_mut()
+            .filter_map(|s| s.as_mut())
+            .find(|n| n.id == id)
+    }
+
+    /// Frees the notification's slot and returns the task that was waiting
+    /// on it, if any.
+    pub fn destroy_notification(&mut self, id: NotificationId) -> Option<TaskIdIndex> {
+        let slot = self.notifications.iter_mut().find(|s| matches!(s, Some(n) if n.id == id))?;
+        slot.take().and_then(|ntfn| ntfn.waiter())
     }
 }
 
 pub enum IpcResult {
     WakeReceiver { receiver: TaskIdIndex },
+    /// A message is pending for the current task. `sender` is set when the
+    /// task that sent it was blocked in a plain send and should be woken.
+    Received { sender: Option<TaskIdIndex> },
     BlockCurrent,
-    NotReady,
     Done,
     Error(IpcError),
 }
 
+/// One-shot right to answer a `call`. It is handed to the receiving server
+/// together with the call and consumed by the first `reply`.
+#[derive(Clone, Copy)]
+pub struct ReplyObject {
+    pub caller: TaskIdIndex,
+    /// Endpoint the call came through.
+    pub ep:     EndpointId,
+}
+
 pub struct IpcManager {
     pub table: EndpointTable,
-    pending_messages: BTreeMap<u32, FastMessage>,
+    pending_messages: BTreeMap<TaskIdIndex, FastMessage>,
+    reply_slots:      BTreeMap<TaskIdIndex, ReplyObject>,
+    /// Endpoint every blocked receiver is queued on.
+    receiving:        BTreeMap<TaskIdIndex, EndpointId>,
 }
 
 impl IpcManager {
     pub const fn new() -> Self {
         IpcManager { 
             table: EndpointTable::new(),
-            pending_messages: BTreeMap::new()
+            pending_messages: BTreeMap::new(),
+            reply_slots:      BTreeMap::new(),
+            receiving:        BTreeMap::new(),
         }
     }
 
-    pub fn create_endpoint(&mut self, task_id: TaskIdIndex) -> Option<EndpointId> {
-        self.table.create_endpoint(task_id)
+    pub fn create_endpoint(&mut self) -> Option<EndpointId> {
+        self.table.create_endpoint()
     }
 
-    pub fn destroy_endpoint(&mut self, ep_id: EndpointId) {
-        self.table.destroy_endpoint(ep_id);
+    /// Destroys `ep_id` and fails everything blocked on it: queued senders
+    /// and receivers, and callers whose call came through it and still wait
+    /// for the reply. Each is left a `CLOSED` message; the returned tasks
+    /// must be woken once the IPC lock is dropped.
+    pub fn destroy_endpoint(&mut self, ep_id: EndpointId) -> Vec<TaskIdIndex> {
+        let mut waiters = self.table.destroy_endpoint(ep_id);
+        for task in waiters.iter().filter_map(|id| get_task_by_index(*id)) {
+            task.tcb.parked_msg.lock().take();
+        }
+        self.receiving.retain(|_, ep| *ep != ep_id);
+
+        self.reply_slots.retain(|&server_id, reply| {
+            if reply.ep != ep_id {
+                return true;
+            }
+            waiters.push(reply.caller);
+            Self::revoke_sched_context(server_id);
+            false
+        });
+
+        for &task_id in &waiters {
+            self.store_pending_message(task_id, FastMessage::empty(MsgLabel::CLOSED));
+        }
+        waiters
+    }
+
+    pub fn create_notification(&mut self) -> Option<NotificationId> {
+        self.table.create_notification()
     }
 
-    pub fn store_pending_message(&mut self, task_id: u32, msg: FastMessage) {
+    /// Unbinds and frees the notification. A task still waiting on it gets
+    /// a `CLOSED` message and is returned; the caller wakes it once the IPC
+    /// lock is dropped.
+    pub fn destroy_notification(&mut self, id: NotificationId) -> Option<TaskIdIndex> {
+        let bound = self.table.get_notification(id).and_then(|n| n.bound_tcb());
+        if let Some(task) = bound.and_then(get_task_by_index) {
+            task.tcb.bound_notification.lock().take();
+        }
+        let waiter = self.table.destroy_notification(id)?;
+        self.store_pending_message(waiter, FastMessage::empty(MsgLabel::CLOSED));
+        Some(waiter)
+    }
+
+    /// Binds the notification to `tcb`. Both sides may hold at most one
+    /// binding.
+    pub fn bind_notification(&mut self, tcb: TaskIdIndex, id: NotificationId) -> Result<(), IpcError> {
+        let task = get_task_by_index(tcb).ok_or(IpcError::InvalidEndpoint)?;
+        let ntfn = self.table.get_notification(id).ok_or(IpcError::InvalidEndpoint)?;
+
+        let mut bound = task.tcb.bound_notification.lock();
+        if bound.is_some() || ntfn.bound_tcb().is_some() {
+            return Err(IpcError::NotReady);
+        }
+
+        ntfn.bind(Some(tcb));
+        *bound = Some(id);
+        Ok(())
+    }
+
+    pub fn unbind_notification(&mut self, tcb: TaskIdIndex) {
+        let id = get_task_by_index(tcb).and_then(|task| task.tcb.bound_notification.lock().take());
+        if let Some(ntfn) = id.and_then(|id| self.table.get_notification(id)) {
+            ntfn.bind(None);
+        }
+    }
+
+    /// Pending badges of the notification bound to `receiver_id`, cleared.
+    fn take_bound_badges(&mut self, receiver_id: TaskIdIndex) -> u64 {
+        let id = get_task_by_index(receiver_id).and_then(|task| *task.tcb.bound_notification.lock());
+        id.and_then(|id| self.table.get_notification(id))
+            .map_or(0, |ntfn| ntfn.take())
+    }
+
+    /// Raises `badge` on the notification. A waiting task is handed every
+    /// pending badge as a `NOTIFY` message and returned for waking.
+    pub fn signal_notification(&mut self, id: NotificationId, badge: u64) -> IpcResult {
+        let ntfn = match self.table.get_notification(id) {
+            Some(n) => n,
+            None    => return IpcResult::Error(IpcError::InvalidEndpoint),
+        };
+
+        if let Some(waiter) = ntfn.signal(badge) {
+            let badges = ntfn.take();
+            self.store_pending_message(waiter, FastMessage::with_data(MsgLabel::NOTIFY, [badges, 0, 0, 0]));
+            return IpcResult::WakeReceiver { receiver: waiter };
+        }
+
+        // Nobody waits on the notification itself; a bound thread blocked in
+        // recv is pulled off its endpoint and gets the badges instead.
+        let bound = match ntfn.bound_tcb() {
+            Some(tcb) => tcb,
+            None      => return IpcResult::Done,
+        };
+        let ep_id = match self.receiving.get(&bound) {
+            Some(ep_id) => *ep_id,
+            None        => return IpcResult::Done,
+        };
+        let cancelled = self.table.get_endpoint(ep_id)
+            .is_some_and(|ep| ep.cancel_recv(bound));
+        if !cancelled {
+            return IpcResult::Done;
+        }
+        self.receiving.remove(&bound);
+
+        let badges = self.take_bound_badges(bound);
+        self.store_pending_message(bound, FastMessage::with_data(MsgLabel::NOTIFY, [badges, 0, 0, 0]));
+        IpcResult::WakeReceiver { receiver: bound }
+    }
+
+    /// Returns the pending badges, or registers `waiter` when there are none.
+    /// A notification has room for a single waiter.
+    pub fn wait_notification(
+        &mut self,
+        waiter: TaskIdIndex,
+        id:     NotificationId,
+    ) -> Result<Option<u64>, IpcError> {
+        let ntfn = self.table.get_notification(id).ok_or(IpcError::InvalidEndpoint)?;
+
+        if ntfn.poll() == 0 && matches!(ntfn.waiter(), Some(w) if w != waiter) {
+            return Err(IpcError::NotReady);
+        }
+        let badges = ntfn.wait(waiter);
+        if badges.is_none() {
+            Self::mark_blocked(waiter);
+        }
+        Ok(badges)
+    }
+
+    /// Returns and clears the pending badges without blocking.
+    pub fn poll_notification(&mut self, id: NotificationId) -> Result<u64, IpcError> {
+        let ntfn = self.table.get_notification(id).ok_or(IpcError::InvalidEndpoint)?;
+        Ok(ntfn.take())
+    }
+
+    pub fn store_pending_message(&mut self, task_id: TaskIdIndex, msg: FastMessage) {
         self.pending_messages.insert(task_id, msg);
     }
 
-    pub fn take_pending_message(&mut self, task_id: u32) -> Option<FastMessage> {
+    pub fn take_pending_message(&mut self, task_id: TaskIdIndex) -> Option<FastMessage> {
         self.pending_messages.remove(&task_id)
     }
 
+    /// Hands `server` the right to answer `caller`. A server without a
+    /// scheduling context of its own runs on the caller's until it replies
+    /// or waits for the next message. `handle_recv` refuses servers that
+    /// still owe a reply, so no reply object is ever replaced.
+    fn open_reply(&mut self, server_id: TaskIdIndex, caller_id: TaskIdIndex, ep: EndpointId) {
+        let displaced = self.reply_slots.insert(server_id, ReplyObject { caller: caller_id, ep });
+        debug_assert!(displaced.is_none(), "open_reply: server already owes a reply");
+        if let (Some(server), Some(caller)) = (get_task_by_index(server_id), get_task_by_index(caller_id)) {
+            server.tcb.borrow_sched_context(&caller.tcb);
+        }
+    }
+
+    fn mark_blocked(task_id: TaskIdIndex) {
+        if let Some(task) = get_task_by_index(task_id) {
+            mark_blocked_on_ipc(&task);
+        }
+    }
+
+    /// Forgets the message a sender parked for a send it no longer waits in.
+    fn drop_parked_msg(task_id: TaskIdIndex) {
+        if let Some(task) = get_task_by_index(task_id) {
+            task.tcb.parked_msg.lock().take();
+        }
+    }
+
+    fn return_sched_context(&mut self, server_id: TaskIdIndex) {
+        if let Some(server) = get_task_by_index(server_id) {
+            server.tcb.return_sched_context();
+        }
+    }
+
+    /// Takes back the context a caller lent `server_id` for a call that is
+    /// gone unanswered. A server still running on it stops at once.
+    fn revoke_sched_context(server_id: TaskIdIndex) {
+        let Some(server) = get_task_by_index(server_id) else { return };
+        if server.tcb.return_sched_context() {
+            reschedule_task(&server);
+        }
+    }
+
+    /// Drops the reply objects owed to `caller_id`, along with the context
+    /// their servers were lent.
+    fn drop_replies_to(&mut self, caller_id: TaskIdIndex) {
+        self.reply_slots.retain(|&server_id, reply| {
+            if reply.caller != caller_id {
+                return true;
+            }
+            Self::revoke_sched_context(server_id);
+            false
+        });
+    }
+
+    /// Delivers `msg` to a waiting receiver, or parks it in the sender's TCB
+    /// and queues the sender on the endpoint.
     pub fn handle_send(
         &mut self,
         sender_id: TaskIdIndex,
         ep_id:     EndpointId,
         msg:       FastMessage,
+        blocking:  bool,
     ) -> IpcResult {
         let ep = match self.table.get_endpoint(ep_id) {
             Some(ep) => ep,
             None     => return IpcResult::Error(IpcError::InvalidEndpoint),
         };
 
-        match ep.try_send(msg.clone()) {
+        if !blocking && !ep.has_waiting_receiver() {
+            return IpcResult::Error(IpcError::NotReady);
+        }
+
+        match ep.try_send(sender_id) {
             Ok(Some(receiver)) => {
+                self.receiving.remove(&receiver);
                 self.store_pending_message(receiver, msg);
 
                 IpcResult::WakeReceiver { receiver }
             } 
-            Ok(None)           => IpcResult::NotReady,
-            Err(e)             => IpcResult::Error(e),
+            Ok(None) => {
+                let sender = match get_task_by_index(sender_id) {
+                    Some(t) => t,
+                    None    => return IpcResult::Error(IpcError::InvalidEndpoint),
+                };
+                *sender.tcb.parked_msg.lock() = Some(msg);
+                mark_blocked_on_ipc(&sender);
+
+                IpcResult::BlockCurrent
+            }
+            Err(e) => IpcResult::Error(e),
         }
     }
 
@@ -140,16 +382,56 @@ impl IpcManager {
         &mut self,
         receiver_id: TaskIdIndex,
         ep_id:       EndpointId,
+        blocking:    bool,
     ) -> IpcResult {
+        // The caller answered by the reply object `receiver_id` holds would
+        // never hear back if a new call replaced it, so that reply goes first.
+        if self.reply_slots.contains_key(&receiver_id) {
+            return IpcResult::Error(IpcError::ReplyPending);
+        }
+
+        self.return_sched_context(receiver_id);
+
+        let badges = self.take_bound_badges(receiver_id);
+        if badges != 0 {
+            self.store_pending_message(receiver_id, FastMessage::with_data(MsgLabel::NOTIFY, [badges, 0, 0, 0]));
+            return IpcResult::Received { sender: None };
+        }
+
         let ep = match self.table.get_endpoint(ep_id) {
             Some(ep) => ep,
             None     => return IpcResult::Error(IpcError::InvalidEndpoint),
         };
 
-        match ep.try_recv(receiver_id) {
-            Ok(())  => IpcResult::BlockCurrent,
-            Err(e)  => IpcResult::Error(e),
+        if !blocking && !ep.has_waiting_sender() {
+            return IpcResult::Error(IpcError::NotReady);
         }
+
+        let sender_id = match ep.try_recv(receiver_id) {
+            Ok(Some(sender_id)) => sender_id,
+            Ok(None) => {
+                self.receiving.insert(receiver_id, ep_id);
+                Self::mark_blocked(receiver_id);
+                return IpcResult::BlockCurrent;
+            }
+            Err(e)   => return IpcResult::Error(e),
+        };
+
+        let msg = get_task_by_index(sender_id)
+            .and_then(|sender| sender.tcb.parked_msg.lock().take())
+            .unwrap_or_else(|| FastMessage::empty(MsgLabel::INVALID));
+
+        // A queued caller stays blocked until the reply; a plain sender is done.
+        let sender = if msg.label.expects_reply() {
+            self.open_reply(receiver_id, sender_id, ep_id);
+            None
+        } else {
+            Some(sender_id)
+        };
+
+        self.store_pending_message(receiver_id, msg);
+
+        IpcResult::Received { sender }
     }
 
     pub fn handle_call(
@@ -157,20 +439,115 @@ impl IpcManager {
         caller_id: TaskIdIndex,
         ep_id:     EndpointId,
         msg:       FastMessage,
+        blocking:  bool,
     ) -> IpcResult {
-        self.handle_send(caller_id, ep_id, msg)
+        let result = self.handle_send(caller_id, ep_id, msg, blocking);
+        if let IpcResult::WakeReceiver { receiver } = result {
+            self.open_reply(receiver, caller_id, ep_id);
+            // The caller waits for the reply, which may come before it is
+            // off the CPU.
+            Self::mark_blocked(caller_id);
+        }
+        result
     }
 
     pub fn handle_reply(
         &mut self,
-        caller_id: TaskIdIndex,
-        reply_msg: FastMessage,
+        replier_id: TaskIdIndex,
+        reply_msg:  FastMessage,
     ) -> IpcResult {
-        self.store_pending_message(caller_id, reply_msg);
+        self.return_sched_context(replier_id);
+
+        let reply = match self.reply_slots.remove(&replier_id) {
+            Some(r) => r,
+            None    => return IpcResult::Error(IpcError::NoReplyObject),
+        };
+
+        self.store_pending_message(reply.caller, reply_msg);
 
         IpcResult::WakeReceiver {
-            receiver: caller_id
+            receiver: reply.caller
+        }
+    }
+
+    /// Answers the current call, if there is one, and queues `server_id` on
+    /// `ep_id` in the same critical section. Returns the caller to wake along
+    /// with the outcome of the receive.
+    pub fn handle_reply_recv(
+        &mut self,
+        server_id: TaskIdIndex,
+        ep_id:     EndpointId,
+        reply_msg: FastMessage,
+        blocking:  bool,
+    ) -> (Option<TaskIdIndex>, IpcResult) {
+        let caller = match self.handle_reply(server_id, reply_msg) {
+            IpcResult::WakeReceiver { receiver } => Some(receiver),
+            _ => None,
+        };
+
+        (caller, self.handle_recv(server_id, ep_id, blocking))
+    }
+
+    /// Takes a timed out receiver off `ep_id`'s wait queue.
+    pub fn cancel_recv(&mut self, receiver_id: TaskIdIndex, ep_id: EndpointId) {
+        if let Some(ep) = self.table.get_endpoint(ep_id) {
+            ep.cancel_recv(receiver_id);
         }
+        self.receiving.remove(&receiver_id);
+    }
+
+    /// Withdraws a timed out call, whether it is still queued on `ep_id` or
+    /// already waiting for its reply. A server serving it on the caller's
+    /// context loses that context.
+    pub fn cancel_call(&mut self, caller_id: TaskIdIndex, ep_id: EndpointId) {
+        if self.table.get_endpoint(ep_id).is_some_and(|ep| ep.cancel_send(caller_id)) {
+            Self::drop_parked_msg(caller_id);
+        }
+        self.drop_replies_to(caller_id);
+    }
+
+    /// Pulls a suspended task out of whatever it was blocked on: wait queues,
+    /// a notification wait and calls still waiting for their reply. A message
+    /// it had queued is dropped and a `CANCELLED` one left in its place. Its
+    /// bindings and any reply it owes as a server are left alone.
+    pub fn cancel_task(&mut self, task_id: TaskIdIndex) {
+        let mut unsent = false;
+        for ep in self.table.endpoints_mut() {
+            ep.cancel_recv(task_id);
+            unsent |= ep.cancel_send(task_id);
+        }
+        if unsent {
+            Self::drop_parked_msg(task_id);
+            self.store_pending_message(task_id, FastMessage::empty(MsgLabel::CANCELLED));
+        }
+        for ntfn in self.table.notifications_mut() {
+            ntfn.cancel_wait(task_id);
+        }
+
+        self.receiving.remove(&task_id);
+        self.drop_replies_to(task_id);
+    }
+
+    /// Drops every trace of a task that is going away: wait queue entries,
+    /// its pending message and notification bindings, and the reply objects
+    /// it holds or is owed. A caller it still owed a reply gets an `INVALID`
+    /// message and is returned for waking.
+    pub fn remove_task(&mut self, task_id: TaskIdIndex) -> Option<TaskIdIndex> {
+        for ep in self.table.endpoints_mut() {
+            ep.cancel_recv(task_id);
+            ep.cancel_send(task_id);
+        }
+        for ntfn in self.table.notifications_mut() {
+            ntfn.forget(task_id);
+        }
+
+        self.receiving.remove(&task_id);
+        self.pending_messages.remove(&task_id);
+        self.drop_replies_to(task_id);
+
+        let caller = self.reply_slots.remove(&task_id)?.caller;
+        self.store_pending_message(caller, FastMessage::empty(MsgLabel::INVALID));
+        Some(caller)
     }
 
     pub fn validate_caps(
@@ -2,16 +2,30 @@ use core::sync::atomic::{AtomicU64, Ordering};
 
 use crate::arch::amd64::scheduler::task::TaskIdIndex;
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct NotificationId(pub u64);
+
+static NEXT_NOTIFICATION_ID: AtomicU64 = AtomicU64::new(1);
+
+impl NotificationId {
+    pub fn alloc() -> Self { NotificationId(NEXT_NOTIFICATION_ID.fetch_add(1, Ordering::Relaxed)) }
+}
+
 pub struct Notification {
+    pub id: NotificationId,
     badges: AtomicU64,
     waiter: Option<TaskIdIndex>,
+    bound:  Option<TaskIdIndex>,
 }
 
 impl Notification {
     pub fn new() -> Self {
         Notification {
+            id:     NotificationId::alloc(),
             badges: AtomicU64::new(0),
             waiter: None,
+            bound:  None,
         }
     }
 
@@ -31,6 +45,40 @@ impl Notification {
         }
     }
 
+    pub fn bound_tcb(&self) -> Option<TaskIdIndex> {
+        self.bound
+    }
+
+    pub fn bind(&mut self, tcb: Option<TaskIdIndex>) {
+        self.bound = tcb;
+    }
+
+    pub fn waiter(&self) -> Option<TaskIdIndex> {
+        self.waiter
+    }
+
+    /// Stops `thread_id` waiting here; its binding is kept.
+    pub fn cancel_wait(&mut self, thread_id: TaskIdIndex) {
+        if self.waiter == Some(thread_id) {
+            self.waiter = None;
+        }
+    }
+
+    /// Forgets `thread_id` as waiter and binding, for a thread that is gone.
+    pub fn forget(&mut self, thread_id: TaskIdIndex) {
+        if self.waiter == Some(thread_id) {
+            self.waiter = None;
+        }
+        if self.bound == Some(thread_id) {
+            self.bound = None;
+        }
+    }
+
+    /// Returns and clears all pending badges.
+    pub fn take(&self) -> u64 {
+        self.badges.swap(0, Ordering::Acquire)
+    }
+
     pub fn poll(&self) -> u64 {
         self.badges.load(Ordering::Acquire)
     }
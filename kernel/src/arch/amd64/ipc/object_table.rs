@@ -1,7 +1,8 @@
 use core::sync::atomic::{AtomicU32, Ordering};
 use spin::Mutex;
+use x86_64::PhysAddr;
 
-use crate::arch::amd64::scheduler::task::TaskIdIndex;
+use crate::arch::amd64::{ipc::{cnode::CNodeRef, notification::NotificationId}, scheduler::{addr_space::VSpaceRef, sched_context::SchedContextRef, task::TaskIdIndex}};
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -13,19 +14,34 @@ pub enum KernelObjType {
     Thread   = 3,
     Irq      = 4,
     CNode    = 5,
+    Notification = 6,
+    Untyped  = 7,
+    SchedContext = 8,
 }
 
 pub enum ObjData {
-    VSpace(TaskIdIndex),
-    Endpoint(TaskIdIndex),
-    CNode(TaskIdIndex),
-    Thread(TaskIdIndex)
+    VSpace(VSpaceRef),
+    Endpoint(u32),
+    CNode(CNodeRef),
+    Thread(TaskIdIndex),
+    Notification(NotificationId),
+    /// Block of `pages` contiguous frames. The object owns one reference
+    /// on it, every VMA mapping it another.
+    Frame { phys: PhysAddr, pages: usize },
+    /// Block of `pages` frames that `retype` carves objects out of. `used`
+    /// bytes from the start are already handed out. Every carved object holds
+    /// a page reference on the block until it dies.
+    Untyped { phys: PhysAddr, pages: usize, used: usize },
+    SchedContext(SchedContextRef),
 }
 
 pub struct KernelObject {
     pub obj_type: KernelObjType,
     pub refcount: AtomicU32,
     pub data: ObjData,
+    /// Untyped block a heap-backed object was charged to. The object holds
+    /// a page reference on it until it is destroyed.
+    pub charge: Option<PhysAddr>,
 }
 
 impl KernelObject {
@@ -34,9 +50,15 @@ impl KernelObject {
             obj_type,
             refcount: AtomicU32::new(1),
             data,
+            charge: None,
         }
     }
 
+    pub fn charged_to(mut self, block: PhysAddr) -> Self {
+        self.charge = Some(block);
+        self
+    }
+
     pub fn inc_ref(&self) -> u32 {
         self.refcount.fetch_add(1, Ordering::Relaxed)
     }
@@ -75,7 +97,8 @@ impl ObjectTable {
         }
     }
 
-    pub fn insert(&mut self, obj: KernelObject) -> Result<HandleRef, ()> {
+    /// Stores `obj`, or hands it back when the table is full.
+    pub fn insert(&mut self, obj: KernelObject) -> Result<HandleRef, KernelObject> {
         for i in self.free_head..MAX_OBJECTS {
             if self.slots[i].obj.is_none() {
                 let generation = self.slots[i].generation;
@@ -84,7 +107,7 @@ impl ObjectTable {
                 return Ok(HandleRef { index: i as u16, generation });
             }
         }
-        Err(())
+        Err(obj)
     }
 
     pub fn get(&self, handle: HandleRef) -> Option<&KernelObject> {
@@ -121,10 +144,20 @@ impl ObjectTable {
 
 static OBJECT_TABLE: Mutex<ObjectTable> = Mutex::new(ObjectTable::new());
 
-pub fn obj_insert(obj: KernelObject) -> Result<HandleRef, ()> {
+pub fn obj_insert(obj: KernelObject) -> Result<HandleRef, KernelObject> {
     OBJECT_TABLE.lock().insert(obj)
 }
 
+pub fn obj_retain(h: HandleRef) -> bool {
+    with_object(h, |obj| { obj.inc_ref(); }).is_some()
+}
+
+pub fn obj_release(h: HandleRef) -> Option<KernelObject> {
+    let mut table = OBJECT_TABLE.lock();
+    let last = table.get(h)?.dec_ref();
+    if last { table.remove(h) } else { None }
+}
+
 pub fn with_object<F, R>(h: HandleRef, f: F) -> Option<R>
 where
     F: FnOnce(&KernelObject) -> R,
@@ -352,6 +352,7 @@ impl Buddy {
             let fh       = Self::frame_mut_unchecked(head);
             (*fh).tag       = BuddyTag::Allocated;
             (*fh).order     = order as u8;
+            (*fh).refcount  = 1;
             (*fh).prev_free = INVALID_PFN;
             (*fh).next_free = INVALID_PFN;
         }
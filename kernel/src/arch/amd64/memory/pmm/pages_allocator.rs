@@ -73,4 +73,33 @@ pub fn free_pages(ptr: PhysAddr) {
     get_zones_manager()
         .lock()
         .free_pages(pfn);
-}
\ No newline at end of file
+}
+
+/// Takes another reference on the allocated block containing `ptr`, for
+/// frames that end up mapped or owned in more than one place.
+pub fn get_pages(ptr: PhysAddr) {
+    let pfn = (ptr.as_u64() as usize) >> PAGE_SHIFT;
+
+    get_zones_manager()
+        .lock()
+        .get_pages(pfn);
+}
+
+/// Drops a reference on the block containing `ptr`, taken by the allocation
+/// or by `get_pages`; the block is freed with the last one.
+pub fn put_pages(ptr: PhysAddr) {
+    let pfn = (ptr.as_u64() as usize) >> PAGE_SHIFT;
+
+    get_zones_manager()
+        .lock()
+        .put_pages(pfn);
+}
+
+/// References currently held on the block containing `ptr`.
+pub fn page_refcount(ptr: PhysAddr) -> u32 {
+    let pfn = (ptr.as_u64() as usize) >> PAGE_SHIFT;
+
+    get_zones_manager()
+        .lock()
+        .page_refs(pfn)
+}
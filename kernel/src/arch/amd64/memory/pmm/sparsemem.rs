@@ -46,6 +46,8 @@ pub struct Frame {
     pub order:     u8,
     pub tag:       BuddyTag,
     pub zone:      ZoneId,
+    /// Owners of an allocated block, kept on its head frame.
+    pub refcount:  u32,
     pub next_free: Pfn,
     pub prev_free: Pfn,
 }
@@ -58,6 +60,7 @@ impl Frame {
             order:     0,
             tag:       BuddyTag::Unused,
             zone:      ZoneId::Normal,
+            refcount:  0,
             next_free: INVALID_PFN,
             prev_free: INVALID_PFN,
         }
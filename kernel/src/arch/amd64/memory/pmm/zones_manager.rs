@@ -6,10 +6,10 @@ use crate::{
     arch::amd64::memory::{
         misc::human_readable_size,
         pmm::{
-            buddy::Buddy,
+            buddy::{Buddy, BuddyTag, MAX_ORDER},
             pfn_iterator::UsablePfnRunIter,
             sparsemem::{
-                FrameState, PAGE_SHIFT, PAGE_SIZE, PAGES_PER_SECTION,
+                Frame, FrameState, PAGE_SHIFT, PAGE_SIZE, PAGES_PER_SECTION,
                 Pfn, SECTION_SHIFT, SparseMem, get_sparse_memory,
             },
         },
@@ -148,6 +148,53 @@ impl ZonesManager {
             .unwrap_or_else(|| panic!("free_pages: zone {:?} not initialized", zid))
             .free(pfn);
     }
+
+    /// Head of the allocated block containing `pfn`.
+    fn block_head(pfn: Pfn) -> Option<(Pfn, *mut Frame)> {
+        let sparse = get_sparse_memory();
+
+        (0..=MAX_ORDER).find_map(|order| {
+            let head  = pfn & !((1usize << order) - 1);
+            let frame = sparse.pfn_to_frame(head)?;
+            let is_head = unsafe { (*frame).tag == BuddyTag::Allocated && (*frame).order as usize >= order };
+            is_head.then_some((head, frame))
+        })
+    }
+
+    /// Takes another reference on the allocated block containing `pfn`.
+    pub fn get_pages(&mut self, pfn: Pfn) {
+        let (_, frame) = Self::block_head(pfn)
+            .unwrap_or_else(|| panic!("get_pages: pfn={} is not allocated", pfn));
+
+        unsafe {
+            (*frame).refcount += 1;
+        }
+    }
+
+    /// References held on the allocated block containing `pfn`.
+    pub fn page_refs(&self, pfn: Pfn) -> u32 {
+        let (_, frame) = Self::block_head(pfn)
+            .unwrap_or_else(|| panic!("page_refs: pfn={} is not allocated", pfn));
+
+        unsafe { (*frame).refcount }
+    }
+
+    /// Drops a reference on the block containing `pfn` and frees it once the
+    /// last one is gone.
+    pub fn put_pages(&mut self, pfn: Pfn) {
+        let (head, frame) = Self::block_head(pfn)
+            .unwrap_or_else(|| panic!("put_pages: pfn={} is not allocated", pfn));
+
+        let last = unsafe {
+            debug_assert!((*frame).refcount > 0, "put_pages: pfn={} has no references", pfn);
+            (*frame).refcount -= 1;
+            (*frame).refcount == 0
+        };
+
+        if last {
+            self.free_pages(head);
+        }
+    }
 }
 
 
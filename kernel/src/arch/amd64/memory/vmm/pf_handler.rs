@@ -1,6 +1,6 @@
 use x86_64::{VirtAddr, registers::control::Cr2};
 
-use crate::{arch::amd64::{cpu::hlt_loop, memory::{pmm::pages_allocator::{PAllocFlags, alloc_pages_by_order}, vmm::{PAGE_SIZE, map_single_page}}, scheduler::{PerCpuSchedulerData, addr_space::{MapFlags, VmaBacking}, task_storage::get_task_by_index}}, early_println, isr};
+use crate::{arch::amd64::{cpu::hlt_loop, scheduler::{PerCpuSchedulerData, addr_space::{AddrSpace, MapFlags, VmaBacking}, fault::raise_fault, task_storage::get_task_by_index}}, early_println, isr};
 
 
 isr!(14, page_fault, |frame| {
@@ -19,48 +19,28 @@ isr!(14, page_fault, |frame| {
 
     let curr_task_id = PerCpuSchedulerData::get().curr_task_id.id();
     let task = get_task_by_index(curr_task_id).unwrap();
-    let mut addr_space = task.tcb.addr_space.lock();
 
-    match addr_space.find(fault_addr) {
-        Some(vma) => {
-            if is_write && !vma.flags.contains(MapFlags::WRITE) {
-                early_println!("PF: write to read-only VMA at {:#x}", fault_addr.as_u64());
-                drop(addr_space);
-                hlt_loop();
-            }
-
-            match vma.backing {
-                VmaBacking::Reserved => {
-                    let phys = alloc_pages_by_order(0, PAllocFlags::ZEROED | PAllocFlags::KERNEL)
-                        .expect("PF: OOM");
-
-                    let page_vaddr = VirtAddr::new(
-                        fault_addr.as_u64() & !(PAGE_SIZE as u64 - 1)
-                    );
+    if populate_reserved(&mut task.tcb.addr_space().lock(), fault_addr, is_present, is_write) {
+        return;
+    }
 
-                    let pt_flags = vma.flags.to_page_table_flags();
+    raise_fault(task, frame, fault_addr.as_u64());
+});
 
-                    map_single_page(&mut addr_space.page_table, page_vaddr, phys, pt_flags)
-                        .expect("PF: map_single_page failed");
+/// Backs the first touch of a `Reserved` VMA page with a zeroed frame.
+/// Returns false for every fault the kernel cannot resolve on its own.
+fn populate_reserved(addr_space: &mut AddrSpace, fault_addr: VirtAddr, is_present: bool, is_write: bool) -> bool {
+    let vma = match addr_space.find(fault_addr) {
+        Some(vma) => vma,
+        None => return false,
+    };
 
-                }
-                VmaBacking::Physical { .. } | VmaBacking::Device { .. } => {
-                    early_println!("PF: physical VMA not mapped at {:#x}", fault_addr.as_u64());
-                    drop(addr_space);
-                    hlt_loop();
-                    //kill_current_task();
-                }
-            }
-        }
-        None => {
-            early_println!(
-                "PF: segfault at {:#x} (no VMA) task={}",
-                fault_addr.as_u64(),
-                curr_task_id
-            );
-            drop(addr_space);
-            hlt_loop();
-            //kill_current_task();
-        }
+    if is_present || !matches!(vma.backing, VmaBacking::Reserved { .. }) {
+        return false;
     }
-});
\ No newline at end of file
+    if is_write && !vma.flags.contains(MapFlags::WRITE) {
+        return false;
+    }
+
+    addr_space.user_phys(fault_addr).is_some()
+}
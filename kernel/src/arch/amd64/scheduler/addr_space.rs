@@ -1,8 +1,14 @@
-use alloc::{collections::BTreeMap, vec::Vec};
-use x86_64::{PhysAddr, VirtAddr, structures::paging::{OffsetPageTable, PageTable, PageTableFlags}};
-use crate::arch::amd64::memory::{misc::virt_to_phys, pmm::pages_allocator::free_pages, vmm::{
+use alloc::{collections::BTreeMap, sync::Arc};
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{OffsetPageTable, PageTable, PageTableFlags, Translate}};
+use crate::arch::amd64::memory::{misc::{phys_to_virt, virt_to_phys}, pmm::pages_allocator::{PAllocFlags, alloc_pages_by_order, free_pages, get_pages, put_pages}, vmm::{
     PAGE_SIZE, map_single_page, unmap_single_page
 }};
+use crate::arch::amd64::{ipc::{message::Rights, object_table::HandleRef}, scheduler::syscall::untyped_handler::carve_page};
+
+/// End of the lower canonical half, the part of every address space that
+/// belongs to user mode.
+pub const USER_ADDR_LIMIT: u64 = 0x0000_8000_0000_0000;
 
 bitflags::bitflags! {
     #[derive(Clone, Copy)]
@@ -26,10 +32,18 @@ impl MapFlags {
     }
 }
 
+/// What a VMA maps. `Physical` maps a frame object handed out by
+/// `frame_alloc`, `Shared` one that other spaces may map as well; either
+/// holds a single reference on the block at `phys_addr`. Pages of a
+/// `Reserved` VMA are allocated on first touch and belong to the space;
+/// they are carved out of `untyped` when set, and taken from the kernel's
+/// allocator only for images and stacks the kernel sets up itself.
+/// `Device` memory is never freed.
 pub enum VmaBacking {
     Physical { phys_addr: PhysAddr },
     Device   { phys_addr: PhysAddr },
-    Reserved,
+    Shared   { phys_addr: PhysAddr },
+    Reserved { untyped: Option<HandleRef> },
 }
 
 pub struct Vma {
@@ -37,6 +51,9 @@ pub struct Vma {
     pub size:    usize,
     pub flags:   MapFlags,
     pub backing: VmaBacking,
+    /// Rights of the cap the mapping was made with; `protect` may not give
+    /// it access beyond them.
+    pub rights:  Rights,
 }
 
 impl Vma {
@@ -51,6 +68,10 @@ impl Vma {
     }
 }
 
+/// Address space shared by every thread configured with it and by the VSpace
+/// caps naming it.
+pub type VSpaceRef = Arc<Mutex<AddrSpace>>;
+
 pub struct AddrSpace {
     vmas:       BTreeMap<u64, Vma>,   
     pub page_table: OffsetPageTable<'static>,
@@ -69,6 +90,10 @@ impl AddrSpace {
         Self { vmas: BTreeMap::new(), page_table }
     }
 
+    pub fn into_ref(self) -> VSpaceRef {
+        Arc::new(Mutex::new(self))
+    }
+
     pub fn get_page_table_phys(&self) -> PhysAddr {
         let virt = self.page_table.level_4_table() as *const PageTable as u64;
         PhysAddr::new(virt_to_phys(virt as usize) as u64)
@@ -80,12 +105,13 @@ impl AddrSpace {
         size:    usize,
         backing: VmaBacking,
         flags:   MapFlags,
+        rights:  Rights,
     ) -> Result<(), VmaError> {
         if !vaddr.is_aligned(PAGE_SIZE as u64) || size % PAGE_SIZE != 0 {
             return Err(VmaError::NotAligned);
         }
 
-        let vma = Vma { vaddr, size, flags, backing };
+        let vma = Vma { vaddr, size, flags, backing, rights };
 
         if self.find_overlapping(&vma).is_some() {
             return Err(VmaError::Overlap);
@@ -94,6 +120,10 @@ impl AddrSpace {
         self.map_in_page_table(&vma)
             .map_err(VmaError::PageTableError)?;
 
+        if let VmaBacking::Physical { phys_addr } | VmaBacking::Shared { phys_addr } = vma.backing {
+            get_pages(phys_addr);
+        }
+
         self.vmas.insert(vaddr.as_u64(), vma);
         Ok(())
     }
@@ -101,20 +131,8 @@ impl AddrSpace {
     pub fn unmap(&mut self, vaddr: VirtAddr) -> Result<(), VmaError> {
         let vma = self.vmas.remove(&vaddr.as_u64())
             .ok_or(VmaError::NotFound)?;
-        
-        let pages = vma.size / PAGE_SIZE;
-        for i in 0..pages {
-            let va = VirtAddr::new(vma.vaddr.as_u64() + (i * PAGE_SIZE) as u64);
-            match &vma.backing {
-                VmaBacking::Reserved => {
-                    let _ = unmap_single_page(&mut self.page_table, va);
-                }
-                VmaBacking::Physical { .. } | VmaBacking::Device { .. } => {
-                    unmap_single_page(&mut self.page_table, va)
-                        .map_err(VmaError::PageTableError)?;
-                }
-            }
-        }
+
+        self.unmap_vma(&vma);
         Ok(())
     }
 
@@ -134,10 +152,11 @@ impl AddrSpace {
 
             let phys = match &vma.backing {
                 VmaBacking::Physical { phys_addr } |
-                VmaBacking::Device   { phys_addr } => {
+                VmaBacking::Device   { phys_addr } |
+                VmaBacking::Shared   { phys_addr } => {
                     PhysAddr::new(phys_addr.as_u64() + (i * PAGE_SIZE) as u64)
                 }
-                VmaBacking::Reserved => continue,
+                VmaBacking::Reserved { .. } => continue,
             };
 
             unmap_single_page(&mut self.page_table, va)
@@ -159,6 +178,67 @@ impl AddrSpace {
             .filter(|vma| vma.contains(addr))
     }
 
+    /// Physical address behind the user address `vaddr`. An untouched page of
+    /// a `Reserved` VMA is allocated and mapped first, as a fault would.
+    pub fn user_phys(&mut self, vaddr: VirtAddr) -> Option<PhysAddr> {
+        if let Some(phys) = self.page_table.translate_addr(vaddr) {
+            return Some(phys);
+        }
+
+        let vma = self.find(vaddr)?;
+        let untyped = match vma.backing {
+            VmaBacking::Reserved { untyped } => untyped,
+            _ => return None,
+        };
+        let pt_flags = vma.flags.to_page_table_flags();
+
+        let phys = match untyped {
+            Some(untyped) => carve_page(untyped)?,
+            None          => alloc_pages_by_order(0, PAllocFlags::ZEROED | PAllocFlags::KERNEL)?,
+        };
+        let page = vaddr.align_down(PAGE_SIZE as u64);
+        if map_single_page(&mut self.page_table, page, phys, pt_flags).is_err() {
+            put_pages(phys);
+            return None;
+        }
+        Some(phys + (vaddr - page))
+    }
+
+    /// Copies `src` to the user address `vaddr`, page by page through
+    /// `user_phys`. Fails if part of the range is not mapped.
+    pub fn write_user(&mut self, vaddr: u64, src: &[u8]) -> Option<()> {
+        let mut done = 0;
+        while done < src.len() {
+            let (dst, len) = self.user_chunk(vaddr, done, src.len())?;
+            unsafe { core::ptr::copy_nonoverlapping(src[done..].as_ptr(), dst, len) };
+            done += len;
+        }
+        Some(())
+    }
+
+    /// Copies `dst.len()` bytes from the user address `vaddr` into `dst`.
+    pub fn read_user(&mut self, vaddr: u64, dst: &mut [u8]) -> Option<()> {
+        let mut done = 0;
+        while done < dst.len() {
+            let (src, len) = self.user_chunk(vaddr, done, dst.len())?;
+            unsafe { core::ptr::copy_nonoverlapping(src, dst[done..].as_mut_ptr(), len) };
+            done += len;
+        }
+        Some(())
+    }
+
+    /// Kernel pointer to byte `done` of a `total` byte user range at `vaddr`
+    /// and how much of the range is left in that page.
+    fn user_chunk(&mut self, vaddr: u64, done: usize, total: usize) -> Option<(*mut u8, usize)> {
+        let va = VirtAddr::try_new(vaddr.checked_add(done as u64)?).ok()?;
+        if va.as_u64() >= USER_ADDR_LIMIT {
+            return None;
+        }
+        let in_page = PAGE_SIZE - (va.as_u64() as usize % PAGE_SIZE);
+        let phys = self.user_phys(va)?;
+        Some((phys_to_virt(phys.as_u64() as usize) as *mut u8, in_page.min(total - done)))
+    }
+
     fn map_in_page_table(&mut self, vma: &Vma) -> Result<(), &'static str> {
         let pages    = vma.size / PAGE_SIZE;
         let pt_flags = vma.flags.to_page_table_flags();
@@ -168,17 +248,37 @@ impl AddrSpace {
 
             match &vma.backing {
                 VmaBacking::Physical { phys_addr } |
-                VmaBacking::Device   { phys_addr } => {
+                VmaBacking::Device   { phys_addr } |
+                VmaBacking::Shared   { phys_addr } => {
                     let pa = PhysAddr::new(phys_addr.as_u64() + (i * PAGE_SIZE) as u64);
                     map_single_page(&mut self.page_table, va, pa, pt_flags)?;
                 }
-                VmaBacking::Reserved => {
+                VmaBacking::Reserved { .. } => {
                 }
             }
         }
         Ok(())
     }
 
+    /// Tears down the page table entries of `vma` and drops the frame
+    /// references it held.
+    fn unmap_vma(&mut self, vma: &Vma) {
+        let pages = vma.size / PAGE_SIZE;
+
+        for i in 0..pages {
+            let va = VirtAddr::new(vma.vaddr.as_u64() + (i * PAGE_SIZE) as u64);
+            let unmapped = unmap_single_page(&mut self.page_table, va);
+
+            if let (VmaBacking::Reserved { .. }, Ok(pa)) = (&vma.backing, unmapped) {
+                put_pages(pa);
+            }
+        }
+
+        if let VmaBacking::Physical { phys_addr } | VmaBacking::Shared { phys_addr } = vma.backing {
+            put_pages(phys_addr);
+        }
+    }
+
     fn find_overlapping(&self, new: &Vma) -> Option<&Vma> {
         self.vmas
             .range(..new.end().as_u64())
@@ -190,34 +290,13 @@ impl AddrSpace {
 
 impl Drop for AddrSpace {
     fn drop(&mut self) {
-        let vaddrs: Vec<u64> = self.vmas.keys().copied().collect();
-        
-        for vaddr in vaddrs {
-            let vma = self.vmas.remove(&vaddr).unwrap();
-            let pages = vma.size / PAGE_SIZE;
-            
-            for i in 0..pages {
-                let va = VirtAddr::new(vma.vaddr.as_u64() + (i * PAGE_SIZE) as u64);
-                
-                match &vma.backing {
-                    VmaBacking::Physical { .. } => {
-                        if let Ok(pa) = unmap_single_page(&mut self.page_table, va) {
-                            free_pages(pa);
-                        }
-                    }
-                    VmaBacking::Device { .. } => {
-                        let _ = unmap_single_page(&mut self.page_table, va);
-                    }
-                    VmaBacking::Reserved => {
-                        if let Ok(pa) = unmap_single_page(&mut self.page_table, va) {
-                            free_pages(pa);
-                        }
-                    }
-                }
-            }
+        let vmas = core::mem::take(&mut self.vmas);
+
+        for vma in vmas.values() {
+            self.unmap_vma(vma);
         }
-        
+
         let pt_phys = self.get_page_table_phys();
         free_pages(pt_phys);
     }
-}
\ No newline at end of file
+}
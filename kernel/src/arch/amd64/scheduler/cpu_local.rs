@@ -1,110 +1,66 @@
-use core::{ptr::null_mut, sync::atomic::{AtomicPtr, AtomicUsize, Ordering, fence}};
+use core::{ptr::null_mut, sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering}};
 
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
-use crate::{arch::amd64::scheduler::task::Task};
+use alloc::{boxed::Box, collections::{BTreeSet, VecDeque}, sync::Arc, vec::Vec};
+use spin::Mutex;
+use crate::{arch::amd64::scheduler::task::{NUM_PRIORITIES, Task, TaskIdIndex}};
 
-pub const RQ_CAP: usize = 1024;
+const BITMAP_WORDS: usize = NUM_PRIORITIES / 64;
 
+/// One FIFO queue per priority and a bitmap of the non-empty ones, so the
+/// highest ready priority is found without walking all levels. A level's
+/// bit only changes under that level's lock.
 pub struct Runqueue {
-    top: AtomicUsize,
-    bottom: AtomicUsize,
-    buf: [AtomicPtr<Task>; RQ_CAP],
+    levels: [Mutex<VecDeque<Arc<Task>>>; NUM_PRIORITIES],
+    bitmap: [AtomicU64; BITMAP_WORDS],
+    len: AtomicUsize,
 }
 
 impl Runqueue {
-    pub const fn new() -> Self {
-        const NULL: AtomicPtr<Task> = AtomicPtr::new(null_mut());
+    pub fn new() -> Self {
         Self {
-            top: AtomicUsize::new(0),
-            bottom: AtomicUsize::new(0),
-            buf: [NULL; RQ_CAP],
+            levels: core::array::from_fn(|_| Mutex::new(VecDeque::new())),
+            bitmap: core::array::from_fn(|_| AtomicU64::new(0)),
+            len: AtomicUsize::new(0),
         }
     }
 
+    /// Queues `task` behind the others of its priority.
     pub fn push(&self, task: Arc<Task>) {
-        let ptr = Arc::into_raw(task) as *mut Task;
-        let b = self.bottom.load(Ordering::Relaxed);
-        let t = self.top.load(Ordering::Acquire);
+        let prio = task.tcb.priority() as usize;
+        let mut level = self.levels[prio].lock();
+        level.push_back(task);
+        self.mark(prio, true);
+        self.len.fetch_add(1, Ordering::Relaxed);
+    }
 
-        if b.wrapping_sub(t) >= RQ_CAP {
-            panic!("Runqueue overflow! Bottom: {} Top: {}", b, t);
-        }
-        
-        self.buf[b % RQ_CAP].store(ptr, Ordering::Relaxed);
-        fence(Ordering::Release);
-        self.bottom.store(b.wrapping_add(1), Ordering::Relaxed);
+    /// Queues `task` ahead of the others of its priority, for a task that was
+    /// preempted before its time slice ran out.
+    pub fn push_front(&self, task: Arc<Task>) {
+        let prio = task.tcb.priority() as usize;
+        let mut level = self.levels[prio].lock();
+        level.push_front(task);
+        self.mark(prio, true);
+        self.len.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Takes the oldest task of the highest non-empty priority.
     pub fn pop(&self) -> Option<Arc<Task>> {
-        // Load bottom and top BEFORE any decrement.
-        // If the queue is already empty (b == t) we must NOT decrement b,
-        // because wrapping_sub(1) when b == 0 produces usize::MAX, making the
-        // unsigned `t <= b` check spuriously true and leaving bottom corrupted.
-        let b_orig = self.bottom.load(Ordering::Relaxed);
-        let t_snap  = self.top.load(Ordering::Acquire);
-
-        if b_orig == t_snap {
-            return None; // empty — nothing to do
-        }
-
-        let b = b_orig.wrapping_sub(1);
-        self.bottom.store(b, Ordering::Relaxed);
-
-        // SeqCst fence so stealers see the new bottom before we read top,
-        // preventing a double-claim of the last element.
-        fence(Ordering::SeqCst);
-        let t = self.top.load(Ordering::Relaxed);
-
-        if t <= b {
-            let ptr = self.buf[b % RQ_CAP].load(Ordering::Relaxed);
-
-            if t == b {
-                // Last element: race with stealers via CAS.
-                let won = self.top
-                    .compare_exchange(t, t.wrapping_add(1),
-                                      Ordering::SeqCst,
-                                      Ordering::Relaxed)
-                    .is_ok();
-                // Restore bottom regardless — top advanced (or stealer took it).
-                self.bottom.store(b.wrapping_add(1), Ordering::Relaxed);
-                if !won {
-                    return None;
-                }
+        loop {
+            let prio = self.highest_priority()? as usize;
+            let mut level = self.levels[prio].lock();
+            let task = level.pop_front();
+            if level.is_empty() {
+                self.mark(prio, false);
             }
-
-            debug_assert!(!ptr.is_null(), "runqueue: null pointer in occupied slot");
-            if ptr.is_null() {
-                return None;
+            if let Some(task) = task {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                return Some(task);
             }
-            Some(unsafe { Arc::from_raw(ptr) })
-        } else {
-            // All remaining elements were stolen while we decremented; restore.
-            self.bottom.store(b.wrapping_add(1), Ordering::Relaxed);
-            None
         }
     }
 
     pub fn steal(&self) -> Option<Arc<Task>> {
-        loop {
-            let t = self.top.load(Ordering::Acquire);
-            fence(Ordering::SeqCst);
-            let b = self.bottom.load(Ordering::Acquire);
-            
-            if t >= b {
-                return None; 
-            }
-            
-            let ptr = self.buf[t % RQ_CAP].load(Ordering::Relaxed);
-            
-            if self.top.compare_exchange(t, t.wrapping_add(1), 
-                                         Ordering::SeqCst, 
-                                         Ordering::Relaxed).is_ok() {
-                if ptr.is_null() {
-                    continue;
-                }
-                return Some(unsafe { Arc::from_raw(ptr) });
-            }
-        }
+        self.pop()
     }
 
     pub fn steal_n(&self, n: usize) -> Vec<Arc<Task>> {
@@ -119,16 +75,78 @@ impl Runqueue {
     }
 
     pub fn len(&self) -> usize {
-        let b = self.bottom.load(Ordering::Relaxed);
-        let t = self.top.load(Ordering::Acquire);
-        b.wrapping_sub(t)
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Highest priority with a queued task. Queue entries of tasks that were
+    /// suspended meanwhile count as well.
+    pub fn highest_priority(&self) -> Option<u8> {
+        (0..BITMAP_WORDS).rev().find_map(|word| {
+            let bits = self.bitmap[word].load(Ordering::Acquire);
+            (bits != 0).then(|| (word * 64 + 63 - bits.leading_zeros() as usize) as u8)
+        })
+    }
+
+    fn mark(&self, prio: usize, non_empty: bool) {
+        let bit = 1u64 << (prio % 64);
+        if non_empty {
+            self.bitmap[prio / 64].fetch_or(bit, Ordering::Release);
+        } else {
+            self.bitmap[prio / 64].fetch_and(!bit, Ordering::Release);
+        }
+    }
+}
+
+/// Wakeups due on one CPU, earliest deadline first. Deadlines are in
+/// nanoseconds of `now_ns`. Cancelling or re-arming a timeout removes its
+/// entry, so the earliest one is always a wakeup still wanted.
+pub struct TimerQueue {
+    entries: Mutex<BTreeSet<(u64, TaskIdIndex)>>,
+}
+
+impl TimerQueue {
+    pub fn new() -> Self {
+        Self { entries: Mutex::new(BTreeSet::new()) }
+    }
+
+    pub fn insert(&self, deadline: u64, task: TaskIdIndex) {
+        self.entries.lock().insert((deadline, task));
+    }
+
+    pub fn remove(&self, deadline: u64, task: TaskIdIndex) {
+        self.entries.lock().remove(&(deadline, task));
+    }
+
+    /// Takes the earliest entry if it is due at `now`.
+    pub fn pop_due(&self, now: u64) -> Option<(u64, TaskIdIndex)> {
+        let mut entries = self.entries.lock();
+        match entries.first() {
+            Some(&(deadline, _)) if deadline <= now => entries.pop_first(),
+            _ => None,
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.entries.lock().first().map(|&(deadline, _)| deadline)
     }
 }
 
 pub struct ExecCpu {
     pub tasks: Runqueue,
-    pub curr_task: *mut Task,
-    pub idle_task: Box<Task>
+    pub timers: TimerQueue,
+    /// Task running on the CPU, null while it idles. Other CPUs only ever
+    /// compare it with null.
+    curr_task: AtomicPtr<Task>,
+    pub idle_task: Box<Task>,
+    /// LAPIC ID, for sending the CPU a reschedule IPI.
+    pub apic_id: AtomicU32,
+    /// When the current task's running time was last charged, in
+    /// nanoseconds of `now_ns`.
+    pub accounted_at: u64,
+    /// Reference of the last task that left the CPU without being queued
+    /// again. It is dropped on the idle task's stack, so an exited task's
+    /// kernel stack is never freed while still in use.
+    pub retired: Option<Arc<Task>>,
 }
 
 unsafe impl Send for ExecCpu {}
@@ -138,8 +156,12 @@ impl ExecCpu {
     pub fn new(idle_task: Task) -> Self {
         Self {
             tasks: Runqueue::new(),
-            curr_task: null_mut(),
-            idle_task: Box::new(idle_task)
+            timers: TimerQueue::new(),
+            curr_task: AtomicPtr::new(null_mut()),
+            idle_task: Box::new(idle_task),
+            apic_id: AtomicU32::new(0),
+            accounted_at: 0,
+            retired: None,
         }
     }
 
@@ -150,10 +172,22 @@ impl ExecCpu {
     }
 
     pub fn get_curr_task(&self) -> *mut Task {
-        return self.curr_task
+        return self.curr_task.load(Ordering::Acquire)
     }
 
     pub fn set_curr_task(&mut self, curr_task: *mut Task) {
-        self.curr_task = curr_task;
+        self.curr_task.store(curr_task, Ordering::Release);
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.get_curr_task().is_null()
+    }
+
+    /// Clears the current task, keeping its run reference in `retired`.
+    pub fn retire_curr_task(&mut self) {
+        let curr = self.curr_task.swap(null_mut(), Ordering::AcqRel);
+        if !curr.is_null() {
+            self.retired = Some(unsafe { Arc::from_raw(curr) });
+        }
     }
 }
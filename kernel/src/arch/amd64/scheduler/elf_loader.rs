@@ -0,0 +1,106 @@
+use alloc::vec::Vec;
+use elf::{ElfBytes, abi::{EM_X86_64, ET_EXEC, PF_R, PF_W, PF_X, PT_LOAD}, endian::LittleEndian, file::Class, segment::ProgramHeader};
+use x86_64::VirtAddr;
+
+use crate::arch::amd64::{
+    ipc::{message::Rights, object_table::HandleRef},
+    memory::vmm::PAGE_SIZE,
+    scheduler::addr_space::{AddrSpace, MapFlags, USER_ADDR_LIMIT, VmaBacking, VmaError},
+};
+
+const USER_STACK_PAGES_COUNT: usize = 4;
+const USER_STACK_SIZE: usize = USER_STACK_PAGES_COUNT * PAGE_SIZE;
+const USER_STACK_TOP_VIRT_ADDR: u64 = 0x7FFF_FFFF_0000;
+
+#[derive(Debug)]
+pub enum ElfLoadError {
+    /// Not an ELF file, or its headers are cut short.
+    Malformed,
+    /// A valid ELF, but not a static x86_64 executable.
+    Unsupported,
+    /// A `PT_LOAD` segment lies outside the user half or past the file.
+    BadSegment,
+    Vma(VmaError),
+}
+
+impl From<VmaError> for ElfLoadError {
+    fn from(e: VmaError) -> Self {
+        ElfLoadError::Vma(e)
+    }
+}
+
+fn segment_flags(phdr: &ProgramHeader) -> MapFlags {
+    let mut flags = MapFlags::USER;
+    if phdr.p_flags & PF_R != 0 { flags |= MapFlags::READ; }
+    if phdr.p_flags & PF_W != 0 { flags |= MapFlags::WRITE; }
+    if phdr.p_flags & PF_X != 0 { flags |= MapFlags::EXEC; }
+    flags
+}
+
+/// Page aligned user range a segment covers.
+fn segment_range(phdr: &ProgramHeader, image_len: usize) -> Result<(VirtAddr, usize), ElfLoadError> {
+    let file_end = phdr.p_offset.checked_add(phdr.p_filesz).ok_or(ElfLoadError::BadSegment)?;
+    let mem_end = phdr.p_vaddr.checked_add(phdr.p_memsz).ok_or(ElfLoadError::BadSegment)?;
+    if phdr.p_filesz > phdr.p_memsz || file_end > image_len as u64 || mem_end > USER_ADDR_LIMIT {
+        return Err(ElfLoadError::BadSegment);
+    }
+
+    let start = phdr.p_vaddr & !(PAGE_SIZE as u64 - 1);
+    let end = mem_end.next_multiple_of(PAGE_SIZE as u64);
+    Ok((VirtAddr::new(start), (end - start) as usize))
+}
+
+/// Maps every `PT_LOAD` segment of `image` into `vspace` with the segment's
+/// own permissions and returns the entry point. Segments become demand paged
+/// VMAs whose file part is copied in now; the rest stays zero. Segments may
+/// not share a page. Their pages are carved out of `untyped` when one is
+/// given. On failure nothing of the image stays mapped.
+pub fn load_elf(vspace: &mut AddrSpace, image: &[u8], untyped: Option<HandleRef>) -> Result<u64, ElfLoadError> {
+    let file = ElfBytes::<LittleEndian>::minimal_parse(image).map_err(|_| ElfLoadError::Malformed)?;
+    let ehdr = &file.ehdr;
+    if ehdr.class != Class::ELF64 || ehdr.e_machine != EM_X86_64 || ehdr.e_type != ET_EXEC {
+        return Err(ElfLoadError::Unsupported);
+    }
+    if ehdr.e_entry >= USER_ADDR_LIMIT {
+        return Err(ElfLoadError::BadSegment);
+    }
+
+    let segments = file.segments().ok_or(ElfLoadError::Malformed)?;
+    let mut mapped = Vec::new();
+
+    let loaded = segments.iter()
+        .filter(|phdr| phdr.p_type == PT_LOAD && phdr.p_memsz != 0)
+        .try_for_each(|phdr| {
+            let (vaddr, size) = segment_range(&phdr, image.len())?;
+            vspace.map(vaddr, size, VmaBacking::Reserved { untyped }, segment_flags(&phdr), Rights::ALL)?;
+            mapped.push(vaddr);
+
+            let data = &image[phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize];
+            vspace.write_user(phdr.p_vaddr, data).ok_or(ElfLoadError::BadSegment)
+        });
+
+    if let Err(e) = loaded {
+        for vaddr in mapped {
+            let _ = vspace.unmap(vaddr);
+        }
+        return Err(e);
+    }
+    Ok(ehdr.e_entry)
+}
+
+fn user_stack_bottom() -> VirtAddr {
+    VirtAddr::new(USER_STACK_TOP_VIRT_ADDR - USER_STACK_SIZE as u64)
+}
+
+/// Reserves the user stack below `USER_STACK_TOP_VIRT_ADDR` and returns the
+/// initial `rsp`, placed as if `_start` had been called. Like the image, its
+/// pages are charged to `untyped` when one is given.
+pub fn map_user_stack(vspace: &mut AddrSpace, untyped: Option<HandleRef>) -> Result<u64, VmaError> {
+    let flags = MapFlags::USER | MapFlags::READ | MapFlags::WRITE;
+    vspace.map(user_stack_bottom(), USER_STACK_SIZE, VmaBacking::Reserved { untyped }, flags, Rights::ALL)?;
+    Ok(USER_STACK_TOP_VIRT_ADDR - 8)
+}
+
+pub fn unmap_user_stack(vspace: &mut AddrSpace) {
+    let _ = vspace.unmap(user_stack_bottom());
+}
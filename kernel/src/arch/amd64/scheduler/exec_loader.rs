@@ -1,18 +1,17 @@
-use core::{arch::naked_asm, cell::UnsafeCell, sync::atomic::AtomicU64};
+use alloc::sync::Arc;
+use core::{arch::naked_asm, cell::UnsafeCell, sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize}};
 
 use spin::Mutex;
 use x86_64::{PhysAddr, VirtAddr, instructions::interrupts, registers::control::Cr3, structures::paging::{Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB}};
 
-use crate::arch::amd64::{ipc::{cnode::CNode, message::{Capability, Rights}, object_table::{KernelObjType, KernelObject, ObjData, obj_insert}}, memory::{misc::{pages_to_order, phys_to_virt}, pmm::{HHDM_OFFSET, pages_allocator::{PAllocFlags, alloc_pages_by_order}}, vmm::{KernelFrameAllocator, PAGE_SIZE, create_new_pt4_from_kernel_pt4, kernel_pt}}, scheduler::{addr_space::AddrSpace, stack::{DEFAULT_KERNEL_STACK_SIZE, allocate_kernel_stack}, task::{AtomicTaskState, Task, TaskId, TaskIdIndex, TaskRegisters, TaskState, Tcb}}};
+use crate::arch::amd64::{gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR}, ipc::{cnode::{CNode, CNodeRef}, mdb::{MDB, MDB_NONE}, message::{Capability, Rights}, object_table::{KernelObjType, KernelObject, ObjData, obj_insert}}, memory::{misc::phys_to_virt, pmm::{HHDM_OFFSET, pages_allocator::{PAllocFlags, alloc_pages_by_order}}, vmm::{KernelFrameAllocator, create_new_pt4_from_kernel_pt4, kernel_pt}}, scheduler::{addr_space::{AddrSpace, VSpaceRef}, elf_loader::{load_elf, map_user_stack}, sched_context::{SchedContext, SchedContextRef}, stack::{DEFAULT_KERNEL_STACK_SIZE, allocate_kernel_stack}, task::{AtomicKernelEntry, AtomicTaskState, DEFAULT_PRIORITY, KernelEntry, MAX_PRIORITY, NO_CPU, TIME_SLICE_NS, Task, TaskId, TaskIdIndex, TaskRegisters, TaskState, Tcb}}};
 
 const RFLAGS_WITH_IR: u64 = 0x202;
-const USER_STACK_PAGES_COUNT: usize = 4;
-const USER_STACK_TOP_VIRT_ADDR: u64 = 0x7FFF_FFFF_0000;
-
-pub const USER_LOAD_VADDR: u64 = 0x400000;
-pub const USER_ENTRY_VADDR: u64 = USER_LOAD_VADDR; 
 pub const BOOTINFO_VADDR: u64 = 0x1000;
 
+/// Size of the untyped block handed to init, as a buddy order (4 MiB).
+pub const INIT_UNTYPED_ORDER: usize = 10;
+
 fn phys_to_offset_page_table(table: PhysAddr) -> OffsetPageTable<'static> {
     let phys_offset = kernel_pt().lock().phys_offset();
     let virt = phys_offset + table.as_u64();
@@ -26,122 +25,79 @@ pub struct InitSvrsBootInfo {
     pub self_vspace_cap: u64,
     pub self_cnode_cap:  u64,
 
-    cpio_base_addr: u64
+    cpio_base_addr: u64,
+
+    pub untyped_cap: u64,
+    /// Scheduling context init runs on, bound to it at boot.
+    pub sched_context_cap: u64,
 }
 
-pub fn make_init_caps(task_id: TaskIdIndex, cnode: &mut CNode) -> InitSvrsBootInfo {
+pub fn make_init_caps(task_id: TaskIdIndex, cnode_ref: &CNodeRef, vspace: &VSpaceRef, sc: &SchedContextRef) -> InitSvrsBootInfo {
     let tcb_handle = obj_insert(KernelObject::new(
         KernelObjType::Thread,
         ObjData::Thread(task_id),
-    )).expect("object table full");
+    )).unwrap_or_else(|_| panic!("object table full"));
 
     let vspace_handle = obj_insert(KernelObject::new(
         KernelObjType::VSpace,
-        ObjData::VSpace(task_id),
-    )).expect("object table full");
+        ObjData::VSpace(vspace.clone()),
+    )).unwrap_or_else(|_| panic!("object table full"));
 
     let cnode_handle = obj_insert(KernelObject::new(
         KernelObjType::CNode,
-        ObjData::CNode(task_id),
-    )).expect("object table full");
+        ObjData::CNode(cnode_ref.clone()),
+    )).unwrap_or_else(|_| panic!("object table full"));
+
+    let untyped_phys = alloc_pages_by_order(INIT_UNTYPED_ORDER, PAllocFlags::KERNEL | PAllocFlags::ZEROED)
+        .expect("make_init_caps: untyped OOM");
+
+    let untyped_handle = obj_insert(KernelObject::new(
+        KernelObjType::Untyped,
+        ObjData::Untyped { phys: untyped_phys, pages: 1 << INIT_UNTYPED_ORDER, used: 0 },
+    )).unwrap_or_else(|_| panic!("object table full"));
+
+    let sc_handle = obj_insert(KernelObject::new(
+        KernelObjType::SchedContext,
+        ObjData::SchedContext(sc.clone()),
+    )).unwrap_or_else(|_| panic!("object table full"));
 
     let tcb_cap = Capability::new(tcb_handle, Rights::ALL);
     let vspace_cap = Capability::new(vspace_handle, Rights::ALL);
     let cnode_cap = Capability::new(cnode_handle, Rights::ALL);
+    let untyped_cap = Capability::new(untyped_handle, Rights::ALL);
+    let sc_cap = Capability::new(sc_handle, Rights::ALL);
 
-    let self_tcb_cap = cnode.alloc(tcb_cap).expect("cnode full") as u64;
-    let self_vspace_cap = cnode.alloc(vspace_cap).expect("cnode full") as u64;
-    let self_cnode_cap = cnode.alloc(cnode_cap).expect("cnode full") as u64;
+    let mut mdb = MDB.lock();
+    let mut cnode = cnode_ref.lock();
+    let self_tcb_cap = mdb.install(&mut cnode, cnode_ref, MDB_NONE, tcb_cap).expect("cnode full") as u64;
+    let self_vspace_cap = mdb.install(&mut cnode, cnode_ref, MDB_NONE, vspace_cap).expect("cnode full") as u64;
+    let self_cnode_cap = mdb.install(&mut cnode, cnode_ref, MDB_NONE, cnode_cap).expect("cnode full") as u64;
+    let untyped_cap = mdb.install(&mut cnode, cnode_ref, MDB_NONE, untyped_cap).expect("cnode full") as u64;
+    let sched_context_cap = mdb.install(&mut cnode, cnode_ref, MDB_NONE, sc_cap).expect("cnode full") as u64;
 
     InitSvrsBootInfo {
         self_tcb_cap,
         self_vspace_cap,
         self_cnode_cap,
         cpio_base_addr: 0,
+        untyped_cap,
+        sched_context_cap,
     }
 }
 
+/// Builds init from the ELF `image`: its segments, a user stack and the
+/// read-only boot info page at `BOOTINFO_VADDR`, passed in `rdi`.
 pub fn make_init_task(
-    bytes: &[u8],
+    image: &[u8],
     task_id: TaskIdIndex,
     cpio_baddr: u64
 ) -> Result<Task, &'static str> {
     let new_pml4_phys = create_new_pt4_from_kernel_pt4();
     let mut pt = phys_to_offset_page_table(new_pml4_phys);
 
-    let page_count = bytes.len().div_ceil(PAGE_SIZE);
-    for i in 0..page_count {
-        let va = VirtAddr::new(USER_LOAD_VADDR + (i * PAGE_SIZE) as u64);
-        let page = Page::<Size4KiB>::containing_address(va);
-
-        let phys = alloc_pages_by_order(0, PAllocFlags::KERNEL | PAllocFlags::ZEROED)
-            .expect("make_init_task: OOM");
-        let frame = PhysFrame::<Size4KiB>::containing_address(phys);
-
-        let src_offset = i * PAGE_SIZE;
-        let src_end = (src_offset + PAGE_SIZE).min(bytes.len());
-        let copy_len = src_end - src_offset;
-
-        unsafe {
-            let dst = phys_to_virt(phys.as_u64() as usize) as *mut u8;
-            core::ptr::copy_nonoverlapping(
-                bytes.as_ptr().add(src_offset),
-                dst,
-                copy_len,
-            );
-
-            pt.map_to(
-                page,
-                frame,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::USER_ACCESSIBLE
-                    | PageTableFlags::WRITABLE,
-                &mut KernelFrameAllocator,
-            )
-            .unwrap()
-            .flush();
-        }
-    }
-
-    let order = pages_to_order(USER_STACK_PAGES_COUNT);
-    let stack_bottom_phys = alloc_pages_by_order(order, PAllocFlags::KERNEL | PAllocFlags::ZEROED)
-        .expect("make_init_task: stack OOM");
-
-    let stack_size   = PAGE_SIZE * USER_STACK_PAGES_COUNT;
-    let stack_top_va = USER_STACK_TOP_VIRT_ADDR;
-    let stack_bot_va = stack_top_va - stack_size as u64;
-
-    let stack_bot_page = Page::<Size4KiB>::containing_address(VirtAddr::new(stack_bot_va));
-    let stack_top_page = Page::<Size4KiB>::containing_address(VirtAddr::new(stack_top_va - 1));
-
-    let stack_flags = PageTableFlags::PRESENT
-        | PageTableFlags::USER_ACCESSIBLE
-        | PageTableFlags::WRITABLE
-        | PageTableFlags::NO_EXECUTE;
-
-    let mut curr_phys = stack_bottom_phys.as_u64();
-    for page in Page::range_inclusive(stack_bot_page, stack_top_page) {
-        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(curr_phys));
-        curr_phys += PAGE_SIZE as u64;
-        unsafe {
-            pt.map_to(page, frame, stack_flags, &mut KernelFrameAllocator)
-                .unwrap()
-                .flush();
-        }
-    }
-
     let bootinfo_phys = alloc_pages_by_order(0, PAllocFlags::KERNEL | PAllocFlags::ZEROED)
         .expect("make_init_task: bootinfo OOM");
 
-    let mut cnode = CNode::new();
-
-    let mut boot_info_svrs = make_init_caps(task_id, &mut cnode);
-    boot_info_svrs.cpio_base_addr = cpio_baddr;
-    unsafe {
-        let dst = phys_to_virt(bootinfo_phys.as_u64() as usize) as *mut InitSvrsBootInfo;
-        core::ptr::write(dst, boot_info_svrs);
-    }
-
     let bootinfo_page = Page::<Size4KiB>::containing_address(VirtAddr::new(BOOTINFO_VADDR));
     let bootinfo_frame = PhysFrame::<Size4KiB>::containing_address(bootinfo_phys);
 
@@ -157,13 +113,28 @@ pub fn make_init_task(
         .flush();
     }
 
+    let mut addr_space = AddrSpace::new(pt);
+    let entry = load_elf(&mut addr_space, image, None).map_err(|_| "init image is not a loadable ELF")?;
+    let stack_top = map_user_stack(&mut addr_space, None).map_err(|_| "init stack does not fit")?;
+    let vspace = addr_space.into_ref();
+    let cnode = CNode::new_root().into_ref();
+
+    let sched_context = Arc::new(SchedContext::bound_to(task_id));
+
+    let mut boot_info_svrs = make_init_caps(task_id, &cnode, &vspace, &sched_context);
+    boot_info_svrs.cpio_base_addr = cpio_baddr;
+    unsafe {
+        let dst = phys_to_virt(bootinfo_phys.as_u64() as usize) as *mut InitSvrsBootInfo;
+        core::ptr::write(dst, boot_info_svrs);
+    }
+
     // kernel stack + trampoline
     let kernel_stack = allocate_kernel_stack(DEFAULT_KERNEL_STACK_SIZE);
     let stack_top_ptr = kernel_stack.top.as_u64() as *mut u64;
 
     unsafe {
-        stack_top_ptr.sub(1).write(stack_top_va - 8);           // rsp
-        stack_top_ptr.sub(2).write(USER_ENTRY_VADDR);           // rip
+        stack_top_ptr.sub(1).write(stack_top);                  // rsp
+        stack_top_ptr.sub(2).write(entry);                      // rip
         stack_top_ptr.sub(3).write(user_task_trampoline as u64);// ret
         for i in 4..=18 {
             stack_top_ptr.sub(i).write(0);
@@ -181,15 +152,90 @@ pub fn make_init_task(
             ..Default::default()
         }),
         tcb: Tcb {
-            wake_at_tick: Mutex::new(AtomicU64::new(0)),
-            addr_space: Mutex::new(AddrSpace::new(pt)),
+            wake_at: Mutex::new(AtomicU64::new(0)),
+            wake_cpu: AtomicUsize::new(NO_CPU),
+            addr_space: Mutex::new(vspace),
             kernel_stack,
             cnode: Mutex::new(cnode),
-            task_state: AtomicTaskState::new(TaskState::Ready)
+            task_state: AtomicTaskState::new(TaskState::Ready),
+            parked_msg: Mutex::new(None),
+            bound_notification: Mutex::new(None),
+            ipc_buffer: Mutex::new(None),
+            fault_handler: Mutex::new(None),
+            exit_notification: Mutex::new(None),
+            kernel_entry: AtomicKernelEntry::new(KernelEntry::Syscall),
+            priority: AtomicU8::new(DEFAULT_PRIORITY),
+            max_priority: AtomicU8::new(MAX_PRIORITY),
+            time_slice: AtomicU64::new(TIME_SLICE_NS),
+            last_cpu: AtomicUsize::new(NO_CPU),
+            on_cpu: AtomicBool::new(false),
+            sched_context: Mutex::new(Some(sched_context)),
+            donated_sc: Mutex::new(None),
         },
     })
 }
 
+/// Empty user address space sharing the kernel half of the page tables.
+pub fn make_user_vspace() -> VSpaceRef {
+    AddrSpace::new(phys_to_offset_page_table(create_new_pt4_from_kernel_pt4())).into_ref()
+}
+
+/// Builds a thread with an empty address space and root CNode. It stays
+/// `Inactive` until someone configures and starts it, and is only run once
+/// a scheduling context is bound to it.
+///
+/// Its kernel stack is laid out as if the thread were stopped in a syscall,
+/// so the first switch returns to user mode through `syscall_frame_trampoline`
+/// with whatever `tcb_set_regs` put in the frame.
+pub fn make_inactive_task(task_id: TaskIdIndex) -> Task {
+    let kernel_stack = allocate_kernel_stack(DEFAULT_KERNEL_STACK_SIZE);
+    let frame_words = core::mem::size_of::<TaskRegisters>() / 8;
+    let stack_top_ptr = kernel_stack.top.as_u64() as *mut u64;
+
+    unsafe {
+        (stack_top_ptr.sub(frame_words) as *mut TaskRegisters).write(TaskRegisters {
+            rflags: RFLAGS_WITH_IR,
+            cs:     USER_CODE_SELECTOR.0 as u64,
+            ss:     USER_DATA_SELECTOR.0 as u64,
+            ..Default::default()
+        });
+        stack_top_ptr.sub(frame_words + 1).write(syscall_frame_trampoline as *const () as u64);
+        for i in 2..=16 {
+            stack_top_ptr.sub(frame_words + i).write(0);
+        }
+    }
+    let initial_rsp = unsafe { stack_top_ptr.sub(frame_words + 16) } as u64;
+
+    Task {
+        id: TaskId::new(task_id),
+        registers: UnsafeCell::new(TaskRegisters {
+            rsp: initial_rsp,
+            ..Default::default()
+        }),
+        tcb: Tcb {
+            wake_at: Mutex::new(AtomicU64::new(0)),
+            wake_cpu: AtomicUsize::new(NO_CPU),
+            addr_space: Mutex::new(make_user_vspace()),
+            kernel_stack,
+            cnode: Mutex::new(CNode::new_root().into_ref()),
+            task_state: AtomicTaskState::new(TaskState::Inactive),
+            parked_msg: Mutex::new(None),
+            bound_notification: Mutex::new(None),
+            ipc_buffer: Mutex::new(None),
+            fault_handler: Mutex::new(None),
+            exit_notification: Mutex::new(None),
+            kernel_entry: AtomicKernelEntry::new(KernelEntry::Syscall),
+            priority: AtomicU8::new(DEFAULT_PRIORITY),
+            max_priority: AtomicU8::new(0),
+            time_slice: AtomicU64::new(TIME_SLICE_NS),
+            last_cpu: AtomicUsize::new(NO_CPU),
+            on_cpu: AtomicBool::new(false),
+            sched_context: Mutex::new(None),
+            donated_sc: Mutex::new(None),
+        },
+    }
+}
+
 #[unsafe(naked)]
 unsafe extern "C" fn user_task_trampoline() {
     naked_asm!(
@@ -203,6 +249,36 @@ unsafe extern "C" fn user_task_trampoline() {
     );
 }
 
+/// Returns to user mode from a `TaskRegisters` frame on top of the stack, the
+/// way the syscall exit path does.
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_frame_trampoline() {
+    naked_asm!(
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rax",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rax",
+        "pop rcx",
+        "add rsp, 8",
+        "pop r11",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+    );
+}
+
 extern "C" fn kernel_task_trampoline(entry: u64) -> ! {
     interrupts::enable();
     let func: extern "C" fn() -> ! = unsafe { core::mem::transmute(entry) };
@@ -238,11 +314,25 @@ pub fn make_kernel_task(id: TaskId, entry_point: u64) -> Task {
             ..TaskRegisters::default()
         }),
         tcb: Tcb { 
-            wake_at_tick: Mutex::new(AtomicU64::new(0)), 
-            addr_space: Mutex::new(AddrSpace::new(page_table)), 
+            wake_at: Mutex::new(AtomicU64::new(0)),
+            wake_cpu: AtomicUsize::new(NO_CPU),
+            addr_space: Mutex::new(AddrSpace::new(page_table).into_ref()),
             kernel_stack, 
-            cnode: Mutex::new(CNode::new()), 
-            task_state: AtomicTaskState::new(TaskState::Ready)
+            cnode: Mutex::new(CNode::new_root().into_ref()), 
+            task_state: AtomicTaskState::new(TaskState::Ready),
+            parked_msg: Mutex::new(None),
+            bound_notification: Mutex::new(None),
+            ipc_buffer: Mutex::new(None),
+            fault_handler: Mutex::new(None),
+            exit_notification: Mutex::new(None),
+            kernel_entry: AtomicKernelEntry::new(KernelEntry::Syscall),
+            priority: AtomicU8::new(0),
+            max_priority: AtomicU8::new(0),
+            time_slice: AtomicU64::new(TIME_SLICE_NS),
+            last_cpu: AtomicUsize::new(NO_CPU),
+            on_cpu: AtomicBool::new(false),
+            sched_context: Mutex::new(None),
+            donated_sc: Mutex::new(None),
         }
     }
 }
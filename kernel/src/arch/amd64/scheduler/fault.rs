@@ -0,0 +1,98 @@
+use alloc::sync::Arc;
+
+use crate::{arch::amd64::{
+    cpu::frames::{INTERRUPT_FRAME_WORDS, InterruptFrame},
+    ipc::{IPC_MANAGER, IpcResult, endpoint::EndpointId, message::{FastMessage, MsgLabel}},
+    scheduler::{PerCpuSchedulerData, awaken_task, block_current_on_ipc, syscall::thread_handler::exit_thread, task::Task, task_storage::get_task_by_index},
+}, early_println};
+
+/// First reply word with which a fault handler lets the thread retry the
+/// faulting instruction. Any other value kills the thread.
+pub const FAULT_RESUME: u64 = 0;
+
+/// Endpoint a thread's faults are sent to, with the badge of the cap it was
+/// registered through.
+#[derive(Clone, Copy)]
+pub struct FaultHandler {
+    pub ep:    EndpointId,
+    pub badge: u64,
+}
+
+/// Hands a fault of the current thread to its fault handler and returns once
+/// the handler wants the thread resumed. Without a handler, or when the
+/// handler refuses, only the faulting thread is torn down; `task` is taken
+/// by value so no reference to it is left on the dying stack.
+///
+/// `addr` is the faulting address for page faults and 0 otherwise.
+pub fn raise_fault(task: Arc<Task>, frame: &InterruptFrame, addr: u64) {
+    if call_fault_handler(&task, frame, addr) {
+        return;
+    }
+
+    let task_id = task.id.id();
+    drop(task);
+
+    early_println!(
+        "Fault: vector={} addr={:#x} error={:#x} rip={:#x}, killing task={}",
+        frame.interrupt, addr, frame.error, frame.rip, task_id
+    );
+    exit_thread(task_id);
+}
+
+/// NMI, double fault and machine check say nothing about the interrupted
+/// thread and are never handed to user space.
+fn is_thread_fault(vector: u64) -> bool {
+    !matches!(vector, 2 | 8 | 18)
+}
+
+/// Turns an exception without a handler of its own into a fault of the
+/// current thread. Returns false, leaving the caller to panic, for kernel
+/// mode and machine-level exceptions.
+pub fn raise_user_exception(frame: &InterruptFrame) -> bool {
+    if !frame.is_from_user() || !is_thread_fault(frame.interrupt) {
+        return false;
+    }
+
+    let curr_task_id = PerCpuSchedulerData::get().curr_task_id.id();
+    let task = match get_task_by_index(curr_task_id) {
+        Some(t) => t,
+        None    => return false,
+    };
+
+    raise_fault(task, frame, 0);
+    true
+}
+
+/// Sends the fault as a call and sleeps until it is answered. Returns whether
+/// the reply asks to resume.
+///
+/// The message carries `[vector, addr, error, rip]`; the whole interrupt
+/// frame follows as extra words for handlers with an IPC buffer.
+fn call_fault_handler(task: &Task, frame: &InterruptFrame, addr: u64) -> bool {
+    let handler = match *task.tcb.fault_handler.lock() {
+        Some(h) => h,
+        None    => return false,
+    };
+    let task_id = task.id.id();
+
+    let mut msg = FastMessage::with_data(MsgLabel::FAULT, [frame.interrupt, addr, frame.error, frame.rip]);
+    msg.badge = handler.badge;
+    msg.extra[..INTERRUPT_FRAME_WORDS].copy_from_slice(frame.as_words());
+    msg.n_extra = INTERRUPT_FRAME_WORDS;
+
+    let result = IPC_MANAGER.lock().handle_call(task_id, handler.ep, msg, true);
+    match result {
+        IpcResult::WakeReceiver { receiver } => {
+            if let Some(receiver) = get_task_by_index(receiver) {
+                awaken_task(receiver);
+            }
+        }
+        IpcResult::BlockCurrent => {}
+        _ => return false,
+    }
+
+    block_current_on_ipc();
+
+    let reply = IPC_MANAGER.lock().take_pending_message(task_id);
+    matches!(reply, Some(reply) if reply.label == MsgLabel::REPLY_OK && reply.data[0] == FAULT_RESUME)
+}
@@ -1,24 +1,28 @@
-use core::{arch::naked_asm, cell::UnsafeCell, ptr::addr_of, sync::atomic::{AtomicU64, Ordering}};
+use core::{arch::naked_asm, cell::UnsafeCell, ptr::addr_of, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
 use alloc::{sync::Arc, vec::Vec};
 use spin::Once;
-use x86_64::{VirtAddr, instructions::hlt};
+use x86_64::{VirtAddr, instructions::interrupts};
 
 pub mod task;
 mod stack;
 mod cpu_local;
 pub mod exec_loader;
+pub mod elf_loader;
 pub mod addr_space;
 pub mod task_storage;
+pub mod fault;
+pub mod sched_context;
 mod syscall;
 
 use crate::{
     arch::amd64::{
-        apic::{PercpuLapic, start_timer}, gdt::set_tss_rsp0, scheduler::{cpu_local::ExecCpu, exec_loader::make_kernel_task, syscall::{init_syscall_subsystem, set_per_cpu_TOP_OF_KERNEL_STACK}, task::{Task, TaskId, TaskIdIndex, TaskState}, task_storage::{add_task_to_execute, get_task_by_index, initialize_task_storage, steal_from_global, table}}
+        apic::{PercpuLapic, clock_event::{init_clock_event, set_next_event}}, gdt::set_tss_rsp0, scheduler::{cpu_local::ExecCpu, exec_loader::make_kernel_task, syscall::{init_syscall_subsystem, set_per_cpu_TOP_OF_KERNEL_STACK}, task::{KernelEntry, TIME_SLICE_NS, Task, TaskId, TaskState}, task_storage::{add_task_to_execute, alloc_task_index, get_task_by_index, initialize_task_storage, steal_from_global}}, timer::now_ns
     }, define_per_cpu_struct, early_println, irq
 };
 
 static CPU_NUM: AtomicU64 = AtomicU64::new(0);
-static TICK_COUNT: AtomicU64 = AtomicU64::new(0);
+
+const RESCHEDULE_VECTOR: u8 = 0x31;
 
 struct CpuDescriptorStorage {
     cpus: Vec<UnsafeCell<ExecCpu>>,
@@ -30,7 +34,7 @@ impl CpuDescriptorStorage {
     pub fn new(n_cpus: usize) -> Self {
         let mut cpus = Vec::with_capacity(n_cpus);
         initialize_task_storage();
-        for _ in 0..n_cpus { cpus.push(UnsafeCell::new(ExecCpu::new(make_kernel_task(TaskId::new(0), idle_task as u64)))); }
+        for _ in 0..n_cpus { cpus.push(UnsafeCell::new(ExecCpu::new(make_kernel_task(TaskId::new(alloc_task_index()), idle_task as u64)))); }
         Self { cpus }
     }
 
@@ -94,18 +98,22 @@ pub fn init_scheduler_percpu() -> ! {
 
     init_syscall_subsystem();
 
-    start_timer(&PercpuLapic::get().lapic);
+    let lapic = &PercpuLapic::get().lapic;
+    init_clock_event(lapic);
 
     let my_desc = descriptors.cpu(cpu_id);
+    my_desc.apic_id.store(lapic.id(), Ordering::Relaxed);
     let dummy_rsp: u64 = 0;
+    let dummy_on_cpu = AtomicBool::new(true);
     let idle_rsp = unsafe { (*my_desc.idle_task.registers.get()).rsp };
-    let idle_cr3 = my_desc.idle_task.tcb.addr_space.lock().get_page_table_phys();
+    let idle_cr3 = my_desc.idle_task.tcb.addr_space().lock().get_page_table_phys();
 
     unsafe {
         switch_to_task(
             addr_of!(dummy_rsp),
             idle_rsp,
             idle_cr3.as_u64(),
+            &dummy_on_cpu,
         );
     }
 
@@ -116,112 +124,277 @@ pub fn global_init_scheduler(n_cpus: usize) {
     CPU_DESCRIPTORS.call_once(|| CpuDescriptorStorage::new(n_cpus));
 }  
 
-pub fn block_current_on_ipc() {
+/// Moves the current task from `Running` to `state` and switches to the idle
+/// task. Returns once something makes the task `Ready` again and it is
+/// picked.
+///
+/// A task blocking in IPC was already made `Sleep` under the IPC lock, and a
+/// wakeup may have made it `Ready` and queued it since; that is left as it
+/// is. The task leaves the CPU either way, and whoever picks it next waits
+/// for `on_cpu` to drop before running it.
+fn park_current_task(state: TaskState) {
     let my_id = PerCpuSchedulerData::get().cpu_id;
     let my_desc = PerCpuSchedulerData::get().descriptors.cpu_mut(my_id);
     let curr_ptr = my_desc.get_curr_task();
 
     if curr_ptr.is_null() {
-        panic!("block_current_on_ipc: no current task");
+        panic!("park_current_task: no current task");
     }
 
     unsafe {
-        (*curr_ptr).tcb.task_state.store(TaskState::Sleep, Ordering::Relaxed);
+        let _ = (*curr_ptr).tcb.task_state
+            .compare_exchange(TaskState::Running, state, Ordering::AcqRel, Ordering::Acquire);
         let task_rsp_ptr = addr_of!((*(*curr_ptr).registers.get()).rsp);
+        let task_on_cpu = addr_of!((*curr_ptr).tcb.on_cpu);
 
-        my_desc.set_curr_task(core::ptr::null_mut());
+        my_desc.retire_curr_task();
         PerCpuSchedulerData::get_mut().curr_task_id = my_desc.idle_task.id;
 
         let idle_rsp = (*my_desc.idle_task.registers.get()).rsp;
-        let idle_cr3 = my_desc.idle_task.tcb.addr_space.lock().get_page_table_phys();
+        let idle_cr3 = my_desc.idle_task.tcb.addr_space().lock().get_page_table_phys();
 
-        switch_to_task(task_rsp_ptr, idle_rsp, idle_cr3.as_u64());
+        switch_to_task(task_rsp_ptr, idle_rsp, idle_cr3.as_u64(), task_on_cpu);
     }
 }
 
-pub fn sleep(ns: u64) {
-    let my_id = PerCpuSchedulerData::get().cpu_id;
-    let my_desc = PerCpuSchedulerData::get().descriptors.cpu_mut(my_id);
-    let curr_ptr = my_desc.get_curr_task();
+/// Marks the current task as blocked in IPC. Called under the IPC lock as
+/// the task is queued, so a waker that finds it queued also finds it
+/// `Sleep`, even while it has not left the CPU yet.
+pub fn mark_blocked_on_ipc(task: &Task) {
+    let _ = task.tcb.task_state
+        .compare_exchange(TaskState::Running, TaskState::Sleep, Ordering::AcqRel, Ordering::Acquire);
+}
 
-    let ticks = (ns + 999_999) / 1_000_000;
+pub fn block_current_on_ipc() {
+    park_current_task(TaskState::Sleep);
+}
 
-    if ticks == 0 {
-        return;
+/// Spins until `task` is off the CPU it last ran on, for callers about to
+/// run it elsewhere or to treat it as stopped.
+pub fn wait_until_off_cpu(task: &Task) {
+    while task.tcb.on_cpu.load(Ordering::Acquire) {
+        core::hint::spin_loop();
     }
+}
 
-    let current_tick = TICK_COUNT.load(Ordering::Relaxed);
-    let wake_at = current_tick + ticks;
+/// Stops the current task until `resume_task` is called on it.
+pub fn suspend_current_task() {
+    park_current_task(TaskState::Inactive);
+}
 
-    unsafe { 
-        (*curr_ptr).tcb.wake_at_tick.lock().store(wake_at, Ordering::Relaxed); 
-        (*curr_ptr).tcb.task_state.store(TaskState::Sleep, Ordering::Release);
+/// Takes the current task off the CPU for good. It is marked `Exiting` and
+/// never queued again; the CPU's reference to it is dropped by the idle task.
+pub fn exit_current_task() -> ! {
+    park_current_task(TaskState::Exiting);
+    unreachable!();
+}
 
-        let task_rsp_ptr = addr_of!((*(*curr_ptr).registers.get()).rsp);
-        my_desc.set_curr_task(core::ptr::null_mut());
-        PerCpuSchedulerData::get_mut().curr_task_id = my_desc.idle_task.id;
+/// Queues an `Inactive` task to run. Returns false for any other state.
+pub fn resume_task(task: Arc<Task>) -> bool {
+    let resumed = task.tcb.task_state
+        .compare_exchange(TaskState::Inactive, TaskState::Ready, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok();
+    if resumed {
+        enqueue_task(task);
+    }
+    resumed
+}
 
-        let idle_rsp = (*my_desc.idle_task.registers.get()).rsp;
-        let idle_cr3 = my_desc.idle_task.tcb.addr_space.lock().get_page_table_phys();
+/// Queues a task throttled on its scheduling context again ahead of the
+/// refill, for when the context was reconfigured or taken away.
+pub fn unthrottle_task(task: Arc<Task>) {
+    let released = task.tcb.task_state
+        .compare_exchange(TaskState::Throttled, TaskState::Ready, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok();
+    if released {
+        clear_wake_timeout(&task);
+        enqueue_task(task);
+    }
+}
+
+/// Makes the CPU running `task`, if any, pick its next task again, for a
+/// task that lost the context it was running on. There is no periodic tick
+/// that would stop it before its next timer event.
+pub fn reschedule_task(task: &Task) {
+    if !task.tcb.on_cpu.load(Ordering::Acquire) {
+        return;
+    }
+    let Some(descriptors) = CPU_DESCRIPTORS.get() else { return };
+    let cpu = task.tcb.last_cpu.load(Ordering::Relaxed);
+    if cpu < descriptors.cpus.len() {
+        PercpuLapic::get().lapic.send_ipi(descriptors.cpu(cpu).apic_id.load(Ordering::Relaxed), RESCHEDULE_VECTOR);
+    }
+}
+
+/// Records how the current task entered the kernel from ring 3.
+pub fn note_kernel_entry(entry: KernelEntry) {
+    let my_id = PerCpuSchedulerData::get().cpu_id;
+    let curr_ptr = PerCpuSchedulerData::get().descriptors.cpu(my_id).get_curr_task();
 
-        switch_to_task(task_rsp_ptr, idle_rsp, idle_cr3.as_u64());
+    if !curr_ptr.is_null() {
+        unsafe { (*curr_ptr).tcb.kernel_entry.store(entry, Ordering::Relaxed) };
     }
 }
 
-fn wake_sleeping_tasks() {
+pub fn sleep(ns: u64) {
+    if ns == 0 {
+        return;
+    }
+
     let my_id = PerCpuSchedulerData::get().cpu_id;
+    let curr_ptr = PerCpuSchedulerData::get().descriptors.cpu(my_id).get_curr_task();
+
+    arm_wakeup(unsafe { &*curr_ptr }, now_ns().saturating_add(ns));
+    park_current_task(TaskState::Sleep);
+}
+
+/// Puts a wakeup for `task` at `deadline` on this CPU's timer queue, in
+/// place of any it had pending. The task must be running here, or be about
+/// to stop running here.
+fn arm_wakeup(task: &Task, deadline: u64) {
+    let wake_at = task.tcb.wake_at.lock();
+    disarm_wakeup(task, &wake_at);
+    wake_at.store(deadline, Ordering::Release);
+
+    let my_id = PerCpuSchedulerData::get().cpu_id;
+    task.tcb.wake_cpu.store(my_id, Ordering::Relaxed);
+    PerCpuSchedulerData::get().descriptors.cpu(my_id).timers.insert(deadline, task.id.id());
+}
 
-    if my_id != 0 {
+/// Drops the pending wakeup of `task`, whose `wake_at` the caller holds,
+/// from the timer queue it sits on.
+fn disarm_wakeup(task: &Task, wake_at: &AtomicU64) {
+    let deadline = wake_at.swap(0, Ordering::AcqRel);
+    if deadline == 0 {
         return;
     }
+    let cpu = task.tcb.wake_cpu.load(Ordering::Relaxed);
+    PerCpuSchedulerData::get().descriptors.cpu(cpu).timers.remove(deadline, task.id.id());
+}
 
-    let now = TICK_COUNT.load(Ordering::Relaxed);
+/// Makes the timer wake `task` once `ns` nanoseconds have passed, should it
+/// still be asleep then. The caller blocks the task itself.
+pub fn set_wake_timeout(task: &Task, ns: u64) {
+    arm_wakeup(task, now_ns().saturating_add(ns));
+}
 
-    let to_wake: Vec<TaskIdIndex> = {
-        let tasks = table().tasks.lock();
-        tasks.values()
-            .filter(|t| {
-                if !matches!(t.tcb.task_state.load(Ordering::Acquire), TaskState::Sleep) {
-                    return false;
-                }
-                let wake_at = t.tcb.wake_at_tick.lock().load(Ordering::Acquire);
-                wake_at != 0 && now >= wake_at
-            })
-            .map(|t| t.id.id())
-            .collect()
-    }; 
-
-    for idx in to_wake {
-        if let Some(task) = get_task_by_index(idx) {
-            task.tcb.wake_at_tick.lock().store(0, Ordering::Release);
-            
-            task.tcb.task_state.store(TaskState::Ready, Ordering::Release);
-            awaken_task(task);
+pub fn clear_wake_timeout(task: &Task) {
+    disarm_wakeup(task, &task.tcb.wake_at.lock());
+}
+
+/// Wakes the tasks whose deadline on this CPU has passed and that still wait
+/// for it.
+fn fire_timers() {
+    let my_id = PerCpuSchedulerData::get().cpu_id;
+    let timers = &PerCpuSchedulerData::get().descriptors.cpu(my_id).timers;
+    let now = now_ns();
+
+    while let Some((deadline, idx)) = timers.pop_due(now) {
+        let Some(task) = get_task_by_index(idx) else { continue };
+
+        let armed = {
+            let wake_at = task.tcb.wake_at.lock();
+            let armed = wake_at.load(Ordering::Acquire) == deadline;
+            if armed {
+                wake_at.store(0, Ordering::Release);
+            }
+            armed
+        };
+        if !armed {
+            continue;
+        }
+
+        let woken = [TaskState::Sleep, TaskState::Throttled].into_iter().any(|from| {
+            task.tcb.task_state
+                .compare_exchange(from, TaskState::Ready, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        });
+        if woken {
+            enqueue_task(task);
         }
     }
 }
 
+/// Queues a runnable task. It goes back to the CPU it last ran on; one that
+/// never ran goes to an idle CPU, or stays here if none is idle. There is no
+/// periodic tick to notice it, so the CPU gets a reschedule IPI, unless it is
+/// this one and the task would not preempt what runs here anyway.
+fn enqueue_task(task: Arc<Task>) {
+    let Some(descriptors) = CPU_DESCRIPTORS.get() else {
+        add_task_to_execute(task);
+        return;
+    };
+
+    let my_id = PerCpuSchedulerData::get().cpu_id;
+    let last = task.tcb.last_cpu.load(Ordering::Relaxed);
+    let cpu = if last < descriptors.cpus.len() {
+        last
+    } else {
+        (0..descriptors.cpus.len())
+            .find(|&cpu| descriptors.cpu(cpu).is_idle())
+            .unwrap_or(my_id)
+    };
+
+    let target = descriptors.cpu(cpu);
+    let prio = task.tcb.priority();
+    target.tasks.push(task);
+
+    let kick = cpu != my_id || {
+        let curr = target.get_curr_task();
+        curr.is_null() || unsafe { (*curr).tcb.priority() } < prio
+    };
+    if kick {
+        PercpuLapic::get().lapic.send_ipi(target.apic_id.load(Ordering::Relaxed), RESCHEDULE_VECTOR);
+    }
+}
+
+/// Queues a task blocked in IPC. Only a `Sleep` task is woken: one blocked
+/// with a timeout can be woken by both the timer and IPC and must be queued
+/// once, and tasks suspended, throttled or dying meanwhile stay put.
 pub fn awaken_task(task: Arc<Task>) {
-    task.tcb.task_state.store(TaskState::Ready, Ordering::Release);
-    add_task_to_execute(task);
+    let woken = task.tcb.task_state
+        .compare_exchange(TaskState::Sleep, TaskState::Ready, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok();
+    if woken {
+        enqueue_task(task);
+    }
+}
+
+const STEAL_BATCH: usize = 4;
+
+/// Moves up to `STEAL_BATCH` tasks from the global queue to `desc`. Returns
+/// how many were moved.
+fn pull_from_global(desc: &ExecCpu) -> usize {
+    let mut global_buf: [Option<Arc<Task>>; STEAL_BATCH] = [None, None, None, None];
+    let n = steal_from_global(&mut global_buf);
+    for slot in global_buf[..n].iter_mut() {
+        if let Some(task) = slot.take() {
+            desc.tasks.push(task);
+        }
+    }
+    n
 }
 
 extern "C" fn idle_task() -> ! {
     loop {
+        // The timer and reschedule IPIs take the same run queue locks; they
+        // must not interrupt us while we hold one, nor between the last look
+        // at the queue and `hlt`.
+        interrupts::disable();
+
         PerCpuSchedulerData::with_guard(|data| {
             data.in_rescheduling = true;
         });
 
-        const STEAL_BATCH: usize = 4;
-        let mut global_buf: [Option<Arc<Task>>; STEAL_BATCH] = [None, None, None, None];
-        let mut steal_buf:  [Option<Arc<Task>>; STEAL_BATCH] = [None, None, None, None];
+        let mut steal_buf: [Option<Arc<Task>>; STEAL_BATCH] = [None, None, None, None];
 
         let my_descr = PerCpuSchedulerData::get_mut().descriptors;
         let my_id: usize = PerCpuSchedulerData::get().cpu_id;
 
-        let mut n = my_descr.try_to_steal_into(my_id, &mut steal_buf);
+        let n = my_descr.try_to_steal_into(my_id, &mut steal_buf);
 
         let my_cpu_data = my_descr.cpu_mut(my_id);
+        drop(my_cpu_data.retired.take());
 
         if n > 0 {
             for slot in steal_buf[..n].iter_mut() {
@@ -230,19 +403,89 @@ extern "C" fn idle_task() -> ! {
                 }
             }
         } else {
-            n = steal_from_global(&mut global_buf);
-            for slot in global_buf[..n].iter_mut() {
-                if let Some(task) = slot.take() {
-                    my_cpu_data.tasks.push(task);
-                }
-            }
+            pull_from_global(my_cpu_data);
         }
 
         PerCpuSchedulerData::with_guard(|data| {
             data.in_rescheduling = false;
         });
 
-        hlt();
+        if my_cpu_data.tasks.len() > 0 {
+            process_tick();
+            continue;
+        }
+
+        // Nothing to run: sleep until the next wakeup is due, or for good
+        // until an IPI or device interrupt arrives.
+        program_next_event(my_cpu_data, None, now_ns());
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Pops the next task that is still `Ready` and claims it as `Running`.
+/// Entries left behind by tasks suspended or killed meanwhile are dropped.
+/// Tasks whose scheduling context has no budget left are throttled until
+/// the refill, and tasks with no context at all until one is bound. A task
+/// still leaving another CPU is waited for.
+fn pop_runnable(desc: &ExecCpu) -> Option<Arc<Task>> {
+    let now = now_ns();
+    while let Some(task) = desc.tasks.pop() {
+        let exhausted = match task.tcb.active_sched_context() {
+            Some(sc) => sc.exhausted_until(now).map(Some),
+            None => Some(None),
+        };
+        if let Some(refill) = exhausted {
+            let throttled = task.tcb.task_state
+                .compare_exchange(TaskState::Ready, TaskState::Throttled, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok();
+            if let (true, Some(refill)) = (throttled, refill) {
+                arm_wakeup(&task, refill);
+            }
+            continue;
+        }
+
+        let claimed = task.tcb.task_state
+            .compare_exchange(TaskState::Ready, TaskState::Running, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+        if claimed {
+            wait_until_off_cpu(&task);
+            task.tcb.on_cpu.store(true, Ordering::Relaxed);
+            return Some(task);
+        }
+    }
+    None
+}
+
+/// Arms this CPU's clock event for the earliest of its pending wakeups and,
+/// while `curr` runs, the end of its time slice or budget. An idle CPU with
+/// no wakeup pending gets no timer interrupt at all.
+fn program_next_event(desc: &ExecCpu, curr: Option<&Task>, now: u64) {
+    let mut next = desc.timers.next_deadline();
+
+    if let Some(curr) = curr {
+        let mut run_for = curr.tcb.time_slice.load(Ordering::Relaxed);
+        if let Some(sc) = curr.tcb.active_sched_context() {
+            run_for = run_for.min(sc.remaining(now));
+        }
+        let end = now.saturating_add(run_for);
+        next = Some(next.map_or(end, |deadline| deadline.min(end)));
+    }
+
+    set_next_event(&PercpuLapic::get().lapic, next);
+}
+
+/// Charges `elapsed` nanoseconds to the running task's time slice and tells
+/// whether it has to make way: at once for a higher priority, once its
+/// slice is used up for an equal one. Lower priorities never preempt it.
+fn charge_slice(curr: &Task, desc: &ExecCpu, elapsed: u64) -> bool {
+    let left = curr.tcb.time_slice.load(Ordering::Relaxed).saturating_sub(elapsed);
+    curr.tcb.time_slice.store(left, Ordering::Relaxed);
+
+    let prio = curr.tcb.priority();
+    match desc.tasks.highest_priority() {
+        Some(best) if best > prio => true,
+        Some(best) if best == prio => left == 0,
+        _ => false,
     }
 }
 
@@ -253,64 +496,113 @@ fn process_tick() {
 
     let my_id = PerCpuSchedulerData::get().cpu_id;
     let my_desc = PerCpuSchedulerData::get().descriptors.cpu_mut(my_id);
+    pull_from_global(my_desc);
+
+    let now = now_ns();
     let curr_ptr = my_desc.get_curr_task();
-    let next_task = my_desc.tasks.pop();
+    if !curr_ptr.is_null() {
+        let curr = unsafe { &*curr_ptr };
+        let elapsed = now.saturating_sub(my_desc.accounted_at);
+        my_desc.accounted_at = now;
+
+        let Some(sc) = curr.tcb.active_sched_context() else {
+            // Its context was taken away or handed back: off the CPU until
+            // another one is bound.
+            park_current_task(TaskState::Throttled);
+            return;
+        };
+        if let Some(refill) = sc.charge(now, elapsed) {
+            // Out of budget: off the CPU until the context is refilled.
+            drop(sc);
+            arm_wakeup(curr, refill);
+            park_current_task(TaskState::Throttled);
+            return;
+        }
+        drop(sc);
+
+        if !charge_slice(curr, my_desc, elapsed) {
+            if curr.tcb.time_slice.load(Ordering::Relaxed) == 0 {
+                curr.tcb.time_slice.store(TIME_SLICE_NS, Ordering::Relaxed);
+            }
+            program_next_event(my_desc, Some(curr), now);
+            return;
+        }
+    }
+    let next_task = pop_runnable(my_desc);
 
     match (curr_ptr.is_null(), next_task) {
-        // no tasks to work, go to idle & try to steal
+        // nothing to run; the idle loop arms the timer before it halts
         (true, None) => {
             return;
         },
 
-        // we have a task, so execute it!
+        // only stale queue entries were left, keep running
         (false, None) => {
+            program_next_event(my_desc, Some(unsafe { &*curr_ptr }), now);
             return;
         },
 
         (true, Some(next)) => {
+            my_desc.accounted_at = now;
+            program_next_event(my_desc, Some(&next), now);
             let next_ptr = Arc::into_raw(next) as *mut Task;
             unsafe {
-                (*next_ptr).tcb.task_state.store(TaskState::Running, Ordering::Release);
                 my_desc.set_curr_task(next_ptr);
+                (*next_ptr).tcb.last_cpu.store(my_id, Ordering::Relaxed);
                 PerCpuSchedulerData::get_mut().curr_task_id = (*next_ptr).id;
                 set_per_cpu_TOP_OF_KERNEL_STACK((*next_ptr).tcb.kernel_stack.top.as_u64());
                 set_tss_rsp0(VirtAddr::new((*next_ptr).tcb.kernel_stack.top.as_u64()));
                 let idle_rsp_ptr = addr_of!((*my_desc.idle_task.registers.get()).rsp);
+                let idle_on_cpu = addr_of!(my_desc.idle_task.tcb.on_cpu);
                 let next_rsp = (*(*next_ptr).registers.get()).rsp;
-                let next_cr3 = (*next_ptr).tcb.addr_space.lock().get_page_table_phys();
+                let next_cr3 = (*next_ptr).tcb.addr_space().lock().get_page_table_phys();
 
-                switch_to_task(idle_rsp_ptr, next_rsp, next_cr3.as_u64());
+                switch_to_task(idle_rsp_ptr, next_rsp, next_cr3.as_u64(), idle_on_cpu);
             }
         },
 
         (false, Some(next)) => {
+            my_desc.accounted_at = now;
+            program_next_event(my_desc, Some(&next), now);
             let next_ptr = Arc::into_raw(next) as *mut Task;
             unsafe {
                 let task_rsp_ptr = addr_of!((*(*curr_ptr).registers.get()).rsp);
+                let task_on_cpu = addr_of!((*curr_ptr).tcb.on_cpu);
                 (*curr_ptr).tcb.task_state.store(TaskState::Ready, Ordering::Release);
                 let curr_arc = Arc::from_raw(curr_ptr);
-                my_desc.tasks.push(curr_arc);
+                // A task that still has time left was preempted by a higher
+                // priority and keeps its place in line.
+                if curr_arc.tcb.time_slice.load(Ordering::Relaxed) == 0 {
+                    curr_arc.tcb.time_slice.store(TIME_SLICE_NS, Ordering::Relaxed);
+                    my_desc.tasks.push(curr_arc);
+                } else {
+                    my_desc.tasks.push_front(curr_arc);
+                }
 
-                (*next_ptr).tcb.task_state.store(TaskState::Running, Ordering::Release);
                 my_desc.set_curr_task(next_ptr);
+                (*next_ptr).tcb.last_cpu.store(my_id, Ordering::Relaxed);
                 PerCpuSchedulerData::get_mut().curr_task_id = (*next_ptr).id;
                 set_per_cpu_TOP_OF_KERNEL_STACK((*next_ptr).tcb.kernel_stack.top.as_u64());
                 set_tss_rsp0(VirtAddr::new((*next_ptr).tcb.kernel_stack.top.as_u64()));
 
                 let next_rsp = (*(*next_ptr).registers.get()).rsp;
-                let next_cr3 = (*next_ptr).tcb.addr_space.lock().get_page_table_phys();
+                let next_cr3 = (*next_ptr).tcb.addr_space().lock().get_page_table_phys();
 
-                switch_to_task(task_rsp_ptr, next_rsp, next_cr3.as_u64());
+                switch_to_task(task_rsp_ptr, next_rsp, next_cr3.as_u64(), task_on_cpu);
             }
         }
     }
 }
 
+/// Saves the current context on its stack and resumes the one saved at
+/// `next_task_stack_pointer`. `previous_on_cpu` is cleared once the old
+/// stack is no longer in use, so another CPU may pick up the previous task.
 #[unsafe(naked)]
 pub(super) unsafe extern "C" fn switch_to_task(
     previous_task_stack_pointer: *const u64,
     next_task_stack_pointer: u64,
     next_page_table: u64,
+    previous_on_cpu: *const AtomicBool,
 ) {
     naked_asm!(
         "push rax",
@@ -330,6 +622,7 @@ pub(super) unsafe extern "C" fn switch_to_task(
         "push r15",
         "mov [rdi], rsp",
         "mov rsp, rsi",
+        "mov byte ptr [rcx], 0",
         "mov cr3, rdx",
         "pop r15",
         "pop r14",
@@ -350,9 +643,17 @@ pub(super) unsafe extern "C" fn switch_to_task(
     );
 }
 
+// One-shot clock event: a wakeup came due, or the running task's slice or
+// budget ran out.
 irq!(0x30, scheduler_tick_irq, |stack| {
-    TICK_COUNT.fetch_add(1, Ordering::Relaxed);
     PercpuLapic::get().lapic.eoi();
-    wake_sleeping_tasks();
+    fire_timers();
+    process_tick();
+});
+
+// A task was queued on this CPU by another one, or one that should preempt
+// the current task was woken here.
+irq!(0x31, reschedule_ipi, |stack| {
+    PercpuLapic::get().lapic.eoi();
     process_tick();
 });
@@ -0,0 +1,148 @@
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use crate::arch::amd64::scheduler::{
+    task::{Task, TaskIdIndex},
+    task_storage::get_task_by_index,
+    unthrottle_task,
+};
+
+/// Period, in nanoseconds, a freshly retyped scheduling context starts
+/// with. Its budget covers the whole period until it is configured.
+pub const DEFAULT_PERIOD_NS: u64 = 10_000_000;
+
+pub type SchedContextRef = Arc<SchedContext>;
+
+/// All times in nanoseconds of `now_ns`.
+struct Budget {
+    budget: u64,
+    period: u64,
+    /// Time left in the current period.
+    remaining: u64,
+    period_start: u64,
+}
+
+impl Budget {
+    /// Starts a new period with a full budget once the current one is over.
+    /// Periods that passed while the context sat unused are skipped.
+    fn refill(&mut self, now: u64) {
+        if now >= self.period_start + self.period {
+            self.period_start = now - (now - self.period_start) % self.period;
+            self.remaining = self.budget;
+        }
+    }
+
+    fn exhausted_until(&self) -> Option<u64> {
+        (self.remaining == 0).then_some(self.period_start + self.period)
+    }
+}
+
+/// CPU time a thread may use: `budget` out of every `period`. Whichever
+/// thread runs on the context is charged for the time it runs and
+/// throttled once the budget is gone, until the next period refills it.
+/// A thread with neither a context of its own nor one lent by a caller is
+/// not run at all; it stays `Throttled` until a context is bound to it.
+pub struct SchedContext {
+    budget: Mutex<Budget>,
+    /// Thread the context is bound to. A stale id, left by a thread that
+    /// exited, counts as unbound.
+    bound: Mutex<Option<TaskIdIndex>>,
+}
+
+impl SchedContext {
+    pub fn new() -> Self {
+        Self {
+            budget: Mutex::new(Budget {
+                budget: DEFAULT_PERIOD_NS,
+                period: DEFAULT_PERIOD_NS,
+                remaining: DEFAULT_PERIOD_NS,
+                period_start: 0,
+            }),
+            bound: Mutex::new(None),
+        }
+    }
+
+    /// A context bound to `task` from the start, for threads the kernel
+    /// builds itself. The thread's `sched_context` has to point back to it.
+    pub fn bound_to(task: TaskIdIndex) -> Self {
+        let sc = Self::new();
+        *sc.bound.lock() = Some(task);
+        sc
+    }
+
+    /// Sets a new budget and period and starts over with a full budget.
+    /// The caller checks `0 < budget <= period`.
+    pub fn configure(&self, budget: u64, period: u64) {
+        *self.budget.lock() = Budget { budget, period, remaining: budget, period_start: 0 };
+    }
+
+    /// Time the budget comes back at, or `None` while some is left at `now`.
+    pub fn exhausted_until(&self, now: u64) -> Option<u64> {
+        let mut budget = self.budget.lock();
+        budget.refill(now);
+        budget.exhausted_until()
+    }
+
+    /// Budget left at `now`.
+    pub fn remaining(&self, now: u64) -> u64 {
+        let mut budget = self.budget.lock();
+        budget.refill(now);
+        budget.remaining
+    }
+
+    /// Charges `ns` of running time that ended at `now`. Returns when the
+    /// budget comes back if that used it up.
+    pub fn charge(&self, now: u64, ns: u64) -> Option<u64> {
+        let mut budget = self.budget.lock();
+        budget.refill(now);
+        budget.remaining = budget.remaining.saturating_sub(ns);
+        budget.exhausted_until()
+    }
+
+    fn bound_task(&self) -> Option<Arc<Task>> {
+        self.bound.lock().and_then(get_task_by_index)
+    }
+}
+
+/// Binds `sc` to `task`, which may run again if it was waiting for a
+/// context. Fails when either one already has a binding.
+pub fn bind_sched_context(sc: &SchedContextRef, task: &Arc<Task>) -> bool {
+    let mut bound = sc.bound.lock();
+    if bound.and_then(get_task_by_index).is_some() {
+        return false;
+    }
+
+    let mut own = task.tcb.sched_context.lock();
+    if own.is_some() {
+        return false;
+    }
+    *own = Some(sc.clone());
+    *bound = Some(task.id.id());
+    drop(own);
+    drop(bound);
+    unthrottle_task(task.clone());
+    true
+}
+
+/// Takes `sc` away from its thread, which stops running until it gets
+/// another one or serves a call on a caller's.
+pub fn unbind_sched_context(sc: &SchedContextRef) {
+    let Some(task) = sc.bound_task() else { return };
+    sc.bound.lock().take();
+
+    let mut own = task.tcb.sched_context.lock();
+    if own.as_ref().is_some_and(|own| Arc::ptr_eq(own, sc)) {
+        own.take();
+    }
+    drop(own);
+    unthrottle_task(task);
+}
+
+/// Applies a new budget and period to `sc`. A bound thread throttled on
+/// the old settings may run again at once.
+pub fn configure_sched_context(sc: &SchedContextRef, budget: u64, period: u64) {
+    sc.configure(budget, period);
+    if let Some(task) = sc.bound_task() {
+        unthrottle_task(task);
+    }
+}
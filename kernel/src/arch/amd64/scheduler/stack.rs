@@ -36,6 +36,12 @@ pub struct KernelStack {
 }
 
 impl KernelStack {
+    /// Placeholder owning no memory, left behind when a stack is handed to
+    /// `deallocate_kernel_stack`.
+    pub const fn empty() -> Self {
+        Self { bottom: VirtAddr::zero(), top: VirtAddr::zero(), pages: Vec::new() }
+    }
+
     #[inline]
     pub fn guard_page_va(&self) -> VirtAddr {
         self.bottom - PAGE_SIZE as u64
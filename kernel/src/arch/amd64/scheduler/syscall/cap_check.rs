@@ -1,4 +1,4 @@
-use crate::arch::amd64::{ipc::{message::{Capability, Rights}, object_table::{HandleRef, KernelObjType, with_object}}, scheduler::task::Task};
+use crate::arch::amd64::{ipc::{cnode::lookup_slot, message::{Capability, Rights}, object_table::{KernelObjType, with_object}}, scheduler::task::Task};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CapError {
@@ -6,6 +6,9 @@ pub enum CapError {
     WrongType,
     WrongOwner,
     InsufficientRights,
+    SlotOccupied,
+    OutOfMemory,
+    InvalidArgument,
 }
 
 impl CapError {
@@ -15,6 +18,9 @@ impl CapError {
             CapError::WrongType          => u64::MAX - 1,
             CapError::WrongOwner         => u64::MAX - 2,
             CapError::InsufficientRights => u64::MAX - 3,
+            CapError::SlotOccupied       => u64::MAX - 4,
+            CapError::OutOfMemory        => u64::MAX - 5,
+            CapError::InvalidArgument    => u64::MAX - 6,
         }
     }
 }
@@ -30,9 +36,10 @@ pub fn resolve_cap(
     cap_idx: u64,
     expected_type: KernelObjType,
     required_rights: Rights,
-) -> Result<(HandleRef, Rights), CapError> {
-    let cnode = task.tcb.cnode.lock();
-    let cap = cnode.get(cap_idx as u32)
+) -> Result<Capability, CapError> {
+    let (cnode, idx) = lookup_slot(&task.tcb.cnode(), cap_idx)
+        .ok_or(CapError::InvalidIdx)?;
+    let cap = *cnode.lock().get(idx)
         .ok_or(CapError::InvalidIdx)?;
 
     if !cap.rights.contains(required_rights) {
@@ -47,5 +54,5 @@ pub fn resolve_cap(
         return Err(CapError::WrongType);
     }
 
-    Ok((cap.handle, cap.rights))
+    Ok(cap)
 }
\ No newline at end of file
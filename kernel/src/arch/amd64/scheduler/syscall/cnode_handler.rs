@@ -0,0 +1,276 @@
+use alloc::sync::Arc;
+use spin::{Mutex, MutexGuard};
+
+use crate::arch::amd64::{
+    ipc::{
+        IPC_MANAGER,
+        cnode::{CNode, CNodeRef, CapIdx},
+        endpoint::EndpointId,
+        mdb::{MDB, SlotRef},
+        message::{Capability, Rights},
+        object_table::{KernelObjType, KernelObject, ObjData, obj_release, obj_retain, with_object},
+    },
+    memory::pmm::pages_allocator::put_pages,
+    scheduler::{
+        PerCpuSchedulerData,
+        awaken_task,
+        sched_context::unbind_sched_context,
+        syscall::{cap_check::{CapError, resolve_cap}, untyped_handler::retype_cnode},
+        task::Task,
+        task_storage::get_task_by_index,
+    },
+};
+
+#[allow(clippy::enum_variant_names)]
+pub enum CNodeSyscallNumbers {
+    CNodeCopy   = 0x20,
+    CNodeMove   = 0x21,
+    CNodeDelete = 0x22,
+    CNodeRevoke = 0x23,
+    CNodeCreate = 0x24,
+    CNodeMint   = 0x25,
+}
+
+pub(super) fn resolve_cnode_cap(task: &Task, cap_idx: u64, required_rights: Rights) -> Result<CNodeRef, CapError> {
+    let handle = resolve_cap(task, cap_idx, KernelObjType::CNode, required_rights)?.handle;
+
+    with_object(handle, |obj| {
+        match &obj.data {
+            ObjData::CNode(cnode) => Some(cnode.clone()),
+            _ => None,
+        }
+    })
+    .flatten()
+    .ok_or(CapError::WrongType)
+}
+
+/// Locks two CNodes in address order. The second guard is `None` when both
+/// refer to the same CNode, in which case the first one must be used for
+/// both sides.
+fn lock_pair<'a>(a: &'a CNodeRef, b: &'a CNodeRef) -> (MutexGuard<'a, CNode>, Option<MutexGuard<'a, CNode>>) {
+    if Arc::ptr_eq(a, b) {
+        return (a.lock(), None);
+    }
+
+    if Arc::as_ptr(a) < Arc::as_ptr(b) {
+        let ga = a.lock();
+        let gb = b.lock();
+        (ga, Some(gb))
+    } else {
+        let gb = b.lock();
+        let ga = a.lock();
+        (ga, Some(gb))
+    }
+}
+
+pub(super) fn destroy_object(obj: KernelObject) {
+    if let Some(block) = obj.charge {
+        put_pages(block);
+    }
+    match obj.data {
+        ObjData::Endpoint(ep_id) => {
+            let waiters = IPC_MANAGER.lock().destroy_endpoint(EndpointId::new(ep_id as u64));
+            for task in waiters.into_iter().filter_map(get_task_by_index) {
+                awaken_task(task);
+            }
+        }
+        ObjData::Notification(id) => {
+            let waiter = IPC_MANAGER.lock().destroy_notification(id);
+            if let Some(task) = waiter.and_then(get_task_by_index) {
+                awaken_task(task);
+            }
+        }
+        ObjData::Frame { phys, .. } | ObjData::Untyped { phys, .. } => put_pages(phys),
+        ObjData::SchedContext(sc) => unbind_sched_context(&sc),
+        // A CNode still serving as some thread's root keeps its caps; they
+        // are released when that thread exits.
+        ObjData::CNode(cnode) => {
+            if let Ok(cnode) = Arc::try_unwrap(cnode) {
+                release_cnode_caps(&cnode);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Empties `cnode`: the caps lose their MDB nodes and the references they
+/// held are released, destroying objects nothing else refers to.
+pub(super) fn release_cnode_caps(cnode: &Mutex<CNode>) {
+    let caps = {
+        let mut mdb = MDB.lock();
+        let caps = cnode.lock().take_all();
+        for cap in caps.iter() {
+            mdb.remove(cap.mdb);
+        }
+        caps
+    };
+
+    for cap in caps.iter() {
+        release_cap(cap);
+    }
+}
+
+pub(super) fn release_cap(cap: &Capability) {
+    if let Some(obj) = obj_release(cap.handle) {
+        destroy_object(obj);
+    }
+}
+
+fn current_task() -> Result<Arc<Task>, CapError> {
+    let curr_task_id = PerCpuSchedulerData::get().curr_task_id.id();
+    get_task_by_index(curr_task_id).ok_or(CapError::InvalidIdx)
+}
+
+fn into_syscall_ret(res: Result<(), CapError>) -> u64 {
+    match res {
+        Ok(())  => 0,
+        Err(e)  => e.as_syscall_err(),
+    }
+}
+
+pub(crate) fn cnode_copy(src_cnode_cap: u64, src_idx: u64, dst_cnode_cap: u64, dst_idx: u64, rights: u64) -> u64 {
+    into_syscall_ret(copy_cap(src_cnode_cap, src_idx, dst_cnode_cap, dst_idx, rights, None))
+}
+
+pub(crate) fn cnode_mint(src_cnode_cap: u64, src_idx: u64, dst_cnode_cap: u64, dst_idx: u64, rights: u64, badge: u64) -> u64 {
+    into_syscall_ret(copy_cap(src_cnode_cap, src_idx, dst_cnode_cap, dst_idx, rights, Some(badge)))
+}
+
+pub(crate) fn cnode_move(src_cnode_cap: u64, src_idx: u64, dst_cnode_cap: u64, dst_idx: u64) -> u64 {
+    into_syscall_ret(move_cap(src_cnode_cap, src_idx, dst_cnode_cap, dst_idx))
+}
+
+pub(crate) fn cnode_delete(cnode_cap: u64, idx: u64) -> u64 {
+    into_syscall_ret(delete_cap(cnode_cap, idx))
+}
+
+pub(crate) fn cnode_revoke(cnode_cap: u64, idx: u64) -> u64 {
+    into_syscall_ret(revoke_cap(cnode_cap, idx))
+}
+
+/// Retypes part of the untyped at `untyped_cap_idx` into a CNode of
+/// `radix_bits` slots behind `guard_bits` of `guard`.
+pub(crate) fn cnode_create(untyped_cap_idx: u64, radix_bits: u64, guard_bits: u64, guard: u64) -> u64 {
+    match retype_cnode(untyped_cap_idx, radix_bits, guard_bits, guard) {
+        Ok(idx) => idx as u64,
+        Err(e)  => e.as_syscall_err(),
+    }
+}
+
+/// Copies a cap into a free slot as a child of the source. With `badge` set
+/// the child is minted with that badge instead of inheriting the source's.
+fn copy_cap(
+    src_cnode_cap: u64,
+    src_idx: u64,
+    dst_cnode_cap: u64,
+    dst_idx: u64,
+    rights: u64,
+    badge: Option<u64>,
+) -> Result<(), CapError> {
+    let curr = current_task()?;
+    let src = resolve_cnode_cap(&curr, src_cnode_cap, Rights::READ)?;
+    let dst = resolve_cnode_cap(&curr, dst_cnode_cap, Rights::WRITE)?;
+
+    let mut mdb = MDB.lock();
+    let (mut src_guard, mut dst_guard) = lock_pair(&src, &dst);
+
+    let cap = *src_guard.get(src_idx as CapIdx).ok_or(CapError::InvalidIdx)?;
+    let dst_cn = match dst_guard.as_deref_mut() {
+        Some(g) => g,
+        None    => &mut *src_guard,
+    };
+
+    if !dst_cn.is_valid_idx(dst_idx as CapIdx) {
+        return Err(CapError::InvalidIdx);
+    }
+    if !dst_cn.is_free(dst_idx as CapIdx) {
+        return Err(CapError::SlotOccupied);
+    }
+
+    let child = match badge {
+        Some(badge) => cap.mint(Rights::from_bits(rights), badge),
+        None        => cap.derive(Rights::from_bits(rights)),
+    }
+    .ok_or(CapError::InsufficientRights)?;
+
+    if child.is_null() {
+        return Err(CapError::InsufficientRights);
+    }
+
+    if !obj_retain(child.handle) {
+        return Err(CapError::InvalidIdx);
+    }
+
+    if !mdb.install_at(dst_cn, &dst, dst_idx as CapIdx, cap.mdb, child) {
+        // The source still holds a reference, so this never drops the last one.
+        obj_release(child.handle);
+        return Err(CapError::InvalidIdx);
+    }
+    Ok(())
+}
+
+fn move_cap(src_cnode_cap: u64, src_idx: u64, dst_cnode_cap: u64, dst_idx: u64) -> Result<(), CapError> {
+    let curr = current_task()?;
+    let src = resolve_cnode_cap(&curr, src_cnode_cap, Rights::WRITE)?;
+    let dst = resolve_cnode_cap(&curr, dst_cnode_cap, Rights::WRITE)?;
+
+    let mut mdb = MDB.lock();
+    let (mut src_guard, mut dst_guard) = lock_pair(&src, &dst);
+
+    {
+        let dst_cn = match dst_guard.as_deref_mut() {
+            Some(g) => g,
+            None    => &mut *src_guard,
+        };
+        if !dst_cn.is_valid_idx(dst_idx as CapIdx) {
+            return Err(CapError::InvalidIdx);
+        }
+        if !dst_cn.is_free(dst_idx as CapIdx) {
+            return Err(CapError::SlotOccupied);
+        }
+    }
+
+    let cap = src_guard.take(src_idx as CapIdx).ok_or(CapError::InvalidIdx)?;
+    let dst_cn = match dst_guard.as_deref_mut() {
+        Some(g) => g,
+        None    => &mut *src_guard,
+    };
+    dst_cn.insert_at(dst_idx as CapIdx, cap);
+    mdb.moved(cap.mdb, SlotRef::new(&dst, dst_idx as CapIdx));
+    Ok(())
+}
+
+fn delete_cap(cnode_cap: u64, idx: u64) -> Result<(), CapError> {
+    let curr = current_task()?;
+    let target = resolve_cnode_cap(&curr, cnode_cap, Rights::WRITE)?;
+
+    let cap = {
+        let mut mdb = MDB.lock();
+        let cap = target.lock()
+            .take(idx as CapIdx)
+            .ok_or(CapError::InvalidIdx)?;
+        mdb.remove(cap.mdb);
+        cap
+    };
+
+    release_cap(&cap);
+    Ok(())
+}
+
+fn revoke_cap(cnode_cap: u64, idx: u64) -> Result<(), CapError> {
+    let curr = current_task()?;
+    let target = resolve_cnode_cap(&curr, cnode_cap, Rights::WRITE)?;
+
+    let revoked = {
+        let mut mdb = MDB.lock();
+        let parent = *target.lock()
+            .get(idx as CapIdx)
+            .ok_or(CapError::InvalidIdx)?;
+        mdb.revoke(parent.mdb)
+    };
+
+    for cap in revoked.iter() {
+        release_cap(cap);
+    }
+    Ok(())
+}
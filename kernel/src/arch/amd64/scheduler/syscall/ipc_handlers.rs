@@ -1,11 +1,15 @@
+use x86_64::VirtAddr;
+
 use crate::arch::amd64::{
     ipc::{
-        IPC_MANAGER, IpcError, IpcResult, cnode::CapIdx, endpoint::EndpointId, message::{Capability, FastMessage, MsgLabel, Rights}, object_table::{KernelObjType, KernelObject, ObjData, obj_insert, with_object}
+        IPC_MANAGER, IpcError, IpcManager, IpcResult, cnode::{CapIdx, lookup_slot}, endpoint::EndpointId, mdb::MDB, message::{Capability, FastMessage, IpcBuffer, MAX_CAPS_PER_MSG, MSG_EXTRA_WORDS, MsgInfo, MsgLabel, Rights}, object_table::{KernelObjType, ObjData, obj_retain, with_object}
     },
+    memory::{misc::phys_to_virt, vmm::PAGE_SIZE},
     scheduler::{
-        awaken_task, block_current_on_ipc,
-        syscall::{IpcSyscallArguments, cap_check::resolve_cap},
-        task::{Task, TaskRegisters},
+        addr_space::{AddrSpace, MapFlags},
+        awaken_task, block_current_on_ipc, clear_wake_timeout, set_wake_timeout,
+        syscall::{IpcSyscallArguments, cap_check::resolve_cap, cnode_handler::release_cap, untyped_handler::retype},
+        task::{Task, TaskIdIndex, TaskRegisters},
         task_storage::get_task_by_index,
     },
 };
@@ -17,6 +21,8 @@ pub(crate) enum IpcSyscallNumbers {
     IpcReply     = 0x63,
     IpcEpCreate  = 0x64,
     IpcEpDestroy = 0x65,
+    IpcReplyRecv = 0x66,
+    IpcSetBuffer = 0x67,
 }
 
 pub(crate) enum IpcSyscallRetCodes {
@@ -25,20 +31,28 @@ pub(crate) enum IpcSyscallRetCodes {
     IpcInvalidEp       = 10,
     IpcInvalidCap      = 11,
     IpcPermissionDenied = 12,
+    IpcNoGrant         = 13,
+    IpcTooManyCaps     = 14,
+    IpcNoReply         = 15,
+    IpcTimeout         = 16,
+    IpcNoBuffer        = 18,
+    IpcMsgTooLong      = 19,
+    IpcReplyPending    = 20,
     IpcUnknown         = 32,
 }
 
-fn resolve_endpoint_cap(
+/// Returns the endpoint named by `cap_idx` together with the cap's badge.
+pub(super) fn resolve_endpoint_cap(
     task: &Task,
     cap_idx: CapIdx,
     required_rights: Rights,
-) -> Result<EndpointId, IpcSyscallRetCodes> {
-    let (handle, _) = resolve_cap(task, cap_idx as u64, KernelObjType::Endpoint, required_rights)
+) -> Result<(EndpointId, u64), IpcSyscallRetCodes> {
+    let cap = resolve_cap(task, cap_idx as u64, KernelObjType::Endpoint, required_rights)
         .map_err(|_| IpcSyscallRetCodes::IpcInvalidCap)?;
 
-    with_object(handle, |obj| {
+    with_object(cap.handle, |obj| {
         match &obj.data {
-            ObjData::Endpoint(ep_id) => Some(EndpointId::new(*ep_id as u64)),
+            ObjData::Endpoint(ep_id) => Some((EndpointId::new(*ep_id as u64), cap.badge)),
             _ => None,
         }
     })
@@ -46,30 +60,208 @@ fn resolve_endpoint_cap(
     .ok_or(IpcSyscallRetCodes::IpcInvalidCap)
 }
 
-pub(crate) fn handle_ipc_ep_create(curr_task_id: u32) -> u64 {
-    let ep_id = IPC_MANAGER
-        .lock()
-        .create_endpoint(curr_task_id)
-        .unwrap()
-        .0;
+/// Rights the endpoint capability needs for a send carrying `info`.
+fn send_rights(info: MsgInfo) -> Rights {
+    if info.n_caps() > 0 { Rights::WRITE.union(Rights::GRANT) } else { Rights::WRITE }
+}
+
+/// Attaches the capabilities named by `ipc.cap_ptrs` to `msg`. The copies in
+/// flight carry the sender's MDB id so the receiver's copy ends up as a child
+/// of the sender's slot.
+fn attach_caps(
+    task: &Task,
+    ipc: &IpcSyscallArguments,
+    msg: &mut FastMessage,
+) -> Result<(), IpcSyscallRetCodes> {
+    let n_caps = ipc.info.n_caps() as usize;
+    if n_caps > MAX_CAPS_PER_MSG {
+        return Err(IpcSyscallRetCodes::IpcTooManyCaps);
+    }
+
+    let mut owned = [Capability::NULL; MAX_CAPS_PER_MSG];
+    for (slot, &cptr) in owned.iter_mut().zip(&ipc.cap_ptrs[..n_caps]) {
+        let (cnode, idx) = lookup_slot(&task.tcb.cnode(), cptr)
+            .ok_or(IpcSyscallRetCodes::IpcInvalidCap)?;
+        *slot = *cnode.lock().get(idx).ok_or(IpcSyscallRetCodes::IpcInvalidCap)?;
+        msg.add_cap(*slot);
+    }
+
+    IPC_MANAGER.lock()
+        .validate_caps(msg, &owned[..n_caps])
+        .map_err(|e| match e {
+            IpcError::NoGrant => IpcSyscallRetCodes::IpcNoGrant,
+            _                 => IpcSyscallRetCodes::IpcPermissionDenied,
+        })?;
+
+    for cap in msg.caps[..n_caps].iter_mut() {
+        let parent = cap.mdb;
+        *cap = cap.derive(cap.rights).ok_or(IpcSyscallRetCodes::IpcNoGrant)?;
+        cap.mdb = parent;
+    }
+    Ok(())
+}
+
+/// Runs `f` on the task's IPC buffer while its address space is locked.
+/// `None` if no buffer is registered, or its range is no longer mapped
+/// user writable or its page has gone away. The mapping is checked on
+/// every access since it may have been replaced since registration.
+pub(super) fn with_ipc_buffer<R>(task: &Task, f: impl FnOnce(&mut IpcBuffer) -> R) -> Option<R> {
+    let vaddr = (*task.tcb.ipc_buffer.lock())?;
+    let vspace = task.tcb.addr_space();
+    let mut addr_space = vspace.lock();
+    check_ipc_buffer(&addr_space, vaddr.as_u64())?;
+    let phys = addr_space.user_phys(vaddr)?;
+    let buf = unsafe { &mut *(phys_to_virt(phys.as_u64() as usize) as *mut IpcBuffer) };
+    Some(f(buf))
+}
+
+/// Copies the `info.extra_words` words past the registers out of the
+/// sender's IPC buffer into `msg`.
+fn attach_extra(task: &Task, info: MsgInfo, msg: &mut FastMessage) -> Result<(), IpcSyscallRetCodes> {
+    let n_extra = info.extra_words() as usize;
+    if n_extra == 0 {
+        return Ok(());
+    }
+    if n_extra > MSG_EXTRA_WORDS {
+        return Err(IpcSyscallRetCodes::IpcMsgTooLong);
+    }
+
+    with_ipc_buffer(task, |buf| msg.extra[..n_extra].copy_from_slice(&buf.words[..n_extra]))
+        .ok_or(IpcSyscallRetCodes::IpcNoBuffer)?;
+    msg.n_extra = n_extra;
+    Ok(())
+}
+
+/// Installs the capabilities carried by `msg` into free slots of the
+/// receiver's CNode and loads the message into its registers and IPC
+/// buffer. Caps whose object died in flight, whose parent cap was deleted
+/// or revoked meanwhile, or that do not fit, are dropped and counted in
+/// `MsgInfo::dropped_caps`. Extra words are dropped when the receiver has
+/// no buffer.
+fn deliver_message(task: &Task, msg: &FastMessage, regs: &mut TaskRegisters) {
+    let mut slots = [0u64; MAX_CAPS_PER_MSG];
+    let mut n_caps = 0;
+
+    if msg.n_caps > 0 {
+        let mut mdb = MDB.lock();
+        let root = task.tcb.cnode();
+        let mut cnode = root.lock();
+        for cap in msg.caps() {
+            if cnode.find_free().is_none() {
+                break;
+            }
+            if !mdb.can_derive_from(cap.mdb) || !obj_retain(cap.handle) {
+                continue;
+            }
+            let idx = mdb.install(&mut cnode, &root, cap.mdb, *cap)
+                .expect("deliver_message: free slot vanished");
+            slots[n_caps] = idx as u64;
+            n_caps += 1;
+        }
+    }
+
+    let n_extra = with_ipc_buffer(task, |buf| {
+        buf.words[..msg.n_extra].copy_from_slice(msg.extra());
+        buf.caps = slots;
+        msg.n_extra
+    }).unwrap_or(0);
+
+    regs.rdi = msg.label.0;
+    regs.rbx = msg.badge;
+    regs.rsi = msg.data[0];
+    regs.rdx = msg.data[1];
+    regs.r10 = msg.data[2];
+    regs.r8  = msg.data[3];
+    regs.r9  = MsgInfo::new()
+        .with_n_caps(n_caps as u8)
+        .with_extra_words(n_extra as u8)
+        .with_dropped_caps((msg.n_caps - n_caps) as u8)
+        .into_bits();
+    regs.r12 = slots[0];
+    regs.r13 = slots[1];
+    regs.r14 = slots[2];
+    regs.r15 = slots[3];
+}
+
+/// Sleeps until a message for the current task is pending or `timeout_ns`
+/// expires (0 waits forever). On timeout `cancel` runs under the IPC lock to
+/// withdraw the task from whatever it was queued on.
+fn wait_for_message(
+    task: &Task,
+    curr_task_id: TaskIdIndex,
+    timeout_ns: u64,
+    cancel: impl FnOnce(&mut IpcManager),
+) -> Option<FastMessage> {
+    if timeout_ns != 0 {
+        set_wake_timeout(task, timeout_ns);
+    }
+    block_current_on_ipc();
+    clear_wake_timeout(task);
+
+    let mut ipc = IPC_MANAGER.lock();
+    let msg = ipc.take_pending_message(curr_task_id);
+    if msg.is_none() {
+        cancel(&mut ipc);
+    }
+    msg
+}
 
-    let handle = obj_insert(KernelObject::new(
-        KernelObjType::Endpoint,
-        ObjData::Endpoint(ep_id as u32),
-    )).expect("object table full");
+/// Completes a receive: either picks up the message of a queued sender right
+/// away or sleeps until one arrives.
+fn finish_recv(
+    task: &Task,
+    curr_task_id: TaskIdIndex,
+    ep_id: EndpointId,
+    timeout_ns: u64,
+    result: IpcResult,
+    regs: &mut TaskRegisters,
+) -> IpcSyscallRetCodes {
+    let msg = match result {
+        IpcResult::Received { sender } => {
+            if let Some(sender) = sender.and_then(get_task_by_index) {
+                awaken_task(sender);
+            }
+            IPC_MANAGER.lock().take_pending_message(curr_task_id)
+        }
+        IpcResult::BlockCurrent => {
+            wait_for_message(task, curr_task_id, timeout_ns, |ipc| ipc.cancel_recv(curr_task_id, ep_id))
+        }
+        IpcResult::Error(IpcError::InvalidEndpoint) => return IpcSyscallRetCodes::IpcInvalidEp,
+        IpcResult::Error(IpcError::NotReady) => return IpcSyscallRetCodes::IpcNotReady,
+        IpcResult::Error(IpcError::ReplyPending) => return IpcSyscallRetCodes::IpcReplyPending,
+        IpcResult::Error(_) => return IpcSyscallRetCodes::IpcUnknown,
+        _ => return IpcSyscallRetCodes::IpcOk,
+    };
 
-    let cap = Capability::new(handle, Rights::ALL);
+    deliver_or_fail(task, msg, regs)
+}
 
-    let task = get_task_by_index(curr_task_id)
-        .expect("handle_ipc_ep_create: task not found");
-    let cap_idx = task.tcb.cnode.lock().alloc(cap)
-        .expect("handle_ipc_ep_create: CNode full");
+/// Delivers the message a blocked receive or call woke up with. No message
+/// or a `CANCELLED` one means the wait timed out or was interrupted; a
+/// `CLOSED` one that the endpoint was destroyed.
+fn deliver_or_fail(task: &Task, msg: Option<FastMessage>, regs: &mut TaskRegisters) -> IpcSyscallRetCodes {
+    match msg {
+        Some(msg) if msg.label == MsgLabel::CLOSED => IpcSyscallRetCodes::IpcInvalidEp,
+        Some(msg) if msg.label == MsgLabel::CANCELLED => IpcSyscallRetCodes::IpcTimeout,
+        Some(msg) => {
+            deliver_message(task, &msg, regs);
+            IpcSyscallRetCodes::IpcOk
+        }
+        None => IpcSyscallRetCodes::IpcTimeout,
+    }
+}
 
-    cap_idx as u64
+/// Retypes part of the untyped at `untyped_cap_idx` into an endpoint.
+/// Returns the slot index or a `CapError` code.
+pub(crate) fn handle_ipc_ep_create(untyped_cap_idx: u64) -> u64 {
+    match retype(untyped_cap_idx, KernelObjType::Endpoint, 0) {
+        Ok(idx) => idx as u64,
+        Err(e)  => e.as_syscall_err(),
+    }
 }
 
 pub(crate) fn handle_ipc_ep_destroy(
-    curr_task_id: u32,
+    curr_task_id: TaskIdIndex,
     cap_idx: u64,
 ) -> IpcSyscallRetCodes {
     let task = match get_task_by_index(curr_task_id) {
@@ -78,26 +270,37 @@ pub(crate) fn handle_ipc_ep_destroy(
     };
 
     let ep_id = match resolve_endpoint_cap(&task, cap_idx as CapIdx, Rights::ALL) {
-        Ok(id) => id,
+        Ok((id, _)) => id,
         Err(e) => return e,
     };
 
-    let handle = {
-        let cnode = task.tcb.cnode.lock();
-        let cap = cnode.get(cap_idx as CapIdx).unwrap();
-        cap.handle
+    // The cap may have been deleted or moved since it was resolved.
+    let cap = {
+        let mut mdb = MDB.lock();
+        let Some((cnode, idx)) = lookup_slot(&task.tcb.cnode(), cap_idx) else {
+            return IpcSyscallRetCodes::IpcInvalidCap;
+        };
+        let Some(cap) = cnode.lock().take(idx) else {
+            return IpcSyscallRetCodes::IpcInvalidCap;
+        };
+        mdb.remove(cap.mdb);
+        cap
     };
 
-    IPC_MANAGER.lock().destroy_endpoint(ep_id);
-    task.tcb.cnode.lock().delete(cap_idx as CapIdx);
+    // Closed at once, even while other caps to it remain; the object goes
+    // with the last of them.
+    let waiters = IPC_MANAGER.lock().destroy_endpoint(ep_id);
+    for task in waiters.into_iter().filter_map(get_task_by_index) {
+        awaken_task(task);
+    }
 
-    with_object(handle, |obj| obj.dec_ref());
+    release_cap(&cap);
 
     IpcSyscallRetCodes::IpcOk
 }
 
 pub(crate) fn handle_ipc_send(
-    curr_task_id: u32,
+    curr_task_id: TaskIdIndex,
     ipc: &IpcSyscallArguments,
 ) -> IpcSyscallRetCodes {
     let task = match get_task_by_index(curr_task_id) {
@@ -105,13 +308,21 @@ pub(crate) fn handle_ipc_send(
         None => return IpcSyscallRetCodes::IpcInvalidCap,
     };
 
-    let ep_id = match resolve_endpoint_cap(&task, ipc.ep_id as CapIdx, Rights::WRITE) {
-        Ok(id) => id,
+    let (ep_id, badge) = match resolve_endpoint_cap(&task, ipc.ep_id as CapIdx, send_rights(ipc.info)) {
+        Ok(ep) => ep,
         Err(e) => return e,
     };
 
-    let msg = FastMessage::with_data(MsgLabel::NOTIFY, ipc.msg);
-    let result = IPC_MANAGER.lock().handle_send(curr_task_id, ep_id, msg);
+    let mut msg = FastMessage::with_data(MsgLabel::SEND, ipc.msg);
+    msg.badge = badge;
+    if let Err(e) = attach_caps(&task, ipc, &mut msg) {
+        return e;
+    }
+    if let Err(e) = attach_extra(&task, ipc.info, &mut msg) {
+        return e;
+    }
+
+    let result = IPC_MANAGER.lock().handle_send(curr_task_id, ep_id, msg, !ipc.info.nonblock());
 
     match result {
         IpcResult::WakeReceiver { receiver } => {
@@ -120,7 +331,17 @@ pub(crate) fn handle_ipc_send(
             }
             IpcSyscallRetCodes::IpcOk
         }
-        IpcResult::NotReady => IpcSyscallRetCodes::IpcNotReady,
+        IpcResult::BlockCurrent => {
+            block_current_on_ipc();
+            // A blocked sender only gets a message when its own did not go
+            // out: the endpoint was destroyed or the sender suspended.
+            match IPC_MANAGER.lock().take_pending_message(curr_task_id) {
+                Some(msg) if msg.label == MsgLabel::CANCELLED => IpcSyscallRetCodes::IpcTimeout,
+                Some(_) => IpcSyscallRetCodes::IpcInvalidEp,
+                None    => IpcSyscallRetCodes::IpcOk,
+            }
+        }
+        IpcResult::Error(IpcError::NotReady) => IpcSyscallRetCodes::IpcNotReady,
         IpcResult::Error(IpcError::InvalidEndpoint) => IpcSyscallRetCodes::IpcInvalidEp,
         IpcResult::Error(_) => IpcSyscallRetCodes::IpcUnknown,
         _ => IpcSyscallRetCodes::IpcOk,
@@ -128,8 +349,8 @@ pub(crate) fn handle_ipc_send(
 }
 
 pub(crate) fn handle_ipc_recv(
-    curr_task_id: u32,
-    cap_idx_raw: u64,
+    curr_task_id: TaskIdIndex,
+    ipc: &IpcSyscallArguments,
     curr_task_regs: &mut TaskRegisters,
 ) -> IpcSyscallRetCodes {
     let task = match get_task_by_index(curr_task_id) {
@@ -137,32 +358,18 @@ pub(crate) fn handle_ipc_recv(
         None => return IpcSyscallRetCodes::IpcInvalidCap,
     };
 
-    let ep_id = match resolve_endpoint_cap(&task, cap_idx_raw as CapIdx, Rights::READ) {
-        Ok(id) => id,
+    let ep_id = match resolve_endpoint_cap(&task, ipc.ep_id as CapIdx, Rights::READ) {
+        Ok((id, _)) => id,
         Err(e) => return e,
     };
 
-    let result = IPC_MANAGER.lock().handle_recv(curr_task_id, ep_id);
+    let result = IPC_MANAGER.lock().handle_recv(curr_task_id, ep_id, !ipc.info.nonblock());
 
-    match result {
-        IpcResult::BlockCurrent => {
-            block_current_on_ipc();
-            if let Some(msg) = IPC_MANAGER.lock().take_pending_message(curr_task_id) {
-                curr_task_regs.rdi = msg.label.0;
-                curr_task_regs.rsi = msg.data[0];
-                curr_task_regs.rdx = msg.data[1];
-                curr_task_regs.r10 = msg.data[2];
-                curr_task_regs.r8  = msg.data[3];
-            }
-            IpcSyscallRetCodes::IpcOk
-        }
-        IpcResult::Error(_) => IpcSyscallRetCodes::IpcUnknown,
-        _ => IpcSyscallRetCodes::IpcOk,
-    }
+    finish_recv(&task, curr_task_id, ep_id, ipc.timeout_ns, result, curr_task_regs)
 }
 
 pub(crate) fn handle_ipc_call(
-    curr_task_id: u32,
+    curr_task_id: TaskIdIndex,
     ipc: &IpcSyscallArguments,
     curr_task_regs: &mut TaskRegisters,
 ) -> IpcSyscallRetCodes {
@@ -171,52 +378,43 @@ pub(crate) fn handle_ipc_call(
         None => return IpcSyscallRetCodes::IpcInvalidCap,
     };
 
-    let server_ep = match resolve_endpoint_cap(&task, ipc.ep_id as CapIdx, Rights::WRITE) {
-        Ok(id) => id,
+    let (server_ep, badge) = match resolve_endpoint_cap(&task, ipc.ep_id as CapIdx, send_rights(ipc.info)) {
+        Ok(ep) => ep,
         Err(e) => return e,
     };
 
-    let reply_ep = match resolve_endpoint_cap(&task, ipc.msg[0] as CapIdx, Rights::READ) {
-        Ok(id) => id,
-        Err(e) => return e,
-    };
-
-    let msg_data = [ipc.msg[1], ipc.msg[2], ipc.msg[3], 0];
-    let msg = FastMessage::with_data(MsgLabel::CALL, msg_data);
+    let mut msg = FastMessage::with_data(MsgLabel::CALL, ipc.msg);
+    msg.badge = badge;
+    if let Err(e) = attach_caps(&task, ipc, &mut msg) {
+        return e;
+    }
+    if let Err(e) = attach_extra(&task, ipc.info, &mut msg) {
+        return e;
+    }
 
-    let send_result = IPC_MANAGER.lock().handle_send(curr_task_id, server_ep, msg);
+    let send_result = IPC_MANAGER.lock().handle_call(curr_task_id, server_ep, msg, !ipc.info.nonblock());
     match send_result {
         IpcResult::WakeReceiver { receiver } => {
             if let Some(task) = get_task_by_index(receiver) {
                 awaken_task(task);
             }
         }
-        IpcResult::NotReady => return IpcSyscallRetCodes::IpcNotReady,
+        IpcResult::Error(IpcError::NotReady) => return IpcSyscallRetCodes::IpcNotReady,
         IpcResult::Error(IpcError::InvalidEndpoint) => return IpcSyscallRetCodes::IpcInvalidEp,
         IpcResult::Error(_) => return IpcSyscallRetCodes::IpcUnknown,
         _ => {}
     }
 
-    let recv_result = IPC_MANAGER.lock().handle_recv(curr_task_id, reply_ep);
-    match recv_result {
-        IpcResult::BlockCurrent => {
-            block_current_on_ipc();
-            if let Some(msg) = IPC_MANAGER.lock().take_pending_message(curr_task_id) {
-                curr_task_regs.rdi = msg.label.0;
-                curr_task_regs.rsi = msg.data[0];
-                curr_task_regs.rdx = msg.data[1];
-                curr_task_regs.r10 = msg.data[2];
-                curr_task_regs.r8  = msg.data[3];
-            }
-            IpcSyscallRetCodes::IpcOk
-        }
-        IpcResult::Error(_) => IpcSyscallRetCodes::IpcUnknown,
-        _ => IpcSyscallRetCodes::IpcOk,
-    }
+    // Whether delivered now or picked up from the send queue later, the call
+    // leaves the server a reply object naming us; sleep until it is used.
+    let reply = wait_for_message(&task, curr_task_id, ipc.timeout_ns, |ipc| ipc.cancel_call(curr_task_id, server_ep));
+    deliver_or_fail(&task, reply, curr_task_regs)
 }
 
+/// Answers the last call received by the current task. The reply object is
+/// consumed, so a second reply fails with `IpcNoReply`.
 pub(crate) fn handle_ipc_reply(
-    curr_task_id: u32,
+    curr_task_id: TaskIdIndex,
     ipc: &IpcSyscallArguments,
 ) -> IpcSyscallRetCodes {
     let task = match get_task_by_index(curr_task_id) {
@@ -224,13 +422,15 @@ pub(crate) fn handle_ipc_reply(
         None => return IpcSyscallRetCodes::IpcInvalidCap,
     };
 
-    let ep_id = match resolve_endpoint_cap(&task, ipc.ep_id as CapIdx, Rights::WRITE) {
-        Ok(id) => id,
-        Err(e) => return e,
-    };
+    let mut msg = FastMessage::with_data(MsgLabel::REPLY_OK, ipc.msg);
+    if let Err(e) = attach_caps(&task, ipc, &mut msg) {
+        return e;
+    }
+    if let Err(e) = attach_extra(&task, ipc.info, &mut msg) {
+        return e;
+    }
 
-    let msg = FastMessage::with_data(MsgLabel::REPLY_OK, ipc.msg);
-    let result = IPC_MANAGER.lock().handle_send(curr_task_id, ep_id, msg);
+    let result = IPC_MANAGER.lock().handle_reply(curr_task_id, msg);
 
     match result {
         IpcResult::WakeReceiver { receiver } => {
@@ -239,9 +439,74 @@ pub(crate) fn handle_ipc_reply(
             }
             IpcSyscallRetCodes::IpcOk
         }
-        IpcResult::NotReady => IpcSyscallRetCodes::IpcNotReady,
-        IpcResult::Error(IpcError::InvalidEndpoint) => IpcSyscallRetCodes::IpcInvalidEp,
+        IpcResult::Error(IpcError::NoReplyObject) => IpcSyscallRetCodes::IpcNoReply,
         IpcResult::Error(_) => IpcSyscallRetCodes::IpcUnknown,
         _ => IpcSyscallRetCodes::IpcOk,
     }
-}
\ No newline at end of file
+}
+
+/// Replies to the current caller and waits on `ipc.ep_id` for the next
+/// message. Without a pending call this is a plain receive.
+pub(crate) fn handle_ipc_reply_recv(
+    curr_task_id: TaskIdIndex,
+    ipc: &IpcSyscallArguments,
+    curr_task_regs: &mut TaskRegisters,
+) -> IpcSyscallRetCodes {
+    let task = match get_task_by_index(curr_task_id) {
+        Some(t) => t,
+        None => return IpcSyscallRetCodes::IpcInvalidCap,
+    };
+
+    let ep_id = match resolve_endpoint_cap(&task, ipc.ep_id as CapIdx, Rights::READ) {
+        Ok((id, _)) => id,
+        Err(e) => return e,
+    };
+
+    let mut msg = FastMessage::with_data(MsgLabel::REPLY_OK, ipc.msg);
+    if let Err(e) = attach_caps(&task, ipc, &mut msg) {
+        return e;
+    }
+    if let Err(e) = attach_extra(&task, ipc.info, &mut msg) {
+        return e;
+    }
+
+    let (caller, result) = IPC_MANAGER.lock()
+        .handle_reply_recv(curr_task_id, ep_id, msg, !ipc.info.nonblock());
+
+    if let Some(task) = caller.and_then(get_task_by_index) {
+        awaken_task(task);
+    }
+
+    finish_recv(&task, curr_task_id, ep_id, ipc.timeout_ns, result, curr_task_regs)
+}
+
+/// Registers the page at `vaddr` as the current task's IPC buffer, or drops
+/// the registration when `vaddr` is 0. The page must sit in a writable user
+/// VMA.
+pub(crate) fn handle_ipc_set_buffer(curr_task_id: TaskIdIndex, vaddr: u64) -> IpcSyscallRetCodes {
+    let task = match get_task_by_index(curr_task_id) {
+        Some(t) => t,
+        None => return IpcSyscallRetCodes::IpcInvalidCap,
+    };
+
+    if vaddr == 0 {
+        *task.tcb.ipc_buffer.lock() = None;
+        return IpcSyscallRetCodes::IpcOk;
+    }
+
+    let Some(vaddr) = check_ipc_buffer(&task.tcb.addr_space().lock(), vaddr) else {
+        return IpcSyscallRetCodes::IpcNoBuffer;
+    };
+
+    *task.tcb.ipc_buffer.lock() = Some(vaddr);
+    IpcSyscallRetCodes::IpcOk
+}
+
+/// Checks that `vaddr` can serve as an IPC buffer in `vspace`: page aligned
+/// and inside a user writable mapping.
+pub(super) fn check_ipc_buffer(vspace: &AddrSpace, vaddr: u64) -> Option<VirtAddr> {
+    let vaddr = VirtAddr::try_new(vaddr).ok().filter(|va| va.is_aligned(PAGE_SIZE as u64))?;
+    vspace.find(vaddr)
+        .filter(|vma| vma.flags.contains(MapFlags::USER | MapFlags::WRITE))
+        .map(|_| vaddr)
+}
@@ -1,92 +1,223 @@
-use x86_64::VirtAddr;
+use alloc::sync::Arc;
+use x86_64::{PhysAddr, VirtAddr};
 
-use crate::arch::amd64::{ipc::{message::Rights, object_table::{KernelObjType, ObjData, with_object}}, memory::pmm::pages_allocator::{PAllocFlags, alloc_pages_by_order}, scheduler::{PerCpuSchedulerData, addr_space::{MapFlags, VmaBacking}, syscall::cap_check::{CapError, resolve_cap}, task_storage::get_task_by_index}};
+use crate::arch::amd64::{ipc::{cnode::CapIdx, message::Rights, object_table::{KernelObjType, ObjData, with_object}}, memory::vmm::PAGE_SIZE, scheduler::{PerCpuSchedulerData, addr_space::{MapFlags, USER_ADDR_LIMIT, VSpaceRef, VmaBacking, VmaError}, syscall::{cap_check::{CapError, resolve_cap}, untyped_handler::retype}, task::Task, task_storage::get_task_by_index}};
 
 pub enum MemorySyscallNumbers {
     FrameAlloc  = 0x2,
     VmaMap      = 0x3,
     VmaUnmap    = 0x4,
-    Mprotect    = 0x5
+    Mprotect    = 0x5,
+    ShmCreate   = 0x6,
+    ShmMap      = 0x7,
 }
 
-pub (crate) fn frame_alloc() -> u64 {
-    alloc_pages_by_order(0, PAllocFlags::ZEROED | PAllocFlags::KERNEL).expect("frame_alloc: OOM").as_u64()
+pub(super) enum MemError {
+    Cap(CapError),
+    Vma(VmaError),
+    /// The requested mapping is larger than the frame backing it.
+    FrameTooSmall,
+    /// The range is empty, wraps around or reaches past the user half.
+    BadRange,
 }
 
-pub(crate) fn vma_map(vspace_cap_idx: u64, vaddr: u64, size: u64, flags: u32) -> u64 {
-    let curr_task_id = PerCpuSchedulerData::get().curr_task_id.id();
-    let curr = get_task_by_index(curr_task_id).unwrap();
+impl From<CapError> for MemError {
+    fn from(e: CapError) -> Self {
+        MemError::Cap(e)
+    }
+}
 
-     let (handle, _rights) = match resolve_cap(&curr, vspace_cap_idx, KernelObjType::VSpace, Rights::WRITE) {
-        Ok(h) => h,
-        Err(e) => return e.as_syscall_err(),
-    };
+impl From<VmaError> for MemError {
+    fn from(e: VmaError) -> Self {
+        MemError::Vma(e)
+    }
+}
 
-    let target_task_id = match with_object(handle, |obj| {
-        match &obj.data {
-            ObjData::VSpace(task_id) => Some(*task_id),
-            _ => None,
+impl MemError {
+    /// Cap failures keep their `CapError` codes; the mapping errors sit
+    /// below them.
+    pub(super) fn as_syscall_err(&self) -> u64 {
+        match self {
+            MemError::Cap(e)                      => e.as_syscall_err(),
+            MemError::Vma(VmaError::NotAligned)   => u64::MAX - 16,
+            MemError::Vma(VmaError::Overlap)      => u64::MAX - 17,
+            MemError::Vma(VmaError::NotFound)     => u64::MAX - 18,
+            MemError::Vma(VmaError::PageTableError(_)) => u64::MAX - 19,
+            MemError::FrameTooSmall               => u64::MAX - 20,
+            MemError::BadRange                    => u64::MAX - 26,
         }
-    }).flatten() {
-        Some(id) => id,
-        None => return CapError::WrongType.as_syscall_err(),
-    };
-
-    let target = get_task_by_index(target_task_id).unwrap();
-    let map_flags = MapFlags::from_bits_truncate(flags);
-    target.tcb.addr_space.lock()
-        .map(VirtAddr::new(vaddr), size as usize, VmaBacking::Reserved, map_flags)
-        .unwrap();
+    }
+}
 
-    0
+fn into_syscall_ret(res: Result<(), MemError>) -> u64 {
+    match res {
+        Ok(())  => 0,
+        Err(e)  => e.as_syscall_err(),
+    }
 }
 
-pub(crate) fn vma_unmap(vspace_cap_idx: u64, vaddr: u64) -> u64 {
+fn current_task() -> Result<Arc<Task>, CapError> {
     let curr_task_id = PerCpuSchedulerData::get().curr_task_id.id();
-    let curr = get_task_by_index(curr_task_id).unwrap();
+    get_task_by_index(curr_task_id).ok_or(CapError::InvalidIdx)
+}
 
-    let (handle, _) = match resolve_cap(&curr, vspace_cap_idx, KernelObjType::VSpace, Rights::WRITE) {
-        Ok(h) => h,
-        Err(e) => return e.as_syscall_err(),
-    };
+/// Returns the address space `cap_idx` names.
+pub(super) fn resolve_vspace_cap(task: &Task, cap_idx: u64) -> Result<VSpaceRef, CapError> {
+    let handle = resolve_cap(task, cap_idx, KernelObjType::VSpace, Rights::WRITE)?.handle;
 
-    let target_task_id = match with_object(handle, |obj| {
+    with_object(handle, |obj| {
         match &obj.data {
-            ObjData::VSpace(task_id) => Some(*task_id),
+            ObjData::VSpace(vspace) => Some(vspace.clone()),
             _ => None,
         }
-    }).flatten() {
-        Some(id) => id,
-        None => return CapError::WrongType.as_syscall_err(),
-    };
-
-    let target = get_task_by_index(target_task_id).unwrap();
-    target.tcb.addr_space.lock().unmap(VirtAddr::new(vaddr)).unwrap();
-    0
+    })
+    .flatten()
+    .ok_or(CapError::WrongType)
 }
 
-pub(crate) fn mprotect(vspace_cap_idx: u64, vaddr: u64, flags: u32) -> u64 {
-    let curr_task_id = PerCpuSchedulerData::get().curr_task_id.id();
-    let curr = get_task_by_index(curr_task_id).unwrap();
+/// Returns the block behind a frame cap together with the cap's rights.
+fn resolve_frame_cap(task: &Task, cap_idx: u64) -> Result<(PhysAddr, usize, Rights), CapError> {
+    let cap = resolve_cap(task, cap_idx, KernelObjType::Frame, Rights::READ)?;
 
-    let (handle, _) = match resolve_cap(&curr, vspace_cap_idx, KernelObjType::VSpace, Rights::WRITE) {
-        Ok(h) => h,
-        Err(e) => return e.as_syscall_err(),
-    };
-
-    let target_task_id = match with_object(handle, |obj| {
+    with_object(cap.handle, |obj| {
         match &obj.data {
-            ObjData::VSpace(task_id) => Some(*task_id),
+            ObjData::Frame { phys, pages } => Some((*phys, *pages, cap.rights)),
             _ => None,
         }
-    }).flatten() {
-        Some(id) => id,
-        None => return CapError::WrongType.as_syscall_err(),
+    })
+    .flatten()
+    .ok_or(CapError::WrongType)
+}
+
+/// Checks that a frame cap with `rights` may be mapped with `flags`.
+fn check_frame_rights(rights: Rights, flags: MapFlags) -> Result<(), CapError> {
+    if flags.contains(MapFlags::WRITE) && !rights.contains(Rights::WRITE) {
+        return Err(CapError::InsufficientRights);
+    }
+    Ok(())
+}
+
+fn user_vaddr(vaddr: u64) -> Result<VirtAddr, MemError> {
+    if vaddr >= USER_ADDR_LIMIT {
+        return Err(MemError::BadRange);
+    }
+    VirtAddr::try_new(vaddr).map_err(|_| MemError::Vma(VmaError::NotAligned))
+}
+
+/// Start of `[vaddr, vaddr + size)`, which must be non-empty and lie in the
+/// user half.
+fn user_range(vaddr: u64, size: u64) -> Result<VirtAddr, MemError> {
+    let end = vaddr.checked_add(size).ok_or(MemError::BadRange)?;
+    if size == 0 || end > USER_ADDR_LIMIT {
+        return Err(MemError::BadRange);
+    }
+    user_vaddr(vaddr)
+}
+
+/// Frame of at least `size` bytes, rounded up to a power of two pages,
+/// retyped from the untyped at `untyped_cap_idx`.
+fn create_frame(untyped_cap_idx: u64, size: u64) -> Result<CapIdx, CapError> {
+    retype(untyped_cap_idx, KernelObjType::Frame, size)
+}
+
+/// Returns a frame cap for one zeroed page carved out of the untyped at
+/// `untyped_cap_idx`. The physical address stays with the kernel.
+pub(crate) fn frame_alloc(untyped_cap_idx: u64) -> u64 {
+    match create_frame(untyped_cap_idx, PAGE_SIZE as u64) {
+        Ok(idx) => idx as u64,
+        Err(e)  => e.as_syscall_err(),
+    }
+}
+
+fn map_vma(vspace_cap_idx: u64, vaddr: u64, size: u64, flags: u32, backing_cap_idx: u64) -> Result<(), MemError> {
+    let curr = current_task()?;
+    let target = resolve_vspace_cap(&curr, vspace_cap_idx)?;
+    let map_flags = MapFlags::from_bits_truncate(flags);
+    let start = user_range(vaddr, size)?;
+
+    let (backing, rights) = if resolve_cap(&curr, backing_cap_idx, KernelObjType::Untyped, Rights::NONE).is_ok() {
+        let untyped = resolve_cap(&curr, backing_cap_idx, KernelObjType::Untyped, Rights::WRITE)?;
+        (VmaBacking::Reserved { untyped: Some(untyped.handle) }, Rights::ALL)
+    } else {
+        let (phys, pages, rights) = resolve_frame_cap(&curr, backing_cap_idx)?;
+        check_frame_rights(rights, map_flags)?;
+        if size as usize > pages * PAGE_SIZE {
+            return Err(MemError::FrameTooSmall);
+        }
+        (VmaBacking::Physical { phys_addr: phys }, rights)
     };
 
-    let target = get_task_by_index(target_task_id).unwrap();
+    target.lock()
+        .map(start, size as usize, backing, map_flags, rights)?;
+    Ok(())
+}
+
+/// Maps `size` bytes at `vaddr` in the space of `vspace_cap_idx`. With a
+/// frame cap in `backing_cap_idx` the frame backs the mapping; with an
+/// untyped cap pages are carved out of the untyped on first touch.
+pub(crate) fn vma_map(vspace_cap_idx: u64, vaddr: u64, size: u64, flags: u32, backing_cap_idx: u64) -> u64 {
+    into_syscall_ret(map_vma(vspace_cap_idx, vaddr, size, flags, backing_cap_idx))
+}
+
+fn unmap_vma(vspace_cap_idx: u64, vaddr: u64) -> Result<(), MemError> {
+    let curr = current_task()?;
+    let target = resolve_vspace_cap(&curr, vspace_cap_idx)?;
+
+    target.lock().unmap(user_vaddr(vaddr)?)?;
+    Ok(())
+}
+
+pub(crate) fn vma_unmap(vspace_cap_idx: u64, vaddr: u64) -> u64 {
+    into_syscall_ret(unmap_vma(vspace_cap_idx, vaddr))
+}
+
+fn protect_vma(vspace_cap_idx: u64, vaddr: u64, flags: u32) -> Result<(), MemError> {
+    let curr = current_task()?;
+    let target = resolve_vspace_cap(&curr, vspace_cap_idx)?;
     let map_flags = MapFlags::from_bits_truncate(flags);
-    target.tcb.addr_space.lock().protect(VirtAddr::new(vaddr), map_flags).unwrap();
-    0
+    let vaddr = user_vaddr(vaddr)?;
+
+    let mut space = target.lock();
+    // The new flags must still be allowed by the cap the mapping was made with.
+    if let Some(vma) = space.find(vaddr) {
+        check_frame_rights(vma.rights, map_flags)?;
+    }
+    space.protect(vaddr, map_flags)?;
+    Ok(())
 }
 
+pub(crate) fn mprotect(vspace_cap_idx: u64, vaddr: u64, flags: u32) -> u64 {
+    into_syscall_ret(protect_vma(vspace_cap_idx, vaddr, flags))
+}
+
+/// Carves zeroed memory of at least `size` bytes, rounded up to a power of
+/// two pages, out of the untyped at `untyped_cap_idx` and returns a frame
+/// cap for it. The memory can be mapped into any number of address spaces
+/// with `shm_map`; it is released once the last cap is deleted and the last
+/// mapping is gone.
+pub(crate) fn shm_create(untyped_cap_idx: u64, size: u64) -> u64 {
+    match create_frame(untyped_cap_idx, size) {
+        Ok(idx) => idx as u64,
+        Err(e)  => e.as_syscall_err(),
+    }
+}
+
+fn map_shared(vspace_cap_idx: u64, frame_cap_idx: u64, vaddr: u64, flags: u32) -> Result<(), MemError> {
+    let curr = current_task()?;
+    let target = resolve_vspace_cap(&curr, vspace_cap_idx)?;
+    let (phys, pages, rights) = resolve_frame_cap(&curr, frame_cap_idx)?;
+    let map_flags = MapFlags::from_bits_truncate(flags);
+    check_frame_rights(rights, map_flags)?;
+    let size = pages * PAGE_SIZE;
+    let start = user_range(vaddr, size as u64)?;
+
+    target.lock()
+        .map(start, size, VmaBacking::Shared { phys_addr: phys }, map_flags, rights)?;
+    Ok(())
+}
+
+/// Maps the whole block behind `frame_cap_idx` at `vaddr` in the space of
+/// `vspace_cap_idx`. A writable mapping needs WRITE on the frame cap.
+pub(crate) fn shm_map(vspace_cap_idx: u64, frame_cap_idx: u64, vaddr: u64, flags: u32) -> u64 {
+    into_syscall_ret(map_shared(vspace_cap_idx, frame_cap_idx, vaddr, flags))
+}
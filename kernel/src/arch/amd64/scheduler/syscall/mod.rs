@@ -3,16 +3,25 @@ use core::arch::naked_asm;
 use spin::Mutex;
 use x86_64::{VirtAddr, registers::{control::{Efer, EferFlags}, model_specific::{LStar, SFMask}, rflags::RFlags}};
 
-use crate::{arch::amd64::{gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR}, ipc::{message::{FastMessage, MsgLabel}}, scheduler::{PerCpuSchedulerData, syscall::{ipc_handlers::{IpcSyscallNumbers, handle_ipc_call, handle_ipc_ep_create, handle_ipc_ep_destroy, handle_ipc_recv, handle_ipc_reply, handle_ipc_send}, memory_handler::{MemorySyscallNumbers, frame_alloc, mprotect, vma_map, vma_unmap}, thread_handler::{ThreadSyscallNums, thread_sleep}}, task::TaskRegisters}}, define_per_cpu_u64, early_print, early_println};
+use crate::{arch::amd64::{gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR}, ipc::message::{FastMessage, MAX_CAPS_PER_MSG, MsgInfo, MsgLabel}, scheduler::{PerCpuSchedulerData, syscall::{cnode_handler::{CNodeSyscallNumbers, cnode_copy, cnode_create, cnode_delete, cnode_mint, cnode_move, cnode_revoke}, ipc_handlers::{IpcSyscallNumbers, handle_ipc_call, handle_ipc_ep_create, handle_ipc_ep_destroy, handle_ipc_recv, handle_ipc_reply, handle_ipc_reply_recv, handle_ipc_send, handle_ipc_set_buffer}, memory_handler::{MemorySyscallNumbers, frame_alloc, mprotect, shm_create, shm_map, vma_map, vma_unmap}, notify_handler::{NotifySyscallNumbers, handle_notify_bind, handle_notify_create, handle_notify_poll, handle_notify_signal, handle_notify_unbind, handle_notify_wait}, thread_handler::{ThreadSyscallNums, thread_exit, thread_set_exit_notification, thread_set_fault_handler, thread_sleep}, tcb_handler::{TcbSyscallNumbers, tcb_configure, tcb_load_image, tcb_read_regs, tcb_resume, tcb_set_priority, tcb_set_regs, tcb_suspend}, sched_handler::{SchedSyscallNumbers, sched_bind, sched_configure, sched_unbind}, untyped_handler::{UntypedSyscallNumbers, untyped_retype}}, note_kernel_entry, task::{KernelEntry, TaskRegisters}}}, define_per_cpu_u64, early_print, early_println};
 
 mod ipc_handlers;
 mod memory_handler;
-mod thread_handler;
+pub(super) mod thread_handler;
+mod cnode_handler;
+mod notify_handler;
+pub(super) mod untyped_handler;
+mod tcb_handler;
+mod sched_handler;
 mod cap_check;
 
 struct IpcSyscallArguments {
     ep_id: u64,
     msg: [u64; 4],
+    info: MsgInfo,
+    cap_ptrs: [u64; MAX_CAPS_PER_MSG],
+    /// Nanoseconds recv and call may wait, 0 meaning forever.
+    timeout_ns: u64,
 }
 
 #[derive(Debug)]
@@ -23,6 +32,7 @@ struct SyscallArguments {
     arg3: u64,
     arg4: u64,
     arg5: u64,
+    arg6: u64,
 }
 
 
@@ -57,31 +67,94 @@ fn syscall_dispatcher(registers: &mut TaskRegisters, args: &SyscallArguments) ->
     let ipc = IpcSyscallArguments {
             ep_id: args.arg1,
             msg: [args.arg2, args.arg3, args.arg4, args.arg5],
+            info: MsgInfo::from_bits(registers.r9),
+            cap_ptrs: [registers.r12, registers.r13, registers.r14, registers.r15],
+            timeout_ns: registers.rbx,
     };
 
     match args.syscall_number {
-        x if x == IpcSyscallNumbers::IpcEpCreate as u64 => handle_ipc_ep_create(curr_task_id),
+        x if x == IpcSyscallNumbers::IpcEpCreate as u64 => handle_ipc_ep_create(args.arg1),
 
         x if x == IpcSyscallNumbers::IpcEpDestroy as u64 => handle_ipc_ep_destroy(curr_task_id, args.arg1) as u64,
 
         x if x == IpcSyscallNumbers::IpcSend as u64 => handle_ipc_send(curr_task_id, &ipc) as u64,
 
-        x if x == IpcSyscallNumbers::IpcRecv as u64 => handle_ipc_recv(curr_task_id, args.arg1, registers) as u64,
+        x if x == IpcSyscallNumbers::IpcRecv as u64 => handle_ipc_recv(curr_task_id, &ipc, registers) as u64,
 
         x if x == IpcSyscallNumbers::IpcCall as u64 => handle_ipc_call(curr_task_id, &ipc, registers) as u64,
 
         x if x == IpcSyscallNumbers::IpcReply as u64 => handle_ipc_reply(curr_task_id, &ipc) as u64,
 
-        x if x == MemorySyscallNumbers::FrameAlloc as u64 => frame_alloc(),
+        x if x == IpcSyscallNumbers::IpcReplyRecv as u64 => handle_ipc_reply_recv(curr_task_id, &ipc, registers) as u64,
 
-        x if x == MemorySyscallNumbers::VmaMap as u64 => vma_map(args.arg1, args.arg2, args.arg3, args.arg4 as u32),
+        x if x == IpcSyscallNumbers::IpcSetBuffer as u64 => handle_ipc_set_buffer(curr_task_id, args.arg1) as u64,
+
+        x if x == NotifySyscallNumbers::NotifyCreate as u64 => handle_notify_create(args.arg1),
+
+        x if x == NotifySyscallNumbers::NotifySignal as u64 => handle_notify_signal(curr_task_id, args.arg1, args.arg2) as u64,
+
+        x if x == NotifySyscallNumbers::NotifyWait as u64 => handle_notify_wait(curr_task_id, args.arg1, registers) as u64,
+
+        x if x == NotifySyscallNumbers::NotifyPoll as u64 => handle_notify_poll(curr_task_id, args.arg1, registers) as u64,
+
+        x if x == NotifySyscallNumbers::NotifyBind as u64 => handle_notify_bind(curr_task_id, args.arg1, args.arg2) as u64,
+
+        x if x == NotifySyscallNumbers::NotifyUnbind as u64 => handle_notify_unbind(curr_task_id, args.arg1) as u64,
+
+        x if x == MemorySyscallNumbers::FrameAlloc as u64 => frame_alloc(args.arg1),
+
+        x if x == MemorySyscallNumbers::VmaMap as u64 => vma_map(args.arg1, args.arg2, args.arg3, args.arg4 as u32, args.arg5),
 
         x if x == MemorySyscallNumbers::VmaUnmap as u64 => vma_unmap(args.arg1, args.arg2),
 
         x if x == MemorySyscallNumbers::Mprotect as u64 => mprotect(args.arg1, args.arg2, args.arg3 as u32),
+
+        x if x == MemorySyscallNumbers::ShmCreate as u64 => shm_create(args.arg1, args.arg2),
+
+        x if x == MemorySyscallNumbers::ShmMap as u64 => shm_map(args.arg1, args.arg2, args.arg3, args.arg4 as u32),
         
         x if x == ThreadSyscallNums::ThreadSleep as u64 => thread_sleep(args.arg1),
 
+        x if x == ThreadSyscallNums::ThreadExit as u64 => thread_exit(curr_task_id, args.arg1),
+
+        x if x == ThreadSyscallNums::ThreadSetFaultHandler as u64 => thread_set_fault_handler(curr_task_id, args.arg1, args.arg2) as u64,
+
+        x if x == ThreadSyscallNums::ThreadSetExitNotification as u64 => thread_set_exit_notification(curr_task_id, args.arg1, args.arg2) as u64,
+
+        x if x == TcbSyscallNumbers::TcbConfigure as u64 => tcb_configure(args.arg1, args.arg2, args.arg3, args.arg4, args.arg5),
+
+        x if x == TcbSyscallNumbers::TcbSetRegs as u64 => tcb_set_regs(args.arg1),
+
+        x if x == TcbSyscallNumbers::TcbReadRegs as u64 => tcb_read_regs(args.arg1),
+
+        x if x == TcbSyscallNumbers::TcbResume as u64 => tcb_resume(args.arg1),
+
+        x if x == TcbSyscallNumbers::TcbSuspend as u64 => tcb_suspend(args.arg1),
+
+        x if x == TcbSyscallNumbers::TcbLoadImage as u64 => tcb_load_image(args.arg1, args.arg2, args.arg3, args.arg4, args.arg5),
+
+        x if x == TcbSyscallNumbers::TcbSetPriority as u64 => tcb_set_priority(args.arg1, args.arg2, args.arg3),
+
+        x if x == SchedSyscallNumbers::SchedConfigure as u64 => sched_configure(args.arg1, args.arg2, args.arg3),
+
+        x if x == SchedSyscallNumbers::SchedBind as u64 => sched_bind(args.arg1, args.arg2),
+
+        x if x == SchedSyscallNumbers::SchedUnbind as u64 => sched_unbind(args.arg1),
+
+        x if x == CNodeSyscallNumbers::CNodeCopy as u64 => cnode_copy(args.arg1, args.arg2, args.arg3, args.arg4, args.arg5),
+
+        x if x == CNodeSyscallNumbers::CNodeMint as u64 => cnode_mint(args.arg1, args.arg2, args.arg3, args.arg4, args.arg5, args.arg6),
+
+        x if x == CNodeSyscallNumbers::CNodeMove as u64 => cnode_move(args.arg1, args.arg2, args.arg3, args.arg4),
+
+        x if x == CNodeSyscallNumbers::CNodeDelete as u64 => cnode_delete(args.arg1, args.arg2),
+
+        x if x == CNodeSyscallNumbers::CNodeRevoke as u64 => cnode_revoke(args.arg1, args.arg2),
+
+        x if x == CNodeSyscallNumbers::CNodeCreate as u64 => cnode_create(args.arg1, args.arg2, args.arg3, args.arg4),
+
+        x if x == UntypedSyscallNumbers::UntypedRetype as u64 => untyped_retype(args.arg1, args.arg2, args.arg3),
+
         _ => {
             early_println!("Unknown syscall: {} task={}", args.syscall_number, curr_task_id);
             return 0;
@@ -181,6 +254,8 @@ pub(super) unsafe extern "C" fn syscall_handler() {
 }
 
 extern "C" fn syscall_handler_inner(registers: &mut TaskRegisters) {
+    note_kernel_entry(KernelEntry::Syscall);
+
     let args = SyscallArguments {
         syscall_number: registers.syscall_number_or_irq_or_error_code,
         arg1: registers.rdi,
@@ -188,6 +263,7 @@ extern "C" fn syscall_handler_inner(registers: &mut TaskRegisters) {
         arg3: registers.rdx,
         arg4: registers.r10,
         arg5: registers.r8,
+        arg6: registers.r9,
     };
 
     registers.syscall_number_or_irq_or_error_code = syscall_dispatcher(registers, &args);
@@ -0,0 +1,199 @@
+use crate::arch::amd64::{
+    ipc::{
+        IPC_MANAGER, IpcError, IpcResult,
+        message::{MsgLabel, Rights},
+        notification::NotificationId,
+        object_table::{KernelObjType, ObjData, with_object},
+    },
+    scheduler::{
+        awaken_task, block_current_on_ipc,
+        syscall::{cap_check::resolve_cap, ipc_handlers::IpcSyscallRetCodes, untyped_handler::retype},
+        task::{Task, TaskIdIndex, TaskRegisters},
+        task_storage::get_task_by_index,
+    },
+};
+
+#[allow(clippy::enum_variant_names)]
+pub(crate) enum NotifySyscallNumbers {
+    NotifyCreate = 0x70,
+    NotifySignal = 0x71,
+    NotifyWait   = 0x72,
+    NotifyPoll   = 0x73,
+    NotifyBind   = 0x74,
+    NotifyUnbind = 0x75,
+}
+
+pub(super) fn resolve_notification_cap(
+    task: &Task,
+    cap_idx: u64,
+    required_rights: Rights,
+) -> Result<NotificationId, IpcSyscallRetCodes> {
+    let handle = resolve_cap(task, cap_idx, KernelObjType::Notification, required_rights)
+        .map_err(|_| IpcSyscallRetCodes::IpcInvalidCap)?
+        .handle;
+
+    with_object(handle, |obj| {
+        match &obj.data {
+            ObjData::Notification(id) => Some(*id),
+            _ => None,
+        }
+    })
+    .flatten()
+    .ok_or(IpcSyscallRetCodes::IpcInvalidCap)
+}
+
+pub(super) fn resolve_thread_cap(task: &Task, cap_idx: u64) -> Result<TaskIdIndex, IpcSyscallRetCodes> {
+    let handle = resolve_cap(task, cap_idx, KernelObjType::Thread, Rights::WRITE)
+        .map_err(|_| IpcSyscallRetCodes::IpcInvalidCap)?
+        .handle;
+
+    with_object(handle, |obj| {
+        match &obj.data {
+            ObjData::Thread(id) => Some(*id),
+            _ => None,
+        }
+    })
+    .flatten()
+    .ok_or(IpcSyscallRetCodes::IpcInvalidCap)
+}
+
+/// Retypes part of the untyped at `untyped_cap_idx` into a notification.
+pub(crate) fn handle_notify_create(untyped_cap_idx: u64) -> u64 {
+    match retype(untyped_cap_idx, KernelObjType::Notification, 0) {
+        Ok(idx) => idx as u64,
+        Err(e)  => e.as_syscall_err(),
+    }
+}
+
+pub(crate) fn handle_notify_signal(curr_task_id: TaskIdIndex, cap_idx: u64, badge: u64) -> IpcSyscallRetCodes {
+    let task = match get_task_by_index(curr_task_id) {
+        Some(t) => t,
+        None => return IpcSyscallRetCodes::IpcInvalidCap,
+    };
+
+    let id = match resolve_notification_cap(&task, cap_idx, Rights::WRITE) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+
+    let result = IPC_MANAGER.lock().signal_notification(id, badge);
+
+    match result {
+        IpcResult::WakeReceiver { receiver } => {
+            if let Some(task) = get_task_by_index(receiver) {
+                awaken_task(task);
+            }
+            IpcSyscallRetCodes::IpcOk
+        }
+        IpcResult::Error(_) => IpcSyscallRetCodes::IpcInvalidCap,
+        _ => IpcSyscallRetCodes::IpcOk,
+    }
+}
+
+/// Blocks until the notification has a badge set and returns the collected
+/// badges in `rdi`.
+pub(crate) fn handle_notify_wait(
+    curr_task_id: TaskIdIndex,
+    cap_idx: u64,
+    curr_task_regs: &mut TaskRegisters,
+) -> IpcSyscallRetCodes {
+    let task = match get_task_by_index(curr_task_id) {
+        Some(t) => t,
+        None => return IpcSyscallRetCodes::IpcInvalidCap,
+    };
+
+    let id = match resolve_notification_cap(&task, cap_idx, Rights::READ) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+
+    loop {
+        let result = IPC_MANAGER.lock().wait_notification(curr_task_id, id);
+        match result {
+            Ok(Some(badges)) => {
+                curr_task_regs.rdi = badges;
+                return IpcSyscallRetCodes::IpcOk;
+            }
+            Ok(None) => {
+                block_current_on_ipc();
+                let msg = IPC_MANAGER.lock().take_pending_message(curr_task_id);
+                match msg {
+                    Some(msg) if msg.label == MsgLabel::CLOSED => return IpcSyscallRetCodes::IpcInvalidCap,
+                    Some(msg) => {
+                        curr_task_regs.rdi = msg.data[0];
+                        return IpcSyscallRetCodes::IpcOk;
+                    }
+                    None => {}
+                }
+            }
+            Err(IpcError::NotReady) => return IpcSyscallRetCodes::IpcNotReady,
+            Err(_) => return IpcSyscallRetCodes::IpcInvalidCap,
+        }
+    }
+}
+
+/// Returns and clears the pending badges in `rdi` without blocking.
+pub(crate) fn handle_notify_poll(
+    curr_task_id: TaskIdIndex,
+    cap_idx: u64,
+    curr_task_regs: &mut TaskRegisters,
+) -> IpcSyscallRetCodes {
+    let task = match get_task_by_index(curr_task_id) {
+        Some(t) => t,
+        None => return IpcSyscallRetCodes::IpcInvalidCap,
+    };
+
+    let id = match resolve_notification_cap(&task, cap_idx, Rights::READ) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+
+    match IPC_MANAGER.lock().poll_notification(id) {
+        Ok(badges) => {
+            curr_task_regs.rdi = badges;
+            IpcSyscallRetCodes::IpcOk
+        }
+        Err(_) => IpcSyscallRetCodes::IpcInvalidCap,
+    }
+}
+
+/// Binds a notification to a thread. While bound, a signal also ends a
+/// `recv` the thread is blocked in, which then returns a `NOTIFY` message.
+pub(crate) fn handle_notify_bind(curr_task_id: TaskIdIndex, tcb_cap: u64, ntfn_cap: u64) -> IpcSyscallRetCodes {
+    let task = match get_task_by_index(curr_task_id) {
+        Some(t) => t,
+        None => return IpcSyscallRetCodes::IpcInvalidCap,
+    };
+
+    let tcb = match resolve_thread_cap(&task, tcb_cap) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+
+    let id = match resolve_notification_cap(&task, ntfn_cap, Rights::READ) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+
+    match IPC_MANAGER.lock().bind_notification(tcb, id) {
+        Ok(())                   => IpcSyscallRetCodes::IpcOk,
+        Err(IpcError::NotReady)  => IpcSyscallRetCodes::IpcPermissionDenied,
+        Err(_)                   => IpcSyscallRetCodes::IpcInvalidCap,
+    }
+}
+
+pub(crate) fn handle_notify_unbind(curr_task_id: TaskIdIndex, tcb_cap: u64) -> IpcSyscallRetCodes {
+    let task = match get_task_by_index(curr_task_id) {
+        Some(t) => t,
+        None => return IpcSyscallRetCodes::IpcInvalidCap,
+    };
+
+    let tcb = match resolve_thread_cap(&task, tcb_cap) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+
+    IPC_MANAGER.lock().unbind_notification(tcb);
+
+    IpcSyscallRetCodes::IpcOk
+}
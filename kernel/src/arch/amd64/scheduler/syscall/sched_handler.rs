@@ -0,0 +1,91 @@
+use alloc::sync::Arc;
+
+use crate::arch::amd64::{
+    ipc::{message::Rights, object_table::{KernelObjType, ObjData, with_object}},
+    scheduler::{
+        PerCpuSchedulerData,
+        sched_context::{SchedContextRef, bind_sched_context, configure_sched_context, unbind_sched_context},
+        syscall::{cap_check::{CapError, resolve_cap}, tcb_handler::resolve_tcb},
+        task::Task,
+        task_storage::get_task_by_index,
+    },
+};
+
+#[allow(clippy::enum_variant_names)]
+pub(crate) enum SchedSyscallNumbers {
+    SchedConfigure = 0x40,
+    SchedBind      = 0x41,
+    SchedUnbind    = 0x42,
+}
+
+fn current_task() -> Result<Arc<Task>, CapError> {
+    let curr_task_id = PerCpuSchedulerData::get().curr_task_id.id();
+    get_task_by_index(curr_task_id).ok_or(CapError::InvalidIdx)
+}
+
+fn into_syscall_ret(res: Result<(), CapError>) -> u64 {
+    match res {
+        Ok(())  => 0,
+        Err(e)  => e.as_syscall_err(),
+    }
+}
+
+fn resolve_sched_context_cap(task: &Task, cap_idx: u64) -> Result<SchedContextRef, CapError> {
+    let handle = resolve_cap(task, cap_idx, KernelObjType::SchedContext, Rights::WRITE)?.handle;
+
+    with_object(handle, |obj| {
+        match &obj.data {
+            ObjData::SchedContext(sc) => Some(sc.clone()),
+            _ => None,
+        }
+    })
+    .flatten()
+    .ok_or(CapError::WrongType)
+}
+
+fn configure(sc_cap: u64, budget_ns: u64, period_ns: u64) -> Result<(), CapError> {
+    let task = current_task()?;
+    let sc = resolve_sched_context_cap(&task, sc_cap)?;
+
+    if budget_ns == 0 || budget_ns > period_ns {
+        return Err(CapError::InvalidArgument);
+    }
+
+    configure_sched_context(&sc, budget_ns, period_ns);
+    Ok(())
+}
+
+fn bind(sc_cap: u64, tcb_cap: u64) -> Result<(), CapError> {
+    let task = current_task()?;
+    let sc = resolve_sched_context_cap(&task, sc_cap)?;
+    let target = resolve_tcb(&task, tcb_cap, Rights::WRITE)?;
+
+    if !bind_sched_context(&sc, &target) {
+        return Err(CapError::SlotOccupied);
+    }
+    Ok(())
+}
+
+fn unbind(sc_cap: u64) -> Result<(), CapError> {
+    let task = current_task()?;
+    let sc = resolve_sched_context_cap(&task, sc_cap)?;
+
+    unbind_sched_context(&sc);
+    Ok(())
+}
+
+/// Gives the context `budget_ns` of CPU time in every `period_ns`, starting
+/// with a full budget.
+pub(crate) fn sched_configure(sc_cap: u64, budget_ns: u64, period_ns: u64) -> u64 {
+    into_syscall_ret(configure(sc_cap, budget_ns, period_ns))
+}
+
+/// Meters the thread at `tcb_cap` with the context. Fails with
+/// `SlotOccupied` when either is already bound.
+pub(crate) fn sched_bind(sc_cap: u64, tcb_cap: u64) -> u64 {
+    into_syscall_ret(bind(sc_cap, tcb_cap))
+}
+
+pub(crate) fn sched_unbind(sc_cap: u64) -> u64 {
+    into_syscall_ret(unbind(sc_cap))
+}
@@ -0,0 +1,416 @@
+use alloc::sync::Arc;
+use core::sync::atomic::Ordering;
+
+use crate::{arch::amd64::{
+    cpu::frames::InterruptFrame,
+    ipc::{IPC_MANAGER, cnode::CapIdx, message::Rights, object_table::{KernelObjType, ObjData, with_object}},
+    scheduler::{
+        PerCpuSchedulerData, addr_space::{USER_ADDR_LIMIT, VmaError}, clear_wake_timeout,
+        elf_loader::{ElfLoadError, load_elf, map_user_stack, unmap_user_stack}, resume_task, suspend_current_task,
+        wait_until_off_cpu,
+        fault::FaultHandler,
+        syscall::{
+            cap_check::{CapError, resolve_cap},
+            cnode_handler::resolve_cnode_cap,
+            ipc_handlers::{check_ipc_buffer, resolve_endpoint_cap, with_ipc_buffer},
+            memory_handler::{MemError, resolve_vspace_cap},
+            thread_handler::NO_FAULT_HANDLER,
+        },
+        task::{KernelEntry, MAX_PRIORITY, Task, TaskRegisters, TaskState},
+        task_storage::get_task_by_index,
+    },
+}, bootinfo::BootInfo, cpio_parser::cpio_find};
+
+#[allow(clippy::enum_variant_names)]
+pub enum TcbSyscallNumbers {
+    TcbConfigure = 0x30,
+    TcbSetRegs   = 0x31,
+    TcbReadRegs  = 0x32,
+    TcbResume    = 0x33,
+    TcbSuspend   = 0x34,
+    TcbLoadImage = 0x35,
+    TcbSetPriority = 0x36,
+}
+
+/// Longest boot image name `tcb_load_image` accepts.
+const MAX_IMAGE_NAME: usize = 64;
+
+/// Number of words `tcb_read_regs` and `tcb_set_regs` move through the
+/// caller's IPC buffer, in this order: rip, rsp, rflags, rax, rbx, rcx, rdx,
+/// rsi, rdi, rbp, r8..r15.
+const REGS_WORDS: usize = 18;
+
+/// Flags user code may set: CF, PF, AF, ZF, SF, TF, DF, OF. IF and the
+/// reserved bit 1 are forced on.
+const USER_RFLAGS_MASK: u64 = 0xDD5;
+const USER_RFLAGS_FORCED: u64 = 0x202;
+
+enum TcbError {
+    Cap(CapError),
+    /// The thread is running, or blocked somewhere its registers and
+    /// bindings cannot be touched.
+    NotStopped,
+    /// The caller has no IPC buffer, or the one given to `tcb_configure`
+    /// is not usable in the new address space.
+    NoBuffer,
+    /// `rip` or `rsp` would point outside the user half.
+    InvalidRegs,
+    /// The boot image is not a static x86_64 ELF executable.
+    BadImage,
+    /// No file of that name in the boot archive.
+    NoImage,
+    Mem(MemError),
+}
+
+impl From<CapError> for TcbError {
+    fn from(e: CapError) -> Self {
+        TcbError::Cap(e)
+    }
+}
+
+impl From<VmaError> for TcbError {
+    fn from(e: VmaError) -> Self {
+        TcbError::Mem(MemError::Vma(e))
+    }
+}
+
+impl From<ElfLoadError> for TcbError {
+    fn from(e: ElfLoadError) -> Self {
+        match e {
+            ElfLoadError::Vma(e) => e.into(),
+            _ => TcbError::BadImage,
+        }
+    }
+}
+
+impl TcbError {
+    /// Cap failures keep their `CapError` codes; the rest sit below the
+    /// memory errors.
+    fn as_syscall_err(&self) -> u64 {
+        match self {
+            TcbError::Cap(e)      => e.as_syscall_err(),
+            TcbError::NotStopped  => u64::MAX - 21,
+            TcbError::NoBuffer    => u64::MAX - 22,
+            TcbError::InvalidRegs => u64::MAX - 23,
+            TcbError::BadImage    => u64::MAX - 24,
+            TcbError::NoImage     => u64::MAX - 25,
+            TcbError::Mem(e)      => e.as_syscall_err(),
+        }
+    }
+}
+
+fn into_syscall_ret(res: Result<(), TcbError>) -> u64 {
+    match res {
+        Ok(())  => 0,
+        Err(e)  => e.as_syscall_err(),
+    }
+}
+
+fn current_task() -> Result<Arc<Task>, CapError> {
+    let curr_task_id = PerCpuSchedulerData::get().curr_task_id.id();
+    get_task_by_index(curr_task_id).ok_or(CapError::InvalidIdx)
+}
+
+/// Returns the thread behind a `Thread` cap of the caller.
+pub(super) fn resolve_tcb(task: &Task, cap_idx: u64, rights: Rights) -> Result<Arc<Task>, CapError> {
+    let handle = resolve_cap(task, cap_idx, KernelObjType::Thread, rights)?.handle;
+
+    with_object(handle, |obj| {
+        match &obj.data {
+            ObjData::Thread(id) => Some(*id),
+            _ => None,
+        }
+    })
+    .flatten()
+    .and_then(get_task_by_index)
+    .ok_or(CapError::WrongType)
+}
+
+/// Which user frame of `target` may be read or written right now. That is
+/// the case while it is suspended, or while it waits on its fault handler
+/// after an exception, and once it has left its CPU.
+fn stopped_entry(target: &Task) -> Result<KernelEntry, TcbError> {
+    let entry = target.tcb.kernel_entry.load(Ordering::Acquire);
+    match target.tcb.task_state.load(Ordering::Acquire) {
+        TaskState::Inactive => {}
+        TaskState::Sleep if matches!(entry, KernelEntry::Interrupt) => {}
+        _ => return Err(TcbError::NotStopped),
+    }
+    wait_until_off_cpu(target);
+    Ok(entry)
+}
+
+fn syscall_frame_regs(f: &TaskRegisters) -> [u64; REGS_WORDS] {
+    [
+        f.rip, f.rsp, f.rflags, f.syscall_number_or_irq_or_error_code, f.rbx, f.rcx, f.rdx,
+        f.rsi, f.rdi, f.rbp, f.r8, f.r9, f.r10, f.r11, f.r12, f.r13, f.r14, f.r15,
+    ]
+}
+
+fn interrupt_frame_regs(f: &InterruptFrame) -> [u64; REGS_WORDS] {
+    [
+        f.rip, f.rsp, f.rflags, f.rax, f.rbx, f.rcx, f.rdx,
+        f.rsi, f.rdi, f.rbp, f.r8, f.r9, f.r10, f.r11, f.r12, f.r13, f.r14, f.r15,
+    ]
+}
+
+/// `rax` goes where the syscall exit path loads it from, the return value
+/// slot.
+fn load_syscall_frame(f: &mut TaskRegisters, r: &[u64; REGS_WORDS]) {
+    f.rip = r[0]; f.rsp = r[1]; f.rflags = r[2];
+    f.syscall_number_or_irq_or_error_code = r[3];
+    f.rbx = r[4]; f.rcx = r[5]; f.rdx = r[6]; f.rsi = r[7]; f.rdi = r[8]; f.rbp = r[9];
+    f.r8 = r[10]; f.r9 = r[11]; f.r10 = r[12]; f.r11 = r[13];
+    f.r12 = r[14]; f.r13 = r[15]; f.r14 = r[16]; f.r15 = r[17];
+}
+
+fn load_interrupt_frame(f: &mut InterruptFrame, r: &[u64; REGS_WORDS]) {
+    f.rip = r[0]; f.rsp = r[1]; f.rflags = r[2];
+    f.rax = r[3]; f.rbx = r[4]; f.rcx = r[5]; f.rdx = r[6]; f.rsi = r[7]; f.rdi = r[8]; f.rbp = r[9];
+    f.r8 = r[10]; f.r9 = r[11]; f.r10 = r[12]; f.r11 = r[13];
+    f.r12 = r[14]; f.r13 = r[15]; f.r14 = r[16]; f.r15 = r[17];
+}
+
+/// Gives an `Inactive` thread its address space, root CNode, fault endpoint
+/// and IPC buffer. Nothing changes if any of them is invalid.
+fn configure(tcb_cap: u64, vspace_cap: u64, cnode_cap: u64, fault_ep_cap: u64, ipc_buffer: u64) -> Result<(), TcbError> {
+    let task = current_task()?;
+    let target = resolve_tcb(&task, tcb_cap, Rights::WRITE)?;
+
+    let vspace = resolve_vspace_cap(&task, vspace_cap)?;
+    let cnode = resolve_cnode_cap(&task, cnode_cap, Rights::WRITE)?;
+
+    let fault_handler = if fault_ep_cap == NO_FAULT_HANDLER {
+        None
+    } else {
+        let (ep, badge) = resolve_endpoint_cap(&task, fault_ep_cap as CapIdx, Rights::WRITE)
+            .map_err(|_| CapError::WrongType)?;
+        Some(FaultHandler { ep, badge })
+    };
+
+    let buffer = if ipc_buffer == 0 {
+        None
+    } else {
+        Some(check_ipc_buffer(&vspace.lock(), ipc_buffer).ok_or(TcbError::NoBuffer)?)
+    };
+
+    if !matches!(target.tcb.task_state.load(Ordering::Acquire), TaskState::Inactive) {
+        return Err(TcbError::NotStopped);
+    }
+
+    *target.tcb.addr_space.lock() = vspace;
+    *target.tcb.cnode.lock() = cnode;
+    *target.tcb.fault_handler.lock() = fault_handler;
+    *target.tcb.ipc_buffer.lock() = buffer;
+    Ok(())
+}
+
+/// Copies the thread's user registers into the caller's IPC buffer.
+fn read_regs(tcb_cap: u64) -> Result<(), TcbError> {
+    let task = current_task()?;
+    let target = resolve_tcb(&task, tcb_cap, Rights::READ)?;
+
+    let regs = match stopped_entry(&target)? {
+        KernelEntry::Syscall   => syscall_frame_regs(unsafe { &*target.syscall_frame() }),
+        KernelEntry::Interrupt => interrupt_frame_regs(unsafe { &*target.interrupt_frame() }),
+    };
+
+    with_ipc_buffer(&task, |buf| buf.words[..REGS_WORDS].copy_from_slice(&regs))
+        .ok_or(TcbError::NoBuffer)
+}
+
+/// Loads the thread's user registers from the caller's IPC buffer. The
+/// privileged bits of `rflags` are not the caller's to choose.
+fn set_regs(tcb_cap: u64) -> Result<(), TcbError> {
+    let task = current_task()?;
+    let target = resolve_tcb(&task, tcb_cap, Rights::WRITE)?;
+
+    let mut regs = [0u64; REGS_WORDS];
+    with_ipc_buffer(&task, |buf| regs.copy_from_slice(&buf.words[..REGS_WORDS]))
+        .ok_or(TcbError::NoBuffer)?;
+
+    if regs[0] >= USER_ADDR_LIMIT || regs[1] >= USER_ADDR_LIMIT {
+        return Err(TcbError::InvalidRegs);
+    }
+    regs[2] = (regs[2] & USER_RFLAGS_MASK) | USER_RFLAGS_FORCED;
+
+    match stopped_entry(&target)? {
+        KernelEntry::Syscall   => load_syscall_frame(unsafe { &mut *target.syscall_frame() }, &regs),
+        KernelEntry::Interrupt => load_interrupt_frame(unsafe { &mut *target.interrupt_frame() }, &regs),
+    }
+    Ok(())
+}
+
+/// Reads the boot image name the caller passed at `ptr`.
+fn read_image_name(task: &Task, ptr: u64, len: u64, buf: &mut [u8; MAX_IMAGE_NAME]) -> Result<usize, CapError> {
+    let len = len as usize;
+    if len == 0 || len > MAX_IMAGE_NAME {
+        return Err(CapError::InvalidArgument);
+    }
+    task.tcb.addr_space().lock()
+        .read_user(ptr, &mut buf[..len])
+        .ok_or(CapError::InvalidArgument)?;
+    Ok(len)
+}
+
+/// Loads the ELF file `name` of the boot archive into the address space of
+/// `vspace_cap`, reserves a user stack there and points the `Inactive`
+/// thread at the entry point. Image and stack pages are carved out of the
+/// untyped at `untyped_cap` as they are touched. The thread still needs
+/// `tcb_configure` with the same address space and `tcb_resume` to run.
+fn load_image(tcb_cap: u64, vspace_cap: u64, untyped_cap: u64, name_ptr: u64, name_len: u64) -> Result<(), TcbError> {
+    let task = current_task()?;
+    let target = resolve_tcb(&task, tcb_cap, Rights::WRITE)?;
+    let vspace = resolve_vspace_cap(&task, vspace_cap)?;
+    let untyped = resolve_cap(&task, untyped_cap, KernelObjType::Untyped, Rights::WRITE)?.handle;
+
+    let mut buf = [0u8; MAX_IMAGE_NAME];
+    let len = read_image_name(&task, name_ptr, name_len, &mut buf)?;
+    let name = core::str::from_utf8(&buf[..len]).map_err(|_| CapError::InvalidArgument)?;
+
+    let image = BootInfo::get_init_srvs()
+        .and_then(|archive| cpio_find(archive, name))
+        .ok_or(TcbError::NoImage)?;
+
+    if !matches!(target.tcb.task_state.load(Ordering::Acquire), TaskState::Inactive) {
+        return Err(TcbError::NotStopped);
+    }
+
+    let (entry, rsp) = {
+        let mut addr_space = vspace.lock();
+        let rsp = map_user_stack(&mut addr_space, Some(untyped))?;
+        match load_elf(&mut addr_space, image, Some(untyped)) {
+            Ok(entry) => (entry, rsp),
+            Err(e) => {
+                unmap_user_stack(&mut addr_space);
+                return Err(e.into());
+            }
+        }
+    };
+
+    match target.tcb.kernel_entry.load(Ordering::Acquire) {
+        KernelEntry::Syscall => {
+            let frame = unsafe { &mut *target.syscall_frame() };
+            frame.rip = entry;
+            frame.rsp = rsp;
+        }
+        KernelEntry::Interrupt => {
+            let frame = unsafe { &mut *target.interrupt_frame() };
+            frame.rip = entry;
+            frame.rsp = rsp;
+        }
+    }
+    Ok(())
+}
+
+/// Takes the thread off the run queues until `tcb_resume`. A thread blocked
+/// in IPC or sleep leaves its wait queue, and the interrupted call returns
+/// as timed out once it is resumed. A thread running on another CPU or
+/// waiting on its fault handler cannot be suspended.
+///
+/// A thread that just blocked or was preempted may still be switching away
+/// on its CPU; this returns only once it is off it.
+fn suspend(tcb_cap: u64) -> Result<(), TcbError> {
+    let task = current_task()?;
+    let target = resolve_tcb(&task, tcb_cap, Rights::WRITE)?;
+
+    if Arc::ptr_eq(&task, &target) {
+        drop(target);
+        drop(task);
+        suspend_current_task();
+        return Ok(());
+    }
+
+    let state = &target.tcb.task_state;
+    loop {
+        match state.load(Ordering::Acquire) {
+            TaskState::Inactive => break,
+
+            TaskState::Ready => {
+                // The stale run queue entry is dropped when it is popped.
+                if state.compare_exchange(TaskState::Ready, TaskState::Inactive, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                    break;
+                }
+            }
+
+            TaskState::Throttled => {
+                if state.compare_exchange(TaskState::Throttled, TaskState::Inactive, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                    clear_wake_timeout(&target);
+                    break;
+                }
+            }
+
+            TaskState::Sleep if matches!(target.tcb.kernel_entry.load(Ordering::Acquire), KernelEntry::Syscall) => {
+                let mut ipc = IPC_MANAGER.lock();
+                if state.compare_exchange(TaskState::Sleep, TaskState::Inactive, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                    ipc.cancel_task(target.id.id());
+                    clear_wake_timeout(&target);
+                    break;
+                }
+            }
+
+            _ => return Err(TcbError::NotStopped),
+        }
+    }
+
+    wait_until_off_cpu(&target);
+    Ok(())
+}
+
+/// Lets a suspended or never started thread run. Threads in any other state
+/// are left alone.
+fn resume(tcb_cap: u64) -> Result<(), TcbError> {
+    let task = current_task()?;
+    let target = resolve_tcb(&task, tcb_cap, Rights::WRITE)?;
+
+    resume_task(target);
+    Ok(())
+}
+
+/// Sets the thread's priority and the ceiling it may hand out to others.
+/// Neither may exceed the caller's own ceiling. A thread already queued
+/// moves to its new level the next time it is queued.
+fn set_priority(tcb_cap: u64, priority: u64, max_priority: u64) -> Result<(), TcbError> {
+    let task = current_task()?;
+    let target = resolve_tcb(&task, tcb_cap, Rights::WRITE)?;
+
+    if priority > MAX_PRIORITY as u64 || max_priority > MAX_PRIORITY as u64 {
+        return Err(CapError::InvalidArgument.into());
+    }
+    let ceiling = task.tcb.max_priority.load(Ordering::Relaxed) as u64;
+    if priority > ceiling || max_priority > ceiling {
+        return Err(CapError::InsufficientRights.into());
+    }
+
+    target.tcb.max_priority.store(max_priority as u8, Ordering::Relaxed);
+    target.tcb.priority.store(priority as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+pub(crate) fn tcb_configure(tcb_cap: u64, vspace_cap: u64, cnode_cap: u64, fault_ep_cap: u64, ipc_buffer: u64) -> u64 {
+    into_syscall_ret(configure(tcb_cap, vspace_cap, cnode_cap, fault_ep_cap, ipc_buffer))
+}
+
+pub(crate) fn tcb_read_regs(tcb_cap: u64) -> u64 {
+    into_syscall_ret(read_regs(tcb_cap))
+}
+
+pub(crate) fn tcb_set_regs(tcb_cap: u64) -> u64 {
+    into_syscall_ret(set_regs(tcb_cap))
+}
+
+pub(crate) fn tcb_load_image(tcb_cap: u64, vspace_cap: u64, untyped_cap: u64, name_ptr: u64, name_len: u64) -> u64 {
+    into_syscall_ret(load_image(tcb_cap, vspace_cap, untyped_cap, name_ptr, name_len))
+}
+
+pub(crate) fn tcb_suspend(tcb_cap: u64) -> u64 {
+    into_syscall_ret(suspend(tcb_cap))
+}
+
+pub(crate) fn tcb_resume(tcb_cap: u64) -> u64 {
+    into_syscall_ret(resume(tcb_cap))
+}
+
+pub(crate) fn tcb_set_priority(tcb_cap: u64, priority: u64, max_priority: u64) -> u64 {
+    into_syscall_ret(set_priority(tcb_cap, priority, max_priority))
+}
@@ -1,18 +1,140 @@
-use crate::arch::amd64::scheduler::{sleep, task::{TaskId, TaskIdIndex}, task_storage::get_task_by_index};
+use alloc::sync::Arc;
 
+use crate::arch::amd64::{
+    ipc::{IPC_MANAGER, IpcResult, cnode::CapIdx, message::Rights, notification::badges},
+    scheduler::{
+        awaken_task, exit_current_task,
+        fault::FaultHandler,
+        sleep,
+        syscall::{cnode_handler::release_cnode_caps, ipc_handlers::{IpcSyscallRetCodes, resolve_endpoint_cap}, notify_handler::{resolve_notification_cap, resolve_thread_cap}},
+        task::{Task, TaskIdIndex, TaskState},
+        task_storage::{for_each_task, get_task_by_index, remove_task},
+    },
+};
+
+#[allow(clippy::enum_variant_names)]
 pub enum ThreadSyscallNums {
     ThreadSleep = 0x99,
-    ThreadExit = 0x11
+    ThreadExit = 0x11,
+    ThreadSetFaultHandler = 0x12,
+    ThreadSetExitNotification = 0x13,
 }
 
-pub (crate) fn thread_exit(self_id: u64, code: u64) -> ! {
-    //let task = get_task_by_index(self_id as TaskIdIndex).expect("Task not found");
-    //drop(task.addr_space.lock());
-    todo!()
+/// Endpoint argument of `thread_set_fault_handler` that removes the handler.
+pub const NO_FAULT_HANDLER: u64 = u64::MAX;
+
+/// Notification argument of `thread_set_exit_notification` that removes it.
+pub const NO_EXIT_NOTIFICATION: u64 = u64::MAX;
+
+/// Deletes the caps in an exiting task's root CNode, unless another task
+/// still uses the same CNode as its root. Nested CNodes are emptied as
+/// their last cap goes.
+fn release_root_cnode(task: &Task) {
+    let root = task.tcb.cnode();
+    let mut shared = false;
+    for_each_task(|other| shared |= Arc::ptr_eq(&other.tcb.cnode(), &root));
+    if shared {
+        return;
+    }
+
+    release_cnode_caps(&root);
+}
+
+fn signal_exit(task: &Task) {
+    let id = match *task.tcb.exit_notification.lock() {
+        Some(id) => id,
+        None     => return,
+    };
+
+    let result = IPC_MANAGER.lock().signal_notification(id, badges::PROC_EXIT);
+    let IpcResult::WakeReceiver { receiver } = result else { return };
+    if let Some(receiver) = get_task_by_index(receiver) {
+        awaken_task(receiver);
+    }
+}
+
+/// Tears down the current thread and never returns. The task leaves the task
+/// table and every IPC queue, its root CNode is emptied and its exit
+/// notification signalled. The address space and kernel stack go with the
+/// last reference, which the idle task drops once the CPU has left them.
+pub(crate) fn exit_thread(task_id: TaskIdIndex) -> ! {
+    if let Some(task) = get_task_by_index(task_id) {
+        task.tcb.task_state.store(TaskState::Exiting, core::sync::atomic::Ordering::Release);
+        remove_task(task_id);
+
+        let orphan = IPC_MANAGER.lock().remove_task(task_id);
+        if let Some(caller) = orphan.and_then(get_task_by_index) {
+            awaken_task(caller);
+        }
+
+        release_root_cnode(&task);
+        signal_exit(&task);
+    }
+
+    exit_current_task()
+}
+
+/// Ends the calling thread. The exit code is not kept anywhere yet.
+pub (crate) fn thread_exit(curr_task_id: TaskIdIndex, _code: u64) -> ! {
+    exit_thread(curr_task_id)
 }
 
 pub (crate) fn thread_sleep(ns: u64) -> u64 {
     sleep(ns);
 
     0
-}
\ No newline at end of file
+}
+
+/// Sends the faults of the thread behind `tcb_cap_idx` to the endpoint of
+/// `ep_cap_idx`, which needs WRITE like any send.
+pub(crate) fn thread_set_fault_handler(curr_task_id: TaskIdIndex, tcb_cap_idx: u64, ep_cap_idx: u64) -> IpcSyscallRetCodes {
+    let task = match get_task_by_index(curr_task_id) {
+        Some(t) => t,
+        None => return IpcSyscallRetCodes::IpcInvalidCap,
+    };
+
+    let target = match resolve_thread_cap(&task, tcb_cap_idx).map(get_task_by_index) {
+        Ok(Some(t)) => t,
+        Ok(None) => return IpcSyscallRetCodes::IpcInvalidCap,
+        Err(e) => return e,
+    };
+
+    let handler = if ep_cap_idx == NO_FAULT_HANDLER {
+        None
+    } else {
+        match resolve_endpoint_cap(&task, ep_cap_idx as CapIdx, Rights::WRITE) {
+            Ok((ep, badge)) => Some(FaultHandler { ep, badge }),
+            Err(e) => return e,
+        }
+    };
+
+    *target.tcb.fault_handler.lock() = handler;
+    IpcSyscallRetCodes::IpcOk
+}
+
+/// Signals the notification of `ntfn_cap_idx` with `PROC_EXIT` once the
+/// thread behind `tcb_cap_idx` exits or is killed.
+pub(crate) fn thread_set_exit_notification(curr_task_id: TaskIdIndex, tcb_cap_idx: u64, ntfn_cap_idx: u64) -> IpcSyscallRetCodes {
+    let task = match get_task_by_index(curr_task_id) {
+        Some(t) => t,
+        None => return IpcSyscallRetCodes::IpcInvalidCap,
+    };
+
+    let target = match resolve_thread_cap(&task, tcb_cap_idx).map(get_task_by_index) {
+        Ok(Some(t)) => t,
+        Ok(None) => return IpcSyscallRetCodes::IpcInvalidCap,
+        Err(e) => return e,
+    };
+
+    let ntfn = if ntfn_cap_idx == NO_EXIT_NOTIFICATION {
+        None
+    } else {
+        match resolve_notification_cap(&task, ntfn_cap_idx, Rights::WRITE) {
+            Ok(id) => Some(id),
+            Err(e) => return e,
+        }
+    };
+
+    *target.tcb.exit_notification.lock() = ntfn;
+    IpcSyscallRetCodes::IpcOk
+}
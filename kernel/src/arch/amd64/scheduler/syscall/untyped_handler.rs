@@ -0,0 +1,233 @@
+use alloc::sync::Arc;
+use x86_64::PhysAddr;
+
+use crate::arch::amd64::{
+    ipc::{
+        IPC_MANAGER,
+        cnode::{CNode, CapIdx, MAX_CNODE_RADIX_BITS},
+        mdb::MDB,
+        message::{Capability, Rights},
+        object_table::{HandleRef, KernelObjType, KernelObject, ObjData, obj_insert, obj_release, with_object_mut},
+    },
+    memory::{
+        misc::{align_up, phys_to_virt},
+        pmm::pages_allocator::{get_pages, page_refcount, put_pages},
+        vmm::PAGE_SIZE,
+    },
+    scheduler::{
+        PerCpuSchedulerData,
+        exec_loader::{make_inactive_task, make_user_vspace},
+        sched_context::SchedContext,
+        stack::DEFAULT_KERNEL_STACK_SIZE,
+        syscall::{cap_check::{CapError, resolve_cap}, cnode_handler::destroy_object},
+        task_storage::{alloc_task_index, get_task_by_index, register_task, remove_task},
+    },
+};
+
+pub(crate) enum UntypedSyscallNumbers {
+    UntypedRetype = 0x80,
+}
+
+/// Bytes charged for objects that live on the kernel heap rather than in
+/// the untyped block itself.
+const ENDPOINT_BYTES:     usize = 1 << 8;
+const NOTIFICATION_BYTES: usize = 1 << 6;
+const THREAD_BYTES:       usize = DEFAULT_KERNEL_STACK_SIZE + PAGE_SIZE;
+const VSPACE_BYTES:       usize = PAGE_SIZE;
+const SCHED_CONTEXT_BYTES: usize = 1 << 7;
+
+/// Size of the object `retype` makes, always a power of two so objects can
+/// be aligned to it. For frames `size_arg` is a byte count, for CNodes the
+/// radix in bits.
+fn object_size(obj_type: KernelObjType, size_arg: u64) -> Result<usize, CapError> {
+    match obj_type {
+        KernelObjType::Frame => {
+            let pages = usize::try_from(size_arg).map_err(|_| CapError::InvalidArgument)?
+                .div_ceil(PAGE_SIZE)
+                .max(1);
+            pages.checked_next_power_of_two()
+                .map(|p| p * PAGE_SIZE)
+                .ok_or(CapError::InvalidArgument)
+        }
+        KernelObjType::CNode => {
+            if size_arg > MAX_CNODE_RADIX_BITS as u64 {
+                return Err(CapError::InvalidArgument);
+            }
+            Ok(CNode::storage_bytes(size_arg as u8).next_power_of_two())
+        }
+        KernelObjType::Endpoint     => Ok(ENDPOINT_BYTES),
+        KernelObjType::Notification => Ok(NOTIFICATION_BYTES),
+        KernelObjType::Thread       => Ok(THREAD_BYTES.next_power_of_two()),
+        KernelObjType::VSpace       => Ok(VSPACE_BYTES),
+        KernelObjType::SchedContext => Ok(SCHED_CONTEXT_BYTES),
+        _ => Err(CapError::WrongType),
+    }
+}
+
+fn obj_type_from_raw(raw: u64) -> Result<KernelObjType, CapError> {
+    match raw {
+        x if x == KernelObjType::VSpace as u64       => Ok(KernelObjType::VSpace),
+        x if x == KernelObjType::Endpoint as u64     => Ok(KernelObjType::Endpoint),
+        x if x == KernelObjType::Frame as u64        => Ok(KernelObjType::Frame),
+        x if x == KernelObjType::Thread as u64       => Ok(KernelObjType::Thread),
+        x if x == KernelObjType::CNode as u64        => Ok(KernelObjType::CNode),
+        x if x == KernelObjType::Notification as u64 => Ok(KernelObjType::Notification),
+        x if x == KernelObjType::SchedContext as u64 => Ok(KernelObjType::SchedContext),
+        _ => Err(CapError::WrongType),
+    }
+}
+
+/// Takes `size` bytes, aligned to `size`, from the untyped's free space and
+/// returns their physical address together with a page reference on the
+/// untyped's block for the new object. Once only the untyped's own
+/// reference is left, everything carved before is gone, and the space is
+/// handed out again from the start.
+fn carve(untyped: &mut ObjData, size: usize) -> Result<PhysAddr, CapError> {
+    match untyped {
+        ObjData::Untyped { phys, pages, used } => {
+            if page_refcount(*phys) == 1 {
+                *used = 0;
+            }
+            let offset = align_up(*used, size);
+            if offset + size > *pages * PAGE_SIZE {
+                return Err(CapError::OutOfMemory);
+            }
+            *used = offset + size;
+            get_pages(*phys);
+            Ok(*phys + offset as u64)
+        }
+        _ => Err(CapError::WrongType),
+    }
+}
+
+/// Carves one zeroed page for a demand-paged VMA charged to `untyped`. The
+/// page holds its reference on the untyped's block until it is unmapped.
+pub(crate) fn carve_page(untyped: HandleRef) -> Option<PhysAddr> {
+    let base = with_object_mut(untyped, |obj| carve(&mut obj.data, PAGE_SIZE).ok()).flatten()?;
+    unsafe {
+        core::ptr::write_bytes(phys_to_virt(base.as_u64() as usize) as *mut u8, 0, PAGE_SIZE);
+    }
+    Some(base)
+}
+
+/// Builds the object data for a freshly carved object of `obj_type`. Frames
+/// and CNodes live in the carved memory and take over the page reference
+/// `carve` took; other objects are charged to the block instead.
+fn make_object(obj_type: KernelObjType, base: PhysAddr, size: usize, size_arg: u64) -> Result<ObjData, CapError> {
+    match obj_type {
+        KernelObjType::Frame => {
+            unsafe {
+                core::ptr::write_bytes(phys_to_virt(base.as_u64() as usize) as *mut u8, 0, size);
+            }
+            Ok(ObjData::Frame { phys: base, pages: size / PAGE_SIZE })
+        }
+        KernelObjType::CNode => {
+            let cnode = CNode::from_untyped(base, size_arg as u8, 0, 0).ok_or(CapError::InvalidArgument)?;
+            Ok(ObjData::CNode(cnode.into_ref()))
+        }
+        KernelObjType::Endpoint => {
+            let id = IPC_MANAGER.lock().create_endpoint().ok_or(CapError::OutOfMemory)?;
+            Ok(ObjData::Endpoint(id.0 as u32))
+        }
+        KernelObjType::Notification => {
+            let id = IPC_MANAGER.lock().create_notification().ok_or(CapError::OutOfMemory)?;
+            Ok(ObjData::Notification(id))
+        }
+        KernelObjType::Thread => {
+            let task_id = alloc_task_index();
+            register_task(Arc::new(make_inactive_task(task_id)));
+            Ok(ObjData::Thread(task_id))
+        }
+        KernelObjType::VSpace => Ok(ObjData::VSpace(make_user_vspace())),
+        KernelObjType::SchedContext => Ok(ObjData::SchedContext(Arc::new(SchedContext::new()))),
+        _ => Err(CapError::WrongType),
+    }
+}
+
+/// Undoes `make_object` for an object that never got a cap.
+fn discard_object(obj: KernelObject) {
+    if let ObjData::Thread(task_id) = obj.data {
+        remove_task(task_id);
+    }
+    destroy_object(obj);
+}
+
+/// Carves one object of `obj_type` out of the untyped at `untyped_cap_idx`
+/// and installs a cap for it, a child of the untyped cap, in the caller's
+/// root CNode.
+pub(super) fn retype(untyped_cap_idx: u64, obj_type: KernelObjType, size_arg: u64) -> Result<CapIdx, CapError> {
+    retype_with(untyped_cap_idx, obj_type, size_arg, |base, size| make_object(obj_type, base, size, size_arg))
+}
+
+/// Like `retype` for a CNode, but one resolved behind a guard.
+pub(super) fn retype_cnode(untyped_cap_idx: u64, radix_bits: u64, guard_bits: u64, guard: u64) -> Result<CapIdx, CapError> {
+    let guard_bits = u8::try_from(guard_bits).map_err(|_| CapError::InvalidArgument)?;
+
+    retype_with(untyped_cap_idx, KernelObjType::CNode, radix_bits, |base, _| {
+        let cnode = CNode::from_untyped(base, radix_bits as u8, guard_bits, guard).ok_or(CapError::InvalidArgument)?;
+        Ok(ObjData::CNode(cnode.into_ref()))
+    })
+}
+
+fn retype_with(
+    untyped_cap_idx: u64,
+    obj_type: KernelObjType,
+    size_arg: u64,
+    make: impl FnOnce(PhysAddr, usize) -> Result<ObjData, CapError>,
+) -> Result<CapIdx, CapError> {
+    let curr_task_id = PerCpuSchedulerData::get().curr_task_id.id();
+    let task = get_task_by_index(curr_task_id).ok_or(CapError::InvalidIdx)?;
+
+    let untyped = resolve_cap(&task, untyped_cap_idx, KernelObjType::Untyped, Rights::WRITE)?;
+    let size = object_size(obj_type, size_arg)?;
+
+    let (block, base) = with_object_mut(untyped.handle, |obj| {
+        let block = match obj.data {
+            ObjData::Untyped { phys, .. } => phys,
+            _ => return Err(CapError::WrongType),
+        };
+        carve(&mut obj.data, size).map(|base| (block, base))
+    })
+    .ok_or(CapError::InvalidIdx)??;
+
+    let data = match make(base, size) {
+        Ok(data) => data,
+        Err(e) => {
+            put_pages(block);
+            return Err(e);
+        }
+    };
+    let obj = match obj_type {
+        KernelObjType::Frame | KernelObjType::CNode => KernelObject::new(obj_type, data),
+        _ => KernelObject::new(obj_type, data).charged_to(block),
+    };
+    let handle = match obj_insert(obj) {
+        Ok(h)    => h,
+        Err(obj) => {
+            discard_object(obj);
+            return Err(CapError::SlotOccupied);
+        }
+    };
+
+    let root = task.tcb.cnode();
+
+    let installed = MDB.lock()
+        .install(&mut root.lock(), &root, untyped.mdb, Capability::new(handle, Rights::ALL));
+
+    installed.ok_or_else(|| {
+        if let Some(obj) = obj_release(handle) {
+            discard_object(obj);
+        }
+        CapError::SlotOccupied
+    })
+}
+
+/// Retypes part of the untyped at `untyped_cap_idx` into an object of
+/// `raw_type`. Returns the slot index or a `CapError` code. The untyped's
+/// space is reused once every object carved from it is gone.
+pub(crate) fn untyped_retype(untyped_cap_idx: u64, raw_type: u64, size_arg: u64) -> u64 {
+    match obj_type_from_raw(raw_type).and_then(|obj_type| retype(untyped_cap_idx, obj_type, size_arg)) {
+        Ok(idx) => idx as u64,
+        Err(e)  => e.as_syscall_err(),
+    }
+}
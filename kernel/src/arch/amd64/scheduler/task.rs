@@ -1,10 +1,25 @@
-use core::{cell::UnsafeCell, sync::atomic::AtomicU64};
+use core::{cell::UnsafeCell, sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering}};
 
 use atomic_enum::atomic_enum;
 use spin::Mutex;
-use crate::arch::amd64::{ipc::cnode::CNode, scheduler::{addr_space::AddrSpace, stack::KernelStack}};
+use x86_64::VirtAddr;
+use crate::arch::amd64::{cpu::frames::InterruptFrame, ipc::{cnode::CNodeRef, message::FastMessage, notification::NotificationId}, scheduler::{addr_space::VSpaceRef, fault::FaultHandler, sched_context::SchedContextRef, stack::{KernelStack, deallocate_kernel_stack}}};
 
-pub type TaskIdIndex = u32;
+/// Slot in the task table plus the generation of the task holding it, the
+/// way `HandleRef` names kernel objects. Removing a task moves its slot to
+/// the next generation, so an id kept in a cap or a queued message stops
+/// matching rather than naming whichever task reuses the slot.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct TaskIdIndex {
+    pub slot: u32,
+    pub generation: u32,
+}
+
+impl core::fmt::Display for TaskIdIndex {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}", self.slot, self.generation)
+    }
+}
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub struct TaskId {
@@ -21,6 +36,16 @@ impl TaskId {
     }
 }
 
+/// Number of scheduling priorities. Higher values run first.
+pub const NUM_PRIORITIES: usize = 256;
+pub const MAX_PRIORITY: u8 = (NUM_PRIORITIES - 1) as u8;
+pub const DEFAULT_PRIORITY: u8 = 128;
+
+pub const NO_CPU: usize = usize::MAX;
+
+/// Nanoseconds a thread may run before others of its priority get a turn.
+pub const TIME_SLICE_NS: u64 = 5_000_000;
+
 #[atomic_enum]
 #[repr(u8)]
 pub enum TaskState {
@@ -28,6 +53,22 @@ pub enum TaskState {
     Ready = 1,
     Exiting = 2,
     Sleep = 3,
+    /// Not started yet or suspended; never picked to run.
+    Inactive = 4,
+    /// Used up its scheduling context's budget, or has no context to run
+    /// on; woken when it is refilled or a context is bound.
+    Throttled = 5,
+}
+
+/// How the thread last came in from ring 3, which decides the layout of the
+/// user frame at the top of its kernel stack.
+#[atomic_enum]
+#[repr(u8)]
+pub enum KernelEntry {
+    /// `TaskRegisters`, pushed by the syscall entry.
+    Syscall = 0,
+    /// `InterruptFrame`, pushed by an exception or IRQ.
+    Interrupt = 1,
 }
 
 pub struct Task {
@@ -37,15 +78,99 @@ pub struct Task {
 }
 
 pub struct Tcb {
-    pub wake_at_tick: Mutex<AtomicU64>,
-    pub addr_space: Mutex<AddrSpace>,
+    /// Deadline of a pending wakeup in nanoseconds of `now_ns`, 0 if none.
+    pub wake_at: Mutex<AtomicU64>,
+    /// CPU whose timer queue holds that wakeup. Changes under `wake_at`.
+    pub wake_cpu: AtomicUsize,
+    /// Address space the thread runs in; `tcb_configure` may replace it.
+    pub addr_space: Mutex<VSpaceRef>,
     pub kernel_stack: KernelStack,
-    pub cnode: Mutex<CNode>,
+    /// Root of the thread's cap space; `tcb_configure` may replace it.
+    pub cnode: Mutex<CNodeRef>,
     pub task_state: AtomicTaskState,
+    /// Message of a send or call waiting in an endpoint's send queue.
+    pub parked_msg: Mutex<Option<FastMessage>>,
+    /// Notification whose signals also end a receive on any endpoint.
+    pub bound_notification: Mutex<Option<NotificationId>>,
+    /// Page registered with `IpcSetBuffer` for words beyond the registers.
+    pub ipc_buffer: Mutex<Option<VirtAddr>>,
+    /// Endpoint told about faults the kernel cannot resolve itself.
+    pub fault_handler: Mutex<Option<FaultHandler>>,
+    /// Notification signalled with `PROC_EXIT` when the thread exits.
+    pub exit_notification: Mutex<Option<NotificationId>>,
+    pub kernel_entry: AtomicKernelEntry,
+    pub priority: AtomicU8,
+    /// Highest priority the thread may hand to itself or other threads.
+    pub max_priority: AtomicU8,
+    /// Nanoseconds left of the current time slice.
+    pub time_slice: AtomicU64,
+    /// CPU the thread last ran on, `NO_CPU` before it first runs. Wakeups
+    /// queue it there.
+    pub last_cpu: AtomicUsize,
+    /// Set while a CPU runs on the thread's kernel stack, cleared by
+    /// `switch_to_task` once it has left it. A thread is only picked to run,
+    /// or treated as stopped, once this is clear.
+    pub on_cpu: AtomicBool,
+    /// Scheduling context bound to the thread, if it is metered.
+    pub sched_context: Mutex<Option<SchedContextRef>>,
+    /// Context lent by a caller while the thread serves its call.
+    pub donated_sc: Mutex<Option<SchedContextRef>>,
+}
+
+impl Tcb {
+    pub fn addr_space(&self) -> VSpaceRef {
+        self.addr_space.lock().clone()
+    }
+
+    pub fn cnode(&self) -> CNodeRef {
+        self.cnode.lock().clone()
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority.load(Ordering::Relaxed)
+    }
+
+    /// Context the thread's running time is charged to.
+    pub fn active_sched_context(&self) -> Option<SchedContextRef> {
+        self.donated_sc.lock().clone().or_else(|| self.sched_context.lock().clone())
+    }
+
+    /// Lets a thread without a context of its own serve a call from
+    /// `caller` on the caller's context.
+    pub fn borrow_sched_context(&self, caller: &Tcb) {
+        if self.sched_context.lock().is_none() {
+            *self.donated_sc.lock() = caller.active_sched_context();
+        }
+    }
+
+    /// Drops the context lent for a call. Returns whether there was one.
+    pub fn return_sched_context(&self) -> bool {
+        self.donated_sc.lock().take().is_some()
+    }
+}
+
+impl Drop for Tcb {
+    fn drop(&mut self) {
+        deallocate_kernel_stack(core::mem::replace(&mut self.kernel_stack, KernelStack::empty()));
+    }
 }
 
 unsafe impl Sync for Task {}
 
+impl Task {
+    /// User registers saved by the syscall entry at the top of the kernel
+    /// stack. Only meaningful while `kernel_entry` is `Syscall`.
+    pub fn syscall_frame(&self) -> *mut TaskRegisters {
+        (self.tcb.kernel_stack.top.as_u64() as usize - core::mem::size_of::<TaskRegisters>()) as *mut TaskRegisters
+    }
+
+    /// Frame pushed when an exception or IRQ interrupted ring 3. Only
+    /// meaningful while `kernel_entry` is `Interrupt`.
+    pub fn interrupt_frame(&self) -> *mut InterruptFrame {
+        (self.tcb.kernel_stack.top.as_u64() as usize - core::mem::size_of::<InterruptFrame>()) as *mut InterruptFrame
+    }
+}
+
 #[derive(Debug, Default)]
 #[repr(packed)]
 #[allow(dead_code)]
@@ -1,4 +1,4 @@
-use alloc::{collections::{VecDeque, btree_map::BTreeMap}, sync::Arc};
+use alloc::{collections::{VecDeque, btree_map::BTreeMap}, sync::Arc, vec::Vec};
 use spin::{Mutex, Once};
 use crate::{arch::amd64::scheduler::task::{Task, TaskId, TaskIdIndex}, early_println};
 
@@ -19,8 +19,8 @@ impl TaskTable {
         self.tasks.lock().get(&idx).cloned()
     }
 
-    pub fn remove(&self, idx: TaskIdIndex) {
-        self.tasks.lock().remove(&idx);
+    pub fn remove(&self, idx: TaskIdIndex) -> Option<Arc<Task>> {
+        self.tasks.lock().remove(&idx)
     }
 }
 
@@ -46,6 +46,39 @@ impl GlobalRunQueue {
     }
 }
 
+/// Hands out task ids. Freed slots are reused under their next generation.
+struct TaskIdAllocator {
+    generations: Vec<u32>,
+    free:        Vec<u32>,
+}
+
+impl TaskIdAllocator {
+    const fn new() -> Self {
+        Self { generations: Vec::new(), free: Vec::new() }
+    }
+
+    fn alloc(&mut self) -> TaskIdIndex {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                self.generations.push(0);
+                (self.generations.len() - 1) as u32
+            }
+        };
+        TaskIdIndex { slot, generation: self.generations[slot as usize] }
+    }
+
+    fn free(&mut self, id: TaskIdIndex) {
+        let Some(generation) = self.generations.get_mut(id.slot as usize) else { return };
+        if *generation != id.generation {
+            return;
+        }
+        *generation = generation.wrapping_add(1);
+        self.free.push(id.slot);
+    }
+}
+
+static TASK_IDS:          Mutex<TaskIdAllocator> = Mutex::new(TaskIdAllocator::new());
 static TASK_TABLE:        Once<TaskTable>      = Once::new();
 static GLOBAL_RUN_QUEUE:  Once<GlobalRunQueue> = Once::new();
 
@@ -64,12 +97,25 @@ pub fn add_task_to_execute(task: Arc<Task>) -> TaskId {
     id
 }
 
+pub fn alloc_task_index() -> TaskIdIndex {
+    TASK_IDS.lock().alloc()
+}
+
+/// Makes `task` known by its index without queueing it to run.
+pub fn register_task(task: Arc<Task>) {
+    table().insert(task);
+}
+
 pub fn get_task_by_index(idx: TaskIdIndex) -> Option<Arc<Task>> {
     table().get_by_index(idx)
 }
 
+/// Drops `idx` from the task table and retires the id, so that nothing
+/// still holding it reaches the next task in the slot.
 pub fn remove_task(idx: TaskIdIndex) {
-    table().remove(idx);
+    if table().remove(idx).is_some() {
+        TASK_IDS.lock().free(idx);
+    }
 }
 
 pub fn inject_sleeping_task(idx: TaskIdIndex) {
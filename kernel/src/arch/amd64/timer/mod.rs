@@ -40,6 +40,13 @@ pub fn get_hpet() -> &'static RwLock<HPET>{
         .expect("HPET not inited yet")
 }
 
+/// Nanoseconds since the HPET was started. This is the monotonic clock
+/// timeouts and scheduling budgets are measured against.
+pub fn now_ns() -> u64 {
+    let hpet = get_hpet().read();
+    (hpet.read_counter() as u128 * hpet.period_fs() as u128 / 1_000_000) as u64
+}
+
 pub struct HPET {
     regs: NonNull<HpetRegisters>,
     /// femtoseconds per tick (from capabilities bits 63:32)
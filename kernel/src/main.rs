@@ -10,7 +10,7 @@ use alloc::sync::Arc;
 
 use crate::arch::amd64::cpu::smp::startup::init_bsp_core_smp;
 use crate::arch::amd64::scheduler::exec_loader::make_init_task;
-use crate::arch::amd64::scheduler::task_storage::add_task_to_execute;
+use crate::arch::amd64::scheduler::task_storage::{add_task_to_execute, alloc_task_index};
 use crate::arch::{arch_init, hlt_loop};
 use crate::bootinfo::BootInfo;
 use crate::cpio_parser::cpio_find;
@@ -74,8 +74,8 @@ unsafe extern "C" fn kmain() -> ! {
 
     let init_srvs = BootInfo::get_init_srvs().expect("No init pack of services found!");
     let cpio_ptr = ptr::addr_of!(init_srvs);
-    if let Some(data) = cpio_find(init_srvs, "server.bin") {
-        let init = make_init_task(data, 1, cpio_ptr as u64).unwrap();
+    if let Some(data) = cpio_find(init_srvs, "server.elf") {
+        let init = make_init_task(data, alloc_task_index(), cpio_ptr as u64).unwrap();
         add_task_to_execute(Arc::new(init));
         early_println!("Init service loaded!");
     } else {
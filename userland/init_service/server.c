@@ -6,6 +6,8 @@ typedef struct {
     uint64_t self_cnode_cap;
 
     uint64_t cpio_addr;
+    uint64_t untyped_cap;
+    uint64_t sched_context_cap;
 } BootInfo_t;
 
 void kill_sleep() {
@@ -20,11 +22,11 @@ void _start(BootInfo_t* boot_info) {
 
     printf("Got initial boot info. TCB CAP: %d | vspace CAP: %d | cnode CAP: %d\n", boot_info->self_tcb_cap, boot_info->self_vspace_cap, boot_info->self_cnode_cap);
 
-    uint64_t addr = alloc_frame();
+    uint64_t frame_cap = alloc_frame(boot_info->untyped_cap);
 
-    printf("Server: allocated frame. Addr: 0x%x\n", addr);
+    printf("Server: allocated frame. CAP: %d\n", frame_cap);
 
-    ret = vma_map(boot_info->self_vspace_cap, 0x5000, 4096, MAP_READ | MAP_WRITE | MAP_USER);
+    ret = vma_map_frame(boot_info->self_vspace_cap, frame_cap, 0x5000, 4096, MAP_READ | MAP_WRITE | MAP_USER);
 
     if (ret != 0) {
         printf("Server: invalid capability provided for map!\n");
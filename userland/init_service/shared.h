@@ -17,19 +17,99 @@ typedef __uint64_t uint64_t;
 #define SYS_IPC_RECV       0x61
 #define SYS_IPC_CALL       0x62
 #define SYS_IPC_REPLY      0x63
+#define SYS_IPC_REPLY_RECV 0x66
+#define SYS_IPC_SET_BUFFER 0x67
 #define SYS_PRINT          0x10
 
+#define SYS_NOTIFY_CREATE  0x70
+#define SYS_NOTIFY_SIGNAL  0x71
+#define SYS_NOTIFY_WAIT    0x72
+#define SYS_NOTIFY_POLL    0x73
+#define SYS_NOTIFY_BIND    0x74
+#define SYS_NOTIFY_UNBIND  0x75
+
+#define MSG_LABEL_NOTIFY   3
+#define MSG_LABEL_CALL     4
+#define MSG_LABEL_SEND     5
+#define MSG_LABEL_FAULT    6
+
 #define SYS_ALLOC_FRAME 0x2
 #define SYS_VMA_MAP     0x3
 #define SYS_VMA_UNMAP   0x4
 #define SYS_MPROTECT    0x5
+#define SYS_SHM_CREATE  0x6
+#define SYS_SHM_MAP     0x7
 
 #define MAP_READ  (1 << 0)
 #define MAP_WRITE (1 << 1)
 #define MAP_EXEC  (1 << 2)
 #define MAP_USER  (1 << 3)
 
-#define SYS_THREAD_SLEEP 0x99
+#define SYS_THREAD_SLEEP             0x99
+#define SYS_THREAD_EXIT              0x11
+#define SYS_THREAD_SET_FAULT_HANDLER 0x12
+#define SYS_THREAD_SET_EXIT_NOTIFICATION 0x13
+
+/* Badge raised on a thread's exit notification when it exits or is killed. */
+#define BADGE_PROC_EXIT (1 << 3)
+#define NO_EXIT_NOTIFICATION ((uint64_t)-1)
+
+/* Fault messages carry { vector, addr, error, rip } in data[0..4]; addr is
+ * only set for page faults. */
+#define FAULT_RESUME 0
+#define FAULT_KILL   1
+#define NO_FAULT_HANDLER ((uint64_t)-1)
+
+#define SYS_TCB_CONFIGURE 0x30
+#define SYS_TCB_SET_REGS  0x31
+#define SYS_TCB_READ_REGS 0x32
+#define SYS_TCB_RESUME    0x33
+#define SYS_TCB_SUSPEND   0x34
+#define SYS_TCB_LOAD_IMAGE 0x35
+#define SYS_TCB_SET_PRIORITY 0x36
+
+#define SYS_SCHED_CONFIGURE 0x40
+#define SYS_SCHED_BIND      0x41
+#define SYS_SCHED_UNBIND    0x42
+
+#define SYS_CNODE_COPY   0x20
+#define SYS_CNODE_MOVE   0x21
+#define SYS_CNODE_DELETE 0x22
+#define SYS_CNODE_REVOKE 0x23
+#define SYS_CNODE_CREATE 0x24
+#define SYS_CNODE_MINT   0x25
+
+#define SYS_UNTYPED_RETYPE 0x80
+
+#define OBJ_VSPACE       0
+#define OBJ_ENDPOINT     1
+#define OBJ_FRAME        2
+#define OBJ_THREAD       3
+#define OBJ_CNODE        5
+#define OBJ_NOTIFICATION 6
+#define OBJ_SCHED_CONTEXT 8
+
+#define RIGHT_READ  (1 << 0)
+#define RIGHT_WRITE (1 << 1)
+#define RIGHT_EXEC  (1 << 2)
+#define RIGHT_GRANT (1 << 3)
+#define RIGHT_ALL   0xF
+
+#define MAX_CAPS_PER_MSG 4
+#define MSG_INFO(n_caps) ((uint64_t)(n_caps) & 0x7)
+#define MSG_NONBLOCK     (1 << 3)
+#define MSG_EXTRA_WORDS  64
+#define MSG_EXTRA(n)     (((uint64_t)(n) & 0x7f) << 4)
+#define MSG_DROPPED_CAPS(info) (((uint64_t)(info) >> 11) & 0x7)
+
+#define IPC_OK        0
+#define IPC_NO_REPLY  15
+#define IPC_TIMEOUT   16
+#define IPC_NOT_READY 17
+#define IPC_NO_BUFFER 18
+#define IPC_MSG_TOO_LONG 19
+/* recv while the last call received is still unanswered */
+#define IPC_REPLY_PENDING 20
 
 typedef struct {
     uint64_t ep_id;
@@ -103,54 +183,154 @@ static inline uint64_t syscall4(uint64_t number, uint64_t arg1, uint64_t arg2,
     return ret;
 }
 
+static inline uint64_t syscall5_caps(uint64_t number, uint64_t arg1, uint64_t arg2,
+                                     uint64_t arg3, uint64_t arg4, uint64_t arg5,
+                                     uint64_t n_caps, const uint64_t *caps) {
+    uint64_t ret;
+    register uint64_t r10 asm("r10") = arg4;
+    register uint64_t r8  asm("r8")  = arg5;
+    register uint64_t r9  asm("r9")  = MSG_INFO(n_caps);
+    register uint64_t r12 asm("r12") = n_caps > 0 ? caps[0] : 0;
+    register uint64_t r13 asm("r13") = n_caps > 1 ? caps[1] : 0;
+    register uint64_t r14 asm("r14") = n_caps > 2 ? caps[2] : 0;
+    register uint64_t r15 asm("r15") = n_caps > 3 ? caps[3] : 0;
+    __asm__ volatile (
+        "syscall"
+        : "=a"(ret), "+r"(r9), "+r"(r12), "+r"(r13), "+r"(r14), "+r"(r15)
+        : "a"(number), "D"(arg1), "S"(arg2), "d"(arg3), "r"(r10), "r"(r8)
+        : "rcx", "r11", "memory"
+    );
+    return ret;
+}
+
 static inline uint64_t syscall5(uint64_t number, uint64_t arg1, uint64_t arg2,
                                 uint64_t arg3, uint64_t arg4, uint64_t arg5) {
+    return syscall5_caps(number, arg1, arg2, arg3, arg4, arg5, 0, 0);
+}
+
+static inline uint64_t syscall6(uint64_t number, uint64_t arg1, uint64_t arg2,
+                                uint64_t arg3, uint64_t arg4, uint64_t arg5,
+                                uint64_t arg6) {
     uint64_t ret;
     register uint64_t r10 asm("r10") = arg4;
     register uint64_t r8  asm("r8")  = arg5;
+    register uint64_t r9  asm("r9")  = arg6;
     __asm__ volatile (
         "syscall"
-        : "=a"(ret)
+        : "=a"(ret), "+r"(r9)
         : "a"(number), "D"(arg1), "S"(arg2), "d"(arg3), "r"(r10), "r"(r8)
-        : "rcx", "r11", "r9",
-          "r12", "r13", "r14", "r15", "memory"
+        : "rcx", "r11", "r12", "r13", "r14", "r15", "memory"
     );
     return ret;
 }
 
 typedef struct {
     uint64_t label;
+    uint64_t badge;
     uint64_t data[4];
+    uint64_t n_caps;
+    uint64_t caps[MAX_CAPS_PER_MSG];
+    uint64_t n_extra;
+    /* Caps the sender attached that did not arrive: the receiving cnode was
+     * full or the cap was deleted or revoked while in flight. */
+    uint64_t n_dropped_caps;
 } ipc_msg_t;
 
-static inline uint64_t ipc_recv_msg(uint64_t ep_id, ipc_msg_t *out) {
+/*
+ * Layout of the page registered with ipc_set_buffer. Outgoing words past
+ * the four in registers are read from `words`; a received message leaves
+ * its extra words there and its installed cap slots in `caps`.
+ */
+typedef struct {
+    uint64_t words[MSG_EXTRA_WORDS];
+    uint64_t caps[MAX_CAPS_PER_MSG];
+} ipc_buffer_t;
+
+/* Register state of a faulting thread, delivered as the extra words of a
+ * MSG_LABEL_FAULT message when the handler has an IPC buffer. */
+typedef struct {
+    uint64_t ds;
+    uint64_t r15, r14, r13, r12, r11, r10, r9, r8;
+    uint64_t rbp, rdi, rsi, rdx, rcx, rbx, rax;
+    uint64_t vector, error;
+    uint64_t rip, cs, rflags, rsp, ss;
+} fault_frame_t;
+
+/* User registers exchanged by tcb_read_regs and tcb_set_regs through the
+ * caller's IPC buffer. */
+typedef struct {
+    uint64_t rip, rsp, rflags;
+    uint64_t rax, rbx, rcx, rdx, rsi, rdi, rbp;
+    uint64_t r8, r9, r10, r11, r12, r13, r14, r15;
+} tcb_regs_t;
+
+/*
+ * Issues an IPC syscall that sends four words and may return a message.
+ * `flags` goes into the message info word (MSG_NONBLOCK, MSG_EXTRA),
+ * `timeout_ns` (0 = forever) bounds how long recv and call wait.
+ */
+static inline uint64_t ipc_syscall_msg(uint64_t number, uint64_t ep_id,
+                                       uint64_t m0, uint64_t m1,
+                                       uint64_t m2, uint64_t m3,
+                                       uint64_t flags, uint64_t timeout_ns,
+                                       ipc_msg_t *out) {
     uint64_t ret;
     register uint64_t rdi asm("rdi") = ep_id;
-    register uint64_t rsi asm("rsi");
-    register uint64_t rdx asm("rdx");
-    register uint64_t r10 asm("r10");
-    register uint64_t r8  asm("r8");
+    register uint64_t rsi asm("rsi") = m0;
+    register uint64_t rdx asm("rdx") = m1;
+    register uint64_t r10 asm("r10") = m2;
+    register uint64_t r8  asm("r8")  = m3;
+    register uint64_t r9  asm("r9")  = flags;
+    register uint64_t rbx asm("rbx") = timeout_ns;
+    register uint64_t r12 asm("r12");
+    register uint64_t r13 asm("r13");
+    register uint64_t r14 asm("r14");
+    register uint64_t r15 asm("r15");
 
     __asm__ volatile (
         "syscall"
-        : "=a"(ret), "+r"(rdi), "=r"(rsi), "=r"(rdx), "=r"(r10), "=r"(r8)
-        : "a"((uint64_t)SYS_IPC_RECV)
-        : "rcx", "r11", "r9", "r12", "r13", "r14", "r15", "memory"
+        : "=a"(ret), "+r"(rdi), "+r"(rsi), "+r"(rdx), "+r"(r10), "+r"(r8),
+          "+r"(r9), "+r"(rbx), "=r"(r12), "=r"(r13), "=r"(r14), "=r"(r15)
+        : "a"(number)
+        : "rcx", "r11", "memory"
     );
 
     if (out) {
         out->label   = rdi;
+        out->badge   = rbx;
         out->data[0] = rsi;
         out->data[1] = rdx;
         out->data[2] = r10;
         out->data[3] = r8;
+        out->n_caps  = r9 & 0x7;
+        out->n_extra = (r9 >> 4) & 0x7f;
+        out->n_dropped_caps = MSG_DROPPED_CAPS(r9);
+        out->caps[0] = r12;
+        out->caps[1] = r13;
+        out->caps[2] = r14;
+        out->caps[3] = r15;
     }
 
     return ret;
 }
 
-static inline uint64_t ipc_ep_create(void) {
-    return syscall1(SYS_IPC_EP_CREATE, 0); 
+static inline uint64_t ipc_recv_msg(uint64_t ep_id, ipc_msg_t *out) {
+    return ipc_syscall_msg(SYS_IPC_RECV, ep_id, 0, 0, 0, 0, 0, 0, out);
+}
+
+/* Returns IPC_TIMEOUT when nothing arrived within `timeout_ns`. */
+static inline uint64_t ipc_recv_timeout(uint64_t ep_id, uint64_t timeout_ns, ipc_msg_t *out) {
+    return ipc_syscall_msg(SYS_IPC_RECV, ep_id, 0, 0, 0, 0, 0, timeout_ns, out);
+}
+
+/* Returns IPC_NOT_READY instead of blocking when no sender is queued. */
+static inline uint64_t ipc_poll(uint64_t ep_id, ipc_msg_t *out) {
+    return ipc_syscall_msg(SYS_IPC_RECV, ep_id, 0, 0, 0, 0, MSG_NONBLOCK, 0, out);
+}
+
+/* Returns an endpoint cap carved out of `untyped_cap`. */
+static inline uint64_t ipc_ep_create(uint64_t untyped_cap) {
+    return syscall1(SYS_IPC_EP_CREATE, untyped_cap);
 }
 
 static inline uint64_t ipc_ep_destroy(uint64_t ep_id) {
@@ -162,45 +342,308 @@ static inline uint64_t ipc_send(uint64_t ep_id, uint64_t msg0, uint64_t msg1,
     return syscall5(SYS_IPC_SEND, ep_id, msg0, msg1, msg2, msg3);
 }
 
-static inline uint64_t ipc_try_recv(uint64_t ep_id) {
+/* Every cap in `caps` needs GRANT, and so does the endpoint cap. */
+static inline uint64_t ipc_send_caps(uint64_t ep_id, uint64_t msg0, uint64_t msg1,
+                                     uint64_t msg2, uint64_t msg3,
+                                     uint64_t n_caps, const uint64_t *caps) {
+    return syscall5_caps(SYS_IPC_SEND, ep_id, msg0, msg1, msg2, msg3, n_caps, caps);
+}
 
-    uint64_t ret = syscall1(SYS_IPC_RECV, ep_id);
-    
-    return ret;
+/* Returns IPC_NOT_READY instead of blocking when no receiver is waiting. */
+static inline uint64_t ipc_try_send(uint64_t ep_id, uint64_t msg0, uint64_t msg1,
+                                    uint64_t msg2, uint64_t msg3) {
+    return ipc_syscall_msg(SYS_IPC_SEND, ep_id, msg0, msg1, msg2, msg3, MSG_NONBLOCK, 0, 0);
 }
 
+/* Blocks until the server replies; the reply lands in `out`. */
 static inline uint64_t ipc_call(uint64_t ep_id, uint64_t req0, uint64_t req1,
-                                uint64_t req2, uint64_t req3,
-                                uint64_t *resp0, uint64_t *resp1,
-                                uint64_t *resp2, uint64_t *resp3) {
-    return syscall5(SYS_IPC_CALL, ep_id, req0, req1, req2, req3);
+                                uint64_t req2, uint64_t req3, ipc_msg_t *out) {
+    return ipc_syscall_msg(SYS_IPC_CALL, ep_id, req0, req1, req2, req3, 0, 0, out);
+}
+
+static inline uint64_t ipc_call_timeout(uint64_t ep_id, uint64_t req0, uint64_t req1,
+                                        uint64_t req2, uint64_t req3,
+                                        uint64_t timeout_ns, ipc_msg_t *out) {
+    return ipc_syscall_msg(SYS_IPC_CALL, ep_id, req0, req1, req2, req3, 0, timeout_ns, out);
 }
 
-static inline uint64_t ipc_reply(uint64_t ep_id, uint64_t resp0, uint64_t resp1,
+/* Answers the last call received; each call can be answered only once. */
+static inline uint64_t ipc_reply(uint64_t resp0, uint64_t resp1,
                                  uint64_t resp2, uint64_t resp3) {
-    return syscall5(SYS_IPC_REPLY, ep_id, resp0, resp1, resp2, resp3);
+    return syscall5(SYS_IPC_REPLY, 0, resp0, resp1, resp2, resp3);
+}
+
+/* Replies to the current caller, then waits on `ep_id` for the next message. */
+static inline uint64_t ipc_reply_recv(uint64_t ep_id, uint64_t resp0, uint64_t resp1,
+                                      uint64_t resp2, uint64_t resp3, ipc_msg_t *out) {
+    return ipc_syscall_msg(SYS_IPC_REPLY_RECV, ep_id, resp0, resp1, resp2, resp3, 0, 0, out);
+}
+
+/* `buf` must be page aligned and writable; 0 drops the registration. */
+static inline uint64_t ipc_set_buffer(ipc_buffer_t *buf) {
+    return syscall1(SYS_IPC_SET_BUFFER, (uint64_t)buf);
+}
+
+/* Like ipc_call, also sending the first `n_extra` words of the IPC buffer. */
+static inline uint64_t ipc_call_long(uint64_t ep_id, uint64_t req0, uint64_t req1,
+                                     uint64_t req2, uint64_t req3, uint64_t n_extra,
+                                     ipc_msg_t *out) {
+    return ipc_syscall_msg(SYS_IPC_CALL, ep_id, req0, req1, req2, req3,
+                           MSG_EXTRA(n_extra), 0, out);
+}
+
+static inline uint64_t ipc_reply_recv_long(uint64_t ep_id, uint64_t resp0, uint64_t resp1,
+                                           uint64_t resp2, uint64_t resp3, uint64_t n_extra,
+                                           ipc_msg_t *out) {
+    return ipc_syscall_msg(SYS_IPC_REPLY_RECV, ep_id, resp0, resp1, resp2, resp3,
+                           MSG_EXTRA(n_extra), 0, out);
+}
+
+/* Returns a notification cap carved out of `untyped_cap`. */
+static inline uint64_t notify_create(uint64_t untyped_cap) {
+    return syscall1(SYS_NOTIFY_CREATE, untyped_cap);
+}
+
+static inline uint64_t notify_signal(uint64_t ntfn_cap, uint64_t badge) {
+    return syscall2(SYS_NOTIFY_SIGNAL, ntfn_cap, badge);
+}
+
+/* Blocks until a badge is set; all pending badges are stored in `badges`.
+ * Fails if the notification is destroyed while the thread waits. */
+static inline uint64_t notify_wait(uint64_t ntfn_cap, uint64_t *badges) {
+    uint64_t ret;
+    register uint64_t rdi asm("rdi") = ntfn_cap;
+    __asm__ volatile (
+        "syscall"
+        : "=a"(ret), "+r"(rdi)
+        : "a"((uint64_t)SYS_NOTIFY_WAIT)
+        : "rcx", "r11", "rsi", "rdx", "r8", "r9", "r10",
+          "r12", "r13", "r14", "r15", "memory"
+    );
+    if (badges) *badges = rdi;
+    return ret;
+}
+
+/* Like notify_wait, but returns right away with whatever is pending. */
+static inline uint64_t notify_poll(uint64_t ntfn_cap, uint64_t *badges) {
+    uint64_t ret;
+    register uint64_t rdi asm("rdi") = ntfn_cap;
+    __asm__ volatile (
+        "syscall"
+        : "=a"(ret), "+r"(rdi)
+        : "a"((uint64_t)SYS_NOTIFY_POLL)
+        : "rcx", "r11", "rsi", "rdx", "r8", "r9", "r10",
+          "r12", "r13", "r14", "r15", "memory"
+    );
+    if (badges) *badges = rdi;
+    return ret;
+}
+
+/*
+ * While bound, signals on `ntfn_cap` also wake the thread out of ipc_recv,
+ * which then returns a MSG_LABEL_NOTIFY message with the badges in data[0].
+ */
+static inline uint64_t notify_bind(uint64_t tcb_cap, uint64_t ntfn_cap) {
+    return syscall2(SYS_NOTIFY_BIND, tcb_cap, ntfn_cap);
 }
 
-static inline uint64_t alloc_frame() {
-    return syscall0(SYS_ALLOC_FRAME);
+static inline uint64_t notify_unbind(uint64_t tcb_cap) {
+    return syscall1(SYS_NOTIFY_UNBIND, tcb_cap);
 }
 
-static inline uint64_t vma_map(uint64_t vspace_cap_idx, uint64_t vaddr, uint64_t size, uint64_t flags) {
-    return syscall4(SYS_VMA_MAP, vspace_cap_idx, vaddr, size, flags);
+/* Returns a frame cap for one zeroed page carved out of `untyped_cap`, or an
+ * error code near (uint64_t)-1. */
+static inline uint64_t alloc_frame(uint64_t untyped_cap) {
+    return syscall1(SYS_ALLOC_FRAME, untyped_cap);
+}
+
+/* Maps anonymous memory, carved out of `untyped_cap` page by page on first touch. */
+static inline uint64_t vma_map(uint64_t vspace_cap_idx, uint64_t vaddr, uint64_t size, uint64_t flags,
+                               uint64_t untyped_cap) {
+    return syscall5(SYS_VMA_MAP, vspace_cap_idx, vaddr, size, flags, untyped_cap);
+}
+
+/* Maps `size` bytes of the frame behind `frame_cap_idx` at `vaddr`. */
+static inline uint64_t vma_map_frame(uint64_t vspace_cap_idx, uint64_t frame_cap_idx,
+                                     uint64_t vaddr, uint64_t size, uint64_t flags) {
+    return syscall5(SYS_VMA_MAP, vspace_cap_idx, vaddr, size, flags, frame_cap_idx);
 }
 
 static inline uint64_t vma_unmap(uint64_t vspace_cap_idx, uint64_t vaddr) {
     return syscall2(SYS_VMA_UNMAP, vspace_cap_idx, vaddr);
 }
 
+/* Changes the flags of the mapping at `vaddr`. A mapping made from a frame
+ * cap without WRITE cannot be made writable. */
 static inline uint64_t mprotect(uint64_t vspace_cap_idx, uint64_t vaddr, uint64_t flags) {
     return syscall3(SYS_MPROTECT, vspace_cap_idx, vaddr, flags);
 }
 
+/* Returns a frame cap for at least `size` bytes of zeroed memory carved out
+ * of `untyped_cap`. */
+static inline uint64_t shm_create(uint64_t untyped_cap, uint64_t size) {
+    return syscall2(SYS_SHM_CREATE, untyped_cap, size);
+}
+
+/* Maps the whole frame at `vaddr`; the same frame may be mapped by several spaces. */
+static inline uint64_t shm_map(uint64_t vspace_cap_idx, uint64_t frame_cap_idx,
+                               uint64_t vaddr, uint64_t flags) {
+    return syscall4(SYS_SHM_MAP, vspace_cap_idx, frame_cap_idx, vaddr, flags);
+}
+
 static inline uint64_t sleep(uint64_t ns) {
     return syscall1(SYS_THREAD_SLEEP, ns);
 }
 
+/* Ends the calling thread; its caps, memory and kernel stack are released. */
+static inline __attribute__((noreturn)) void thread_exit(uint64_t code) {
+    syscall1(SYS_THREAD_EXIT, code);
+    __builtin_unreachable();
+}
+
+/* Signals `ntfn_cap` with BADGE_PROC_EXIT once `tcb_cap` exits;
+ * NO_EXIT_NOTIFICATION removes it. */
+static inline uint64_t thread_set_exit_notification(uint64_t tcb_cap, uint64_t ntfn_cap) {
+    return syscall2(SYS_THREAD_SET_EXIT_NOTIFICATION, tcb_cap, ntfn_cap);
+}
+
+/* Sends the faults of `tcb_cap` to `ep_cap`; NO_FAULT_HANDLER removes it. */
+static inline uint64_t thread_set_fault_handler(uint64_t tcb_cap, uint64_t ep_cap) {
+    return syscall2(SYS_THREAD_SET_FAULT_HANDLER, tcb_cap, ep_cap);
+}
+
+/*
+ * Binds an inactive thread to a vspace and root cnode. `fault_ep_cap` may be
+ * NO_FAULT_HANDLER and `ipc_buffer` 0 for none.
+ */
+static inline uint64_t tcb_configure(uint64_t tcb_cap, uint64_t vspace_cap, uint64_t cnode_cap,
+                                     uint64_t fault_ep_cap, uint64_t ipc_buffer) {
+    return syscall5(SYS_TCB_CONFIGURE, tcb_cap, vspace_cap, cnode_cap, fault_ep_cap, ipc_buffer);
+}
+
+/*
+ * Register access works while the thread is suspended or waits on its fault
+ * handler. Both calls go through `buf`, the caller's registered IPC buffer.
+ * A thread stopped inside a syscall gets that call's result in rax, and
+ * sysret clobbers rcx and r11.
+ */
+static inline uint64_t tcb_read_regs(uint64_t tcb_cap, ipc_buffer_t *buf, tcb_regs_t *out) {
+    uint64_t ret = syscall1(SYS_TCB_READ_REGS, tcb_cap);
+    if (ret == 0) {
+        uint64_t *words = (uint64_t *)out;
+        for (uint64_t i = 0; i < sizeof(*out) / 8; i++) words[i] = buf->words[i];
+    }
+    return ret;
+}
+
+static inline uint64_t tcb_set_regs(uint64_t tcb_cap, ipc_buffer_t *buf, const tcb_regs_t *regs) {
+    const uint64_t *words = (const uint64_t *)regs;
+    for (uint64_t i = 0; i < sizeof(*regs) / 8; i++) buf->words[i] = words[i];
+    return syscall1(SYS_TCB_SET_REGS, tcb_cap);
+}
+
+static inline uint64_t tcb_resume(uint64_t tcb_cap) {
+    return syscall1(SYS_TCB_RESUME, tcb_cap);
+}
+
+/*
+ * Loads the ELF file `name` from the boot archive into `vspace_cap`, with a
+ * user stack, and points the inactive `tcb_cap` at its entry. The image and
+ * stack pages are carved out of `untyped_cap` as they are touched. Configure
+ * the thread with the same vspace and resume it to start the service.
+ */
+static inline uint64_t tcb_load_image(uint64_t tcb_cap, uint64_t vspace_cap,
+                                      uint64_t untyped_cap, const char *name) {
+    uint64_t len = 0;
+    while (name[len]) len++;
+    return syscall5(SYS_TCB_LOAD_IMAGE, tcb_cap, vspace_cap, untyped_cap, (uint64_t)name, len);
+}
+
+/* Priorities run from 0 to 255, higher first. Neither value may exceed the
+ * caller's own max_priority. */
+static inline uint64_t tcb_set_priority(uint64_t tcb_cap, uint64_t priority, uint64_t max_priority) {
+    return syscall3(SYS_TCB_SET_PRIORITY, tcb_cap, priority, max_priority);
+}
+
+/* Fails for a thread running on another CPU or waiting on its fault
+ * handler. A thread blocked in IPC sees its call time out once resumed. */
+static inline uint64_t tcb_suspend(uint64_t tcb_cap) {
+    return syscall1(SYS_TCB_SUSPEND, tcb_cap);
+}
+
+/*
+ * A scheduling context lets the thread bound to it run for `budget_ns` out
+ * of every `period_ns`; once the budget is used up the thread is held back
+ * until the next period. Threads are charged for the time they actually ran. A
+ * server with no context of its own runs on its caller's while it handles
+ * a call. A thread with neither is not run: a new thread only starts once
+ * a context is bound to it, and one whose context is unbound stops until it
+ * gets another. Init gets a context of its own at boot.
+ */
+static inline uint64_t sched_configure(uint64_t sc_cap, uint64_t budget_ns, uint64_t period_ns) {
+    return syscall3(SYS_SCHED_CONFIGURE, sc_cap, budget_ns, period_ns);
+}
+
+static inline uint64_t sched_bind(uint64_t sc_cap, uint64_t tcb_cap) {
+    return syscall2(SYS_SCHED_BIND, sc_cap, tcb_cap);
+}
+
+static inline uint64_t sched_unbind(uint64_t sc_cap) {
+    return syscall1(SYS_SCHED_UNBIND, sc_cap);
+}
+
+/* Answers a MSG_LABEL_FAULT message: FAULT_RESUME retries the faulting
+ * instruction, FAULT_KILL stops the thread. */
+static inline uint64_t fault_reply(uint64_t action) {
+    return ipc_reply(action, 0, 0, 0);
+}
+
+static inline uint64_t cnode_copy(uint64_t src_cnode, uint64_t src_idx,
+                                  uint64_t dst_cnode, uint64_t dst_idx, uint64_t rights) {
+    return syscall5(SYS_CNODE_COPY, src_cnode, src_idx, dst_cnode, dst_idx, rights);
+}
+
+/* Like cnode_copy, but the new cap carries `badge`; see ipc_msg_t.badge. */
+static inline uint64_t cnode_mint(uint64_t src_cnode, uint64_t src_idx,
+                                  uint64_t dst_cnode, uint64_t dst_idx,
+                                  uint64_t rights, uint64_t badge) {
+    return syscall6(SYS_CNODE_MINT, src_cnode, src_idx, dst_cnode, dst_idx, rights, badge);
+}
+
+static inline uint64_t cnode_move(uint64_t src_cnode, uint64_t src_idx,
+                                  uint64_t dst_cnode, uint64_t dst_idx) {
+    return syscall4(SYS_CNODE_MOVE, src_cnode, src_idx, dst_cnode, dst_idx);
+}
+
+static inline uint64_t cnode_delete(uint64_t cnode, uint64_t idx) {
+    return syscall2(SYS_CNODE_DELETE, cnode, idx);
+}
+
+static inline uint64_t cnode_revoke(uint64_t cnode, uint64_t idx) {
+    return syscall2(SYS_CNODE_REVOKE, cnode, idx);
+}
+
+// Slots are addressed by capability pointers walked from the least significant
+// bit: each level matches its low guard_bits against guard, then indexes
+// radix_bits, and the walk continues into a CNode while set bits remain. The
+// root has no guard, so root slot `r` is just `r`.
+//
+// Give a CNode a non-zero guard if its slot 0 must be reachable from a root.
+// Its slots are carved out of untyped_cap.
+static inline uint64_t cnode_create(uint64_t untyped_cap, uint64_t radix_bits,
+                                    uint64_t guard_bits, uint64_t guard) {
+    return syscall4(SYS_CNODE_CREATE, untyped_cap, radix_bits, guard_bits, guard);
+}
+
+/*
+ * Carves an object out of an untyped and returns a cap to it. `size` is a
+ * byte count for OBJ_FRAME, the radix in bits for OBJ_CNODE and ignored
+ * otherwise.
+ */
+static inline uint64_t untyped_retype(uint64_t untyped_cap, uint64_t obj_type, uint64_t size) {
+    return syscall3(SYS_UNTYPED_RETYPE, untyped_cap, obj_type, size);
+}
+
 static inline uint64_t sys_print(const char *str, uint64_t len) {
     if ((uint64_t)str < 0x1000 || (uint64_t)str > 0x00007FFFFFFFFFFF) {
         return 1;